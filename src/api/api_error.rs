@@ -0,0 +1,87 @@
+use std::fmt;
+
+use hyper::Body;
+use warp::http::StatusCode;
+use warp::Reply;
+
+use crate::api::openai::ErrorResponse;
+use crate::llm::sampling_params::InvalidParameter;
+use crate::scheduler::generation_scheduler::QueueFull;
+
+/// Errors returned to the client before any generation starts
+#[derive(Debug, Clone)]
+pub enum ApiError {
+    InvalidParameter(InvalidParameter),
+    QueueFull(QueueFull),
+    /// The prompt config could not be reloaded, the previous one stays live
+    InvalidConfig(String),
+    NotFound(String),
+    Conflict(String),
+    /// Admin route called without the admin token
+    Unauthorized(String),
+    /// The model failed on a request which does not stream its answer
+    Internal(String),
+}
+
+impl From<InvalidParameter> for ApiError {
+    fn from(err: InvalidParameter) -> Self {
+        ApiError::InvalidParameter(err)
+    }
+}
+
+impl From<QueueFull> for ApiError {
+    fn from(err: QueueFull) -> Self {
+        ApiError::QueueFull(err)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::InvalidParameter(err) => write!(f, "{}", err),
+            ApiError::QueueFull(err) => write!(f, "{}", err),
+            ApiError::InvalidConfig(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl ApiError {
+    pub fn into_response(self) -> hyper::Response<Body> {
+        match self {
+            ApiError::InvalidParameter(err) => {
+                let body = warp::reply::json(&ErrorResponse::invalid_request(err.to_string()));
+                warp::reply::with_status(body, StatusCode::BAD_REQUEST).into_response()
+            }
+            ApiError::QueueFull(err) => {
+                let body = warp::reply::json(&ErrorResponse::server_busy(err.to_string()));
+                let reply = warp::reply::with_status(body, StatusCode::SERVICE_UNAVAILABLE);
+                warp::reply::with_header(reply, "retry-after", err.retry_after_secs.to_string()).into_response()
+            }
+            ApiError::InvalidConfig(message) => {
+                let body = warp::reply::json(&ErrorResponse::invalid_config(message));
+                warp::reply::with_status(body, StatusCode::UNPROCESSABLE_ENTITY).into_response()
+            }
+            ApiError::NotFound(message) => {
+                let body = warp::reply::json(&ErrorResponse::not_found(message));
+                warp::reply::with_status(body, StatusCode::NOT_FOUND).into_response()
+            }
+            ApiError::Conflict(message) => {
+                let body = warp::reply::json(&ErrorResponse::conflict(message));
+                warp::reply::with_status(body, StatusCode::CONFLICT).into_response()
+            }
+            ApiError::Unauthorized(message) => {
+                let body = warp::reply::json(&ErrorResponse::unauthorized(message));
+                let reply = warp::reply::with_status(body, StatusCode::UNAUTHORIZED);
+                warp::reply::with_header(reply, "www-authenticate", "Bearer").into_response()
+            }
+            ApiError::Internal(message) => {
+                let body = warp::reply::json(&ErrorResponse::server_error(message));
+                warp::reply::with_status(body, StatusCode::INTERNAL_SERVER_ERROR).into_response()
+            }
+        }
+    }
+}
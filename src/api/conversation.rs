@@ -0,0 +1,37 @@
+use std::collections::VecDeque;
+
+use crate::llm::prompt_template::Exchange;
+
+/// Number of exchanges kept by a conversation, the model context usually fills up before
+const MAX_EXCHANGES: usize = 32;
+
+/// Turns exchanged so far on a connection, replayed in front of each new prompt
+#[derive(Debug, Clone, Default)]
+pub struct Conversation {
+    exchanges: VecDeque<Exchange>,
+}
+
+impl Conversation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn history(&self) -> Vec<Exchange> {
+        self.exchanges.iter().cloned().collect()
+    }
+
+    /// Record a question and its answer, the oldest exchange is dropped when full
+    pub fn push_exchange(&mut self, query: &str, answer: &str) {
+        if self.exchanges.len() >= MAX_EXCHANGES {
+            self.exchanges.pop_front();
+        }
+        self.exchanges.push_back(Exchange {
+            user: query.trim().to_string(),
+            assistant: answer.trim().to_string(),
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.exchanges.clear();
+    }
+}
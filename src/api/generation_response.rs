@@ -0,0 +1,43 @@
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::oneshot;
+
+use crate::api::api_error::ApiError;
+use crate::llm::generation_summary::{GenerationOutcome, GenerationSummary};
+use crate::llm::sql_output::{SqlCheck, SqlOutput};
+
+/*****************************************************************/
+// Single JSON answer of /token_stream, when it is not streamed
+/*****************************************************************/
+
+#[derive(Serialize, Debug, Clone)]
+pub struct GenerationResponse {
+    pub text: String,
+    #[serde(flatten)]
+    pub summary: GenerationSummary,
+    /// Query checked for the sql profiles
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sql: Option<SqlOutput>,
+}
+
+/// Drain the generation channel, and return the text along with the generation summary
+pub async fn collect_generation(
+    mut tokens: UnboundedReceiver<String>,
+    outcome: oneshot::Receiver<GenerationOutcome>,
+    sql: Option<SqlCheck>,
+) -> Result<GenerationResponse, ApiError> {
+    let mut text = String::new();
+
+    while let Some(token) = tokens.recv().await {
+        text.push_str(token.as_str());
+    }
+
+    let summary = match outcome.await {
+        Ok(Ok(summary)) => summary,
+        Ok(Err(message)) => return Err(ApiError::Internal(message)),
+        Err(_) => return Err(ApiError::Internal("generation ended unexpectedly".to_string())),
+    };
+    let sql = sql.map(|sql| sql.check(text.as_str()));
+
+    Ok(GenerationResponse { text, summary, sql })
+}
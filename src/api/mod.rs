@@ -0,0 +1,7 @@
+pub mod openai;
+pub mod api_error;
+pub mod token_events;
+pub mod generation_response;
+pub mod conversation;
+pub mod websocket;
+pub mod sessions;
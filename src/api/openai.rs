@@ -0,0 +1,522 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use futures_util::{stream, Stream};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::{oneshot, watch};
+
+use crate::api::api_error::ApiError;
+use crate::llm::generation_summary::{FinishReason, GenerationOutcome};
+use crate::llm::prompt_template::Exchange;
+use crate::llm::sampling_params::{InvalidParameter, SamplingOverrides};
+use crate::scheduler::generation_scheduler::QueueState;
+
+/*****************************************************************/
+// OpenAI chat completions schema ( subset )
+/*****************************************************************/
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+/// `stop` can either be a single string or a list of strings
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum StopSequences {
+    Single(String),
+    Many(Vec<String>),
+}
+
+impl StopSequences {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            StopSequences::Single(s) => vec![s],
+            StopSequences::Many(v) => v,
+        }
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .collect()
+    }
+}
+
+/// `json_object` and `json_schema` constrain the answer to JSON
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JsonSchemaFormat {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub schema: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatCompletionRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub top_p: Option<f64>,
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    #[serde(default)]
+    pub stop: Option<StopSequences>,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
+    #[serde(default)]
+    pub logit_bias: Option<BTreeMap<String, f32>>,
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>,
+    #[serde(default)]
+    pub stream: Option<bool>,
+    /// Prompt profile providing the context and default sampling ( extension )
+    #[serde(default)]
+    pub profile: Option<String>,
+}
+
+impl ChatCompletionRequest {
+    /// Split the message list into the context ( system messages ), the previous
+    /// exchanges and the query ( the last user turn ). Falls back to the configured
+    /// context when no system message is given.
+    pub fn context_and_conversation(&self, default_context: &str) -> Result<(String, Vec<Exchange>, String), InvalidParameter> {
+        let system: Vec<&str> = self
+            .messages
+            .iter()
+            .filter(|m| m.role == "system")
+            .map(|m| m.content.trim())
+            .collect();
+
+        let context = if system.is_empty() {
+            default_context.to_string()
+        } else {
+            system.join("\n")
+        };
+
+        let turns: Vec<&ChatMessage> = self.messages.iter().filter(|m| m.role != "system").collect();
+        if turns.last().is_none_or(|m| m.role != "user") {
+            return Err(InvalidParameter {
+                field: "messages",
+                message: "the last message must come from the user".to_string(),
+            });
+        }
+
+        // Consecutive user messages make up a single turn
+        let mut history = Vec::new();
+        let mut pending: Vec<&str> = Vec::new();
+        for message in turns {
+            match message.role.as_str() {
+                "assistant" => {
+                    history.push(Exchange {
+                        user: pending.join("\n"),
+                        assistant: message.content.trim().to_string(),
+                    });
+                    pending.clear();
+                }
+                _ => pending.push(message.content.trim()),
+            }
+        }
+
+        Ok((context, history, pending.join("\n")))
+    }
+
+    /// Sampling parameters given in the request, the others fall back to the command line defaults
+    pub fn sampling_overrides(&self) -> SamplingOverrides {
+        SamplingOverrides {
+            seed: self.seed,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            sample_len: self.max_tokens,
+            stop: self.stop.clone().map(StopSequences::into_vec),
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            logit_bias: self.logit_bias.clone(),
+            json_schema: match &self.response_format {
+                Some(ResponseFormat::JsonObject) => Some(json!({ "type": "object" })),
+                Some(ResponseFormat::JsonSchema { json_schema }) => Some(json_schema.schema.clone()),
+                Some(ResponseFormat::Text) | None => None,
+            },
+            ..SamplingOverrides::default()
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ErrorBody {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+}
+
+impl ErrorResponse {
+    pub fn invalid_request(message: String) -> Self {
+        Self {
+            error: ErrorBody {
+                message,
+                kind: "invalid_request_error",
+            },
+        }
+    }
+
+    pub fn invalid_config(message: String) -> Self {
+        Self {
+            error: ErrorBody {
+                message,
+                kind: "invalid_config",
+            },
+        }
+    }
+
+    pub fn not_found(message: String) -> Self {
+        Self {
+            error: ErrorBody {
+                message,
+                kind: "not_found_error",
+            },
+        }
+    }
+
+    pub fn conflict(message: String) -> Self {
+        Self {
+            error: ErrorBody {
+                message,
+                kind: "conflict_error",
+            },
+        }
+    }
+
+    pub fn unauthorized(message: String) -> Self {
+        Self {
+            error: ErrorBody {
+                message,
+                kind: "authentication_error",
+            },
+        }
+    }
+
+    pub fn server_error(message: String) -> Self {
+        Self {
+            error: ErrorBody {
+                message,
+                kind: "server_error",
+            },
+        }
+    }
+
+    pub fn server_busy(message: String) -> Self {
+        Self {
+            error: ErrorBody {
+                message,
+                kind: "server_busy",
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ChatCompletion {
+    pub id: String,
+    pub object: &'static str,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<ChatChoice>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ChatChoice {
+    pub index: usize,
+    pub message: ChatMessage,
+    pub finish_reason: &'static str,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ChatCompletionChunk {
+    pub id: String,
+    pub object: &'static str,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<ChunkChoice>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ChunkChoice {
+    pub index: usize,
+    pub delta: Delta,
+    pub finish_reason: Option<&'static str>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+/*****************************************************************/
+// Response builders
+/*****************************************************************/
+
+static COMPLETION_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub fn completion_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    let count = COMPLETION_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("chatcmpl-{:x}{:04x}", nanos, count & 0xffff)
+}
+
+pub fn created_timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// OpenAI only knows about `stop` and `length`
+fn finish_reason(reason: FinishReason) -> &'static str {
+    match reason {
+        FinishReason::Length | FinishReason::MaxTime => "length",
+        _ => "stop",
+    }
+}
+
+/// Drain the generation channel and build a single chat completion.
+/// Stop sequences are already cut by the generation.
+pub async fn collect_completion(
+    mut rx: UnboundedReceiver<String>,
+    outcome: oneshot::Receiver<GenerationOutcome>,
+    model: String,
+) -> Result<ChatCompletion, ApiError> {
+    let mut content = String::new();
+
+    while let Some(token) = rx.recv().await {
+        content.push_str(token.as_str());
+    }
+
+    let summary = match outcome.await {
+        Ok(Ok(summary)) => summary,
+        Ok(Err(message)) => return Err(ApiError::Internal(message)),
+        Err(_) => return Err(ApiError::Internal("generation ended unexpectedly".to_string())),
+    };
+
+    Ok(ChatCompletion {
+        id: completion_id(),
+        object: "chat.completion",
+        created: created_timestamp(),
+        model,
+        choices: vec![ChatChoice {
+            index: 0,
+            message: ChatMessage {
+                role: "assistant".to_string(),
+                content,
+            },
+            finish_reason: finish_reason(summary.finish_reason),
+        }],
+    })
+}
+
+enum ChunkPhase {
+    Queued,
+    Role,
+    Tokens,
+    Finish,
+    Done,
+    Closed,
+}
+
+struct ChunkState {
+    rx: UnboundedReceiver<String>,
+    queue: watch::Receiver<QueueState>,
+    queue_position: Option<usize>,
+    outcome: Option<oneshot::Receiver<GenerationOutcome>>,
+    phase: ChunkPhase,
+    id: String,
+    created: u64,
+    model: String,
+}
+
+impl ChunkState {
+    fn event(&self, delta: Delta, finish_reason: Option<&'static str>) -> Bytes {
+        let chunk = ChatCompletionChunk {
+            id: self.id.clone(),
+            object: "chat.completion.chunk",
+            created: self.created,
+            model: self.model.clone(),
+            choices: vec![ChunkChoice {
+                index: 0,
+                delta,
+                finish_reason,
+            }],
+        };
+        let json = serde_json::to_string(&chunk).unwrap_or_default();
+        Bytes::from(format!("data: {}\n\n", json))
+    }
+}
+
+/// Turn the generation channel into `chat.completion.chunk` server sent events,
+/// terminated by `data: [DONE]`. While the request is queued, its position is
+/// sent as SSE comments, which clients ignore.
+pub fn chunk_stream(
+    rx: UnboundedReceiver<String>,
+    queue: watch::Receiver<QueueState>,
+    outcome: oneshot::Receiver<GenerationOutcome>,
+    model: String,
+) -> impl Stream<Item = Result<Bytes, Infallible>> + Send + 'static {
+    let state = ChunkState {
+        rx,
+        queue,
+        queue_position: None,
+        outcome: Some(outcome),
+        phase: ChunkPhase::Queued,
+        id: completion_id(),
+        created: created_timestamp(),
+        model,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        loop {
+            match state.phase {
+                ChunkPhase::Queued => {
+                    let queue_state = *state.queue.borrow_and_update();
+                    match queue_state {
+                        QueueState::Waiting(position) if state.queue_position != Some(position) => {
+                            state.queue_position = Some(position);
+                            let comment = format!(": queue position {}\n\n", position);
+                            return Some((Ok(Bytes::from(comment)), state));
+                        }
+                        QueueState::Waiting(_) => {
+                            // Wait for the next move in the queue
+                            if state.queue.changed().await.is_err() {
+                                state.phase = ChunkPhase::Role;
+                            }
+                        }
+                        QueueState::Running => state.phase = ChunkPhase::Role,
+                    }
+                }
+                ChunkPhase::Role => {
+                    state.phase = ChunkPhase::Tokens;
+                    let delta = Delta {
+                        role: Some("assistant"),
+                        content: None,
+                    };
+                    let event = state.event(delta, None);
+                    return Some((Ok(event), state));
+                }
+                ChunkPhase::Tokens => {
+                    let text = match state.rx.recv().await {
+                        Some(text) if text.is_empty() => continue,
+                        Some(text) => text,
+                        None => {
+                            state.phase = ChunkPhase::Finish;
+                            continue;
+                        }
+                    };
+                    let delta = Delta {
+                        role: None,
+                        content: Some(text),
+                    };
+                    let event = state.event(delta, None);
+                    return Some((Ok(event), state));
+                }
+                ChunkPhase::Finish => {
+                    state.phase = ChunkPhase::Done;
+                    let outcome = match state.outcome.take() {
+                        Some(outcome) => outcome.await.unwrap_or_else(|_| Err("generation ended unexpectedly".to_string())),
+                        None => Err("generation ended unexpectedly".to_string()),
+                    };
+                    let event = match outcome {
+                        Ok(summary) => state.event(Delta::default(), Some(finish_reason(summary.finish_reason))),
+                        // The status is already sent, the failure goes in an error event instead of a finish reason
+                        Err(message) => {
+                            let json = serde_json::to_string(&ErrorResponse::server_error(message)).unwrap_or_default();
+                            Bytes::from(format!("data: {}\n\n", json))
+                        }
+                    };
+                    return Some((Ok(event), state));
+                }
+                ChunkPhase::Done => {
+                    state.phase = ChunkPhase::Closed;
+                    return Some((Ok(Bytes::from("data: [DONE]\n\n")), state));
+                }
+                ChunkPhase::Closed => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::StreamExt;
+    use tokio::sync::mpsc;
+
+    use crate::llm::generation_summary::GenerationSummary;
+
+    use super::*;
+
+    /// Events of a stream which already got a slot, after `tokens` were generated
+    async fn chunk_events(tokens: &[&str], outcome: Option<GenerationOutcome>) -> Vec<String> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        for token in tokens {
+            tx.send(token.to_string()).unwrap();
+        }
+        drop(tx);
+        let (_queue_tx, queue) = watch::channel(QueueState::Running);
+        let (outcome_tx, outcome_rx) = oneshot::channel();
+        match outcome {
+            Some(outcome) => outcome_tx.send(outcome).unwrap(),
+            None => drop(outcome_tx),
+        }
+
+        chunk_stream(rx, queue, outcome_rx, "model".to_string())
+            .map(|event| String::from_utf8(event.unwrap().to_vec()).unwrap())
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn finish_reason_of_the_generation() {
+        let summary = GenerationSummary::new(FinishReason::Length, 3, 2, Duration::ZERO);
+        let events = chunk_events(&["Hello", " world"], Some(Ok(summary))).await;
+
+        assert_eq!(events.len(), 5);
+        assert!(events[0].contains(r#""role":"assistant""#));
+        assert!(events[1].contains(r#""content":"Hello""#));
+        assert!(events[3].contains(r#""finish_reason":"length""#));
+        assert_eq!(events[4], "data: [DONE]\n\n");
+    }
+
+    #[tokio::test]
+    async fn failed_generation_sends_an_error_event() {
+        let events = chunk_events(&["Hello"], Some(Err("out of memory".to_string()))).await;
+
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[2], "data: {\"error\":{\"message\":\"out of memory\",\"type\":\"server_error\"}}\n\n");
+        assert!(events.iter().all(|event| !event.contains("finish_reason\":\"stop")));
+        assert_eq!(events[3], "data: [DONE]\n\n");
+
+        // The generation task went away without an outcome
+        let events = chunk_events(&[], None).await;
+        assert!(events[1].contains("generation ended unexpectedly"));
+    }
+}
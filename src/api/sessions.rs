@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::api::api_error::ApiError;
+use crate::api::openai::ChatMessage;
+use crate::llm::generation_summary::FinishReason;
+use crate::llm::prompt_template::Exchange;
+use crate::llm::sampling_params::{InvalidParameter, SamplingOverrides};
+use crate::scheduler::generation_task::GenerationChannels;
+
+/// Profile and sampling parameters used by every reply of a session
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SessionSettings {
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(flatten)]
+    pub sampling: SamplingOverrides,
+}
+
+/// A session as returned to the client
+#[derive(Serialize, Debug, Clone)]
+pub struct SessionView {
+    pub session_id: String,
+    #[serde(flatten)]
+    pub settings: SessionSettings,
+    pub messages: Vec<ChatMessage>,
+}
+
+/// What a reply needs : the settings, the previous exchanges and the question to answer
+pub struct PendingReply {
+    pub settings: SessionSettings,
+    pub history: Vec<Exchange>,
+    pub query: String,
+}
+
+struct Session {
+    settings: SessionSettings,
+    messages: Vec<ChatMessage>,
+    /// Only one reply at a time, the answer is appended to the history once done
+    replying: bool,
+    last_used: Instant,
+}
+
+static SESSION_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn session_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    let count = SESSION_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("sess-{:x}{:04x}", nanos, count & 0xffff)
+}
+
+/// Conversations kept on the server, by session id. Idle sessions expire, and the least
+/// recently used one is dropped to make room beyond `max_sessions`.
+pub struct SessionStore {
+    sessions: Mutex<HashMap<String, Session>>,
+    max_sessions: usize,
+    idle_timeout: Duration,
+}
+
+impl SessionStore {
+    pub fn new(max_sessions: usize, idle_timeout: Duration) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            max_sessions: max_sessions.max(1),
+            idle_timeout,
+        }
+    }
+
+    /// Sessions locked for use, the expired ones removed
+    fn sessions(&self) -> MutexGuard<'_, HashMap<String, Session>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let idle_timeout = self.idle_timeout;
+        sessions.retain(|_, session| session.replying || session.last_used.elapsed() < idle_timeout);
+        sessions
+    }
+
+    pub fn create(&self, settings: SessionSettings) -> SessionView {
+        let id = session_id();
+        let session = Session {
+            settings: settings.clone(),
+            messages: Vec::new(),
+            replying: false,
+            last_used: Instant::now(),
+        };
+        let mut sessions = self.sessions();
+        if sessions.len() >= self.max_sessions {
+            // A reply still running on a dropped session is not recorded
+            let oldest = sessions.iter().min_by_key(|(_, session)| session.last_used).map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                println!("session `{}` dropped, {} sessions at most", oldest, self.max_sessions);
+                sessions.remove(&oldest);
+            }
+        }
+        sessions.insert(id.clone(), session);
+        SessionView {
+            session_id: id,
+            settings,
+            messages: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Result<SessionView, ApiError> {
+        let mut sessions = self.sessions();
+        let session = sessions.get_mut(id).ok_or_else(|| not_found(id))?;
+        session.last_used = Instant::now();
+        Ok(SessionView {
+            session_id: id.to_string(),
+            settings: session.settings.clone(),
+            messages: session.messages.clone(),
+        })
+    }
+
+    pub fn delete(&self, id: &str) -> Result<(), ApiError> {
+        self.sessions().remove(id).map(|_| ()).ok_or_else(|| not_found(id))
+    }
+
+    /// Append a user ( or assistant ) message, returns the number of messages in the session
+    pub fn append(&self, id: &str, message: ChatMessage) -> Result<usize, ApiError> {
+        if message.role != "user" && message.role != "assistant" {
+            return Err(InvalidParameter {
+                field: "role",
+                message: "must be `user` or `assistant`".to_string(),
+            }
+            .into());
+        }
+        let mut sessions = self.sessions();
+        let session = sessions.get_mut(id).ok_or_else(|| not_found(id))?;
+        if session.replying {
+            return Err(ApiError::Conflict(format!("session `{}` is replying", id)));
+        }
+        session.last_used = Instant::now();
+        session.messages.push(message);
+        Ok(session.messages.len())
+    }
+
+    /// Mark the session as replying, and split its messages into the previous exchanges
+    /// and the user messages waiting for an answer
+    pub fn start_reply(&self, id: &str) -> Result<PendingReply, ApiError> {
+        let mut sessions = self.sessions();
+        let session = sessions.get_mut(id).ok_or_else(|| not_found(id))?;
+        if session.replying {
+            return Err(ApiError::Conflict(format!("session `{}` is already replying", id)));
+        }
+        session.last_used = Instant::now();
+
+        let mut history = Vec::new();
+        let mut pending: Vec<&str> = Vec::new();
+        for message in session.messages.iter() {
+            match message.role.as_str() {
+                "assistant" => {
+                    history.push(Exchange {
+                        user: pending.join("\n"),
+                        assistant: message.content.clone(),
+                    });
+                    pending.clear();
+                }
+                _ => pending.push(message.content.trim()),
+            }
+        }
+        if pending.is_empty() {
+            return Err(InvalidParameter {
+                field: "messages",
+                message: "no user message is waiting for a reply".to_string(),
+            }
+            .into());
+        }
+
+        let query = pending.join("\n");
+        session.replying = true;
+        Ok(PendingReply {
+            settings: session.settings.clone(),
+            history,
+            query,
+        })
+    }
+
+    /// End a reply, the answer is appended to the history when the generation completed
+    pub fn finish_reply(&self, id: &str, answer: Option<String>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        // The session may have been deleted in the meantime
+        if let Some(session) = sessions.get_mut(id) {
+            session.replying = false;
+            session.last_used = Instant::now();
+            if let Some(content) = answer {
+                session.messages.push(ChatMessage {
+                    role: "assistant".to_string(),
+                    content: content.trim().to_string(),
+                });
+            }
+        }
+    }
+}
+
+fn not_found(id: &str) -> ApiError {
+    ApiError::NotFound(format!("no session `{}`", id))
+}
+
+/*****************************************************************/
+// Forward the reply to the client, and keep it in the session
+/*****************************************************************/
+pub fn record_reply(sessions: Arc<SessionStore>, id: String, channels: GenerationChannels) -> GenerationChannels {
+    let GenerationChannels { mut tokens, queue, outcome } = channels;
+    let (tokens_tx, tokens_rx) = mpsc::unbounded_channel();
+    let (outcome_tx, outcome_rx) = oneshot::channel();
+
+    tokio::spawn(async move {
+        let mut answer = String::new();
+        loop {
+            tokio::select! {
+                token = tokens.recv() => match token {
+                    Some(token) => {
+                        answer.push_str(token.as_str());
+                        let _ = tokens_tx.send(token);
+                    }
+                    None => break,
+                },
+                // The client went away, dropping `tokens` cancels the generation
+                _ = tokens_tx.closed() => break,
+            }
+        }
+        drop(tokens);
+        drop(tokens_tx);
+
+        let outcome = outcome
+            .await
+            .unwrap_or_else(|_| Err("generation ended unexpectedly".to_string()));
+        let completed = matches!(&outcome, Ok(summary) if summary.finish_reason != FinishReason::Cancelled);
+        sessions.finish_reply(id.as_str(), completed.then_some(answer));
+        let _ = outcome_tx.send(outcome);
+    });
+
+    GenerationChannels {
+        tokens: tokens_rx,
+        queue,
+        outcome: outcome_rx,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    fn store() -> SessionStore {
+        SessionStore::new(10, Duration::from_secs(3600))
+    }
+
+    #[test]
+    fn reply_follows_the_previous_exchanges() {
+        let sessions = store();
+        let id = sessions.create(SessionSettings::default()).session_id;
+        sessions.append(&id, message("user", "Hello")).unwrap();
+        sessions.append(&id, message("assistant", "Hi")).unwrap();
+        sessions.append(&id, message("user", " What is 12 times 12 ? ")).unwrap();
+        sessions.append(&id, message("user", "Answer with a number")).unwrap();
+
+        let pending = sessions.start_reply(&id).unwrap();
+        assert_eq!(pending.history.len(), 1);
+        assert_eq!((pending.history[0].user.as_str(), pending.history[0].assistant.as_str()), ("Hello", "Hi"));
+        assert_eq!(pending.query, "What is 12 times 12 ?\nAnswer with a number");
+
+        // One reply at a time, and no message while replying
+        assert!(matches!(sessions.start_reply(&id), Err(ApiError::Conflict(_))));
+        assert!(matches!(sessions.append(&id, message("user", "Hurry")), Err(ApiError::Conflict(_))));
+
+        sessions.finish_reply(&id, Some(" 144 \n".to_string()));
+        let messages = sessions.get(&id).unwrap().messages;
+        assert_eq!(messages.len(), 5);
+        assert_eq!((messages[4].role.as_str(), messages[4].content.as_str()), ("assistant", "144"));
+    }
+
+    #[test]
+    fn interrupted_reply_is_not_recorded() {
+        let sessions = store();
+        let id = sessions.create(SessionSettings::default()).session_id;
+        assert!(matches!(sessions.start_reply(&id), Err(ApiError::InvalidParameter(_))));
+
+        sessions.append(&id, message("user", "Hello")).unwrap();
+        sessions.start_reply(&id).unwrap();
+        sessions.finish_reply(&id, None);
+        assert_eq!(sessions.get(&id).unwrap().messages.len(), 1);
+
+        // The user message is still waiting, it can be answered again
+        assert!(sessions.start_reply(&id).is_ok());
+        assert!(matches!(sessions.append(&id, message("system", "Be brief")), Err(ApiError::InvalidParameter(_))));
+        assert!(matches!(sessions.get("sess-unknown"), Err(ApiError::NotFound(_))));
+    }
+
+    #[test]
+    fn least_recently_used_session_is_dropped() {
+        let sessions = SessionStore::new(2, Duration::from_secs(3600));
+        let first = sessions.create(SessionSettings::default()).session_id;
+        let second = sessions.create(SessionSettings::default()).session_id;
+        sessions.get(&first).unwrap();
+
+        let third = sessions.create(SessionSettings::default()).session_id;
+        assert!(sessions.get(&first).is_ok());
+        assert!(matches!(sessions.get(&second), Err(ApiError::NotFound(_))));
+        assert!(sessions.get(&third).is_ok());
+    }
+
+    #[test]
+    fn idle_sessions_expire_unless_replying() {
+        let sessions = SessionStore::new(10, Duration::ZERO);
+        let idle = sessions.create(SessionSettings::default()).session_id;
+        let replying = sessions.create(SessionSettings::default()).session_id;
+        sessions.sessions.lock().unwrap().get_mut(&replying).unwrap().replying = true;
+
+        assert!(matches!(sessions.get(&idle), Err(ApiError::NotFound(_))));
+        assert!(sessions.get(&replying).is_ok());
+    }
+}
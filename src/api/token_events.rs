@@ -0,0 +1,178 @@
+use std::convert::Infallible;
+
+use bytes::Bytes;
+use futures_util::{stream, Stream};
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::{oneshot, watch};
+
+use crate::llm::generation_summary::GenerationOutcome;
+use crate::llm::sql_output::SqlCheck;
+use crate::scheduler::generation_scheduler::QueueState;
+
+/*****************************************************************/
+// Server sent events for /token_stream
+/*****************************************************************/
+
+#[derive(Serialize)]
+struct TokenEvent<'a> {
+    text: &'a str,
+}
+
+#[derive(Serialize)]
+struct QueuedEvent {
+    /// Number of requests ahead
+    position: usize,
+}
+
+#[derive(Serialize)]
+struct ErrorEvent<'a> {
+    message: &'a str,
+}
+
+fn sse_event(event: &str, data: &impl Serialize) -> Bytes {
+    let json = serde_json::to_string(data).unwrap_or_default();
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, json))
+}
+
+enum EventPhase {
+    Queued,
+    Tokens,
+    Outcome,
+    /// The `done` event, held back while the `sql` event is sent
+    Done(Bytes),
+    Closed,
+}
+
+struct EventState {
+    tokens: UnboundedReceiver<String>,
+    queue: watch::Receiver<QueueState>,
+    /// Last position sent in a `queued` event
+    queue_position: Option<usize>,
+    outcome: Option<oneshot::Receiver<GenerationOutcome>>,
+    sql: Option<SqlCheck>,
+    /// Whole answer, kept for the sql check
+    text: String,
+    phase: EventPhase,
+}
+
+/// Turn the generation channels into `token` events carrying the text, followed by
+/// a single `done` event with the generation summary, or an `error` event.
+/// While the request waits for a slot, a `queued` event is sent each time its position changes.
+/// With a sql check, a `sql` event carrying the checked query comes right before `done`.
+pub fn token_event_stream(
+    tokens: UnboundedReceiver<String>,
+    queue: watch::Receiver<QueueState>,
+    outcome: oneshot::Receiver<GenerationOutcome>,
+    sql: Option<SqlCheck>,
+) -> impl Stream<Item = Result<Bytes, Infallible>> + Send + 'static {
+    let state = EventState {
+        tokens,
+        queue,
+        queue_position: None,
+        outcome: Some(outcome),
+        sql,
+        text: String::new(),
+        phase: EventPhase::Queued,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        loop {
+            match state.phase {
+                EventPhase::Queued => {
+                    let queue_state = *state.queue.borrow_and_update();
+                    match queue_state {
+                        QueueState::Waiting(position) if state.queue_position != Some(position) => {
+                            state.queue_position = Some(position);
+                            let event = sse_event("queued", &QueuedEvent { position });
+                            return Some((Ok(event), state));
+                        }
+                        QueueState::Waiting(_) => {
+                            // Wait for the next move in the queue
+                            if state.queue.changed().await.is_err() {
+                                state.phase = EventPhase::Tokens;
+                            }
+                        }
+                        QueueState::Running => state.phase = EventPhase::Tokens,
+                    }
+                }
+                EventPhase::Tokens => match state.tokens.recv().await {
+                    Some(token) if token.is_empty() => continue,
+                    Some(token) => {
+                        if state.sql.is_some() {
+                            state.text.push_str(token.as_str());
+                        }
+                        let event = sse_event("token", &TokenEvent { text: token.as_str() });
+                        return Some((Ok(event), state));
+                    }
+                    // The token channel closes when the generation ends
+                    None => state.phase = EventPhase::Outcome,
+                },
+                EventPhase::Outcome => {
+                    state.phase = EventPhase::Closed;
+                    let outcome = state.outcome.take()?;
+                    let outcome = outcome.await;
+                    let event = match &outcome {
+                        Ok(Ok(summary)) => sse_event("done", summary),
+                        Ok(Err(message)) => sse_event("error", &ErrorEvent { message: message.as_str() }),
+                        Err(_) => sse_event("error", &ErrorEvent { message: "generation ended unexpectedly" }),
+                    };
+                    // The answer of a failed generation is not checked
+                    if let Some(sql) = state.sql.take().filter(|_| matches!(outcome, Ok(Ok(_)))) {
+                        state.phase = EventPhase::Done(event);
+                        let event = sse_event("sql", &sql.check(state.text.as_str()));
+                        return Some((Ok(event), state));
+                    }
+                    return Some((Ok(event), state));
+                }
+                EventPhase::Done(event) => {
+                    state.phase = EventPhase::Closed;
+                    return Some((Ok(event), state));
+                }
+                EventPhase::Closed => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn queued_events_until_a_slot_is_granted() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (queue_tx, queue) = watch::channel(QueueState::Waiting(2));
+        let (outcome_tx, outcome_rx) = oneshot::channel();
+        let mut events = Box::pin(token_event_stream(rx, queue, outcome_rx, None)).map(|event| String::from_utf8(event.unwrap().to_vec()).unwrap());
+
+        assert_eq!(events.next().await.unwrap(), "event: queued\ndata: {\"position\":2}\n\n");
+        queue_tx.send(QueueState::Waiting(0)).unwrap();
+        assert_eq!(events.next().await.unwrap(), "event: queued\ndata: {\"position\":0}\n\n");
+
+        queue_tx.send(QueueState::Running).unwrap();
+        tx.send("Paris".to_string()).unwrap();
+        assert_eq!(events.next().await.unwrap(), "event: token\ndata: {\"text\":\"Paris\"}\n\n");
+
+        drop(tx);
+        outcome_tx.send(Err("out of memory".to_string())).unwrap();
+        assert_eq!(events.next().await.unwrap(), "event: error\ndata: {\"message\":\"out of memory\"}\n\n");
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn no_queued_event_when_running_right_away() {
+        let (tx, rx) = mpsc::unbounded_channel::<String>();
+        let (_queue_tx, queue) = watch::channel(QueueState::Running);
+        let (outcome_tx, outcome_rx) = oneshot::channel();
+        drop(tx);
+        drop(outcome_tx);
+
+        let events: Vec<_> = token_event_stream(rx, queue, outcome_rx, None).collect().await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].as_ref().unwrap(), &Bytes::from("event: error\ndata: {\"message\":\"generation ended unexpectedly\"}\n\n"));
+    }
+}
@@ -0,0 +1,263 @@
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::{oneshot, watch};
+use warp::ws::{Message, WebSocket};
+
+use crate::api::conversation::Conversation;
+use crate::llm::generation_summary::{FinishReason, GenerationOutcome, GenerationSummary};
+use crate::llm::sampling_params::SamplingOverrides;
+use crate::scheduler::generation_scheduler::QueueState;
+use crate::scheduler::generation_task::{GenerationChannels, GenerationService};
+
+/*****************************************************************/
+// Messages exchanged on /ws, as JSON text frames tagged by `type`
+/*****************************************************************/
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Ask a question, answered with the previous turns of the connection as history
+    Prompt {
+        query: String,
+        #[serde(default)]
+        profile: Option<String>,
+    },
+    /// Stop the running generation
+    Cancel,
+    /// Change the profile or sampling parameters of the next prompts
+    SetParams(Box<ConnectionParams>),
+    /// Forget the previous turns
+    Reset,
+}
+
+/// Parameters kept by a connection, given fields replace the current ones
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct ConnectionParams {
+    #[serde(default)]
+    profile: Option<String>,
+    #[serde(flatten)]
+    sampling: SamplingOverrides,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage<'a> {
+    Queued { position: usize },
+    Token { text: &'a str },
+    Done(GenerationSummary),
+    Params(&'a ConnectionParams),
+    Reset,
+    Error { message: String },
+}
+
+/*****************************************************************/
+// Generation running for a connection
+/*****************************************************************/
+
+enum GenerationEvent {
+    Queued(usize),
+    Token(String),
+    Finished(GenerationOutcome),
+}
+
+struct ActiveGeneration {
+    query: String,
+    answer: String,
+    /// Dropped on cancel, the generation stops at its next token
+    tokens: Option<UnboundedReceiver<String>>,
+    queue: watch::Receiver<QueueState>,
+    queue_position: Option<usize>,
+    waiting: bool,
+    outcome: oneshot::Receiver<GenerationOutcome>,
+}
+
+impl ActiveGeneration {
+    fn new(query: String, channels: GenerationChannels) -> Self {
+        Self {
+            query,
+            answer: String::new(),
+            tokens: Some(channels.tokens),
+            queue: channels.queue,
+            queue_position: None,
+            waiting: true,
+            outcome: channels.outcome,
+        }
+    }
+
+    fn cancel(&mut self) {
+        self.tokens = None;
+    }
+
+    async fn next_event(&mut self) -> GenerationEvent {
+        loop {
+            if self.waiting {
+                let queue_state = *self.queue.borrow_and_update();
+                match queue_state {
+                    QueueState::Waiting(position) if self.queue_position != Some(position) => {
+                        self.queue_position = Some(position);
+                        return GenerationEvent::Queued(position);
+                    }
+                    QueueState::Waiting(_) => {}
+                    QueueState::Running => self.waiting = false,
+                }
+            }
+
+            let tokens = match self.tokens.as_mut() {
+                Some(tokens) => tokens,
+                None => {
+                    let outcome = (&mut self.outcome).await;
+                    return GenerationEvent::Finished(
+                        outcome.unwrap_or_else(|_| Err("generation ended unexpectedly".to_string())),
+                    );
+                }
+            };
+
+            tokio::select! {
+                token = tokens.recv() => match token {
+                    Some(token) => {
+                        self.answer.push_str(token.as_str());
+                        return GenerationEvent::Token(token);
+                    }
+                    // The token channel closes when the generation ends
+                    None => self.tokens = None,
+                },
+                changed = self.queue.changed(), if self.waiting => {
+                    if changed.is_err() {
+                        self.waiting = false;
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn next_generation_event(active: &mut Option<ActiveGeneration>) -> GenerationEvent {
+    match active {
+        Some(generation) => generation.next_event().await,
+        None => std::future::pending().await,
+    }
+}
+
+/*****************************************************************/
+// Connection loop
+/*****************************************************************/
+
+/// Serve one websocket connection. The connection keeps its own parameters and
+/// conversation, and runs at most one generation at a time.
+pub async fn handle_socket(socket: WebSocket, service: GenerationService) {
+    let (mut ws_tx, mut ws_rx) = socket.split();
+
+    let mut params = ConnectionParams::default();
+    let mut conversation = Conversation::new();
+    let mut active: Option<ActiveGeneration> = None;
+
+    loop {
+        let reply = tokio::select! {
+            message = ws_rx.next() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    // Dropping the running generation, if any, cancels it
+                    _ => break,
+                };
+                if message.is_close() {
+                    break;
+                }
+                let text = match message.to_str() {
+                    Ok(text) => text,
+                    // Pings and binary frames are ignored
+                    Err(_) => continue,
+                };
+                match serde_json::from_str::<ClientMessage>(text) {
+                    Ok(client_message) => handle_client_message(client_message, &service, &mut params, &mut conversation, &mut active),
+                    Err(e) => Some(error_message(format!("invalid message: {}", e))),
+                }
+            }
+            event = next_generation_event(&mut active) => match event {
+                GenerationEvent::Queued(position) => Some(to_json(&ServerMessage::Queued { position })),
+                GenerationEvent::Token(text) => Some(to_json(&ServerMessage::Token { text: text.as_str() })),
+                GenerationEvent::Finished(outcome) => {
+                    let finished = active.take();
+                    match outcome {
+                        Ok(summary) => {
+                            // A cancelled answer is left out, later turns would follow a half written reply
+                            if let Some(generation) = finished.filter(|_| summary.finish_reason != FinishReason::Cancelled) {
+                                conversation.push_exchange(generation.query.as_str(), generation.answer.as_str());
+                            }
+                            Some(to_json(&ServerMessage::Done(summary)))
+                        }
+                        Err(message) => Some(error_message(message)),
+                    }
+                }
+            }
+        };
+
+        if let Some(json) = reply {
+            if ws_tx.send(Message::text(json)).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+fn handle_client_message(
+    client_message: ClientMessage,
+    service: &GenerationService,
+    params: &mut ConnectionParams,
+    conversation: &mut Conversation,
+    active: &mut Option<ActiveGeneration>,
+) -> Option<String> {
+    match client_message {
+        ClientMessage::Prompt { query, profile } => {
+            if active.is_some() {
+                return Some(error_message("a generation is already running, cancel it first"));
+            }
+            let profile = profile.or_else(|| params.profile.clone());
+            let (context, sampling) = match service.resolve(profile.as_deref(), &params.sampling) {
+                Ok(resolved) => resolved,
+                Err(e) => return Some(error_message(e.to_string())),
+            };
+            match service.spawn_conversation(conversation.history(), query.clone(), sampling, context) {
+                Ok(channels) => {
+                    *active = Some(ActiveGeneration::new(query, channels));
+                    None
+                }
+                Err(e) => Some(error_message(e.to_string())),
+            }
+        }
+        ClientMessage::Cancel => match active.as_mut() {
+            Some(generation) => {
+                // The `done` message follows, with a `cancelled` finish reason
+                generation.cancel();
+                None
+            }
+            None => Some(error_message("no generation to cancel")),
+        },
+        ClientMessage::SetParams(new_params) => {
+            let merged = ConnectionParams {
+                profile: new_params.profile.or_else(|| params.profile.clone()),
+                sampling: new_params.sampling.or(&params.sampling),
+            };
+            // Check the parameters now rather than on the next prompt
+            match service.resolve(merged.profile.as_deref(), &merged.sampling) {
+                Ok(_) => {
+                    *params = merged;
+                    Some(to_json(&ServerMessage::Params(params)))
+                }
+                Err(e) => Some(error_message(e.to_string())),
+            }
+        }
+        ClientMessage::Reset => {
+            conversation.clear();
+            Some(to_json(&ServerMessage::Reset))
+        }
+    }
+}
+
+fn error_message(message: impl Into<String>) -> String {
+    to_json(&ServerMessage::Error { message: message.into() })
+}
+
+fn to_json(message: &ServerMessage) -> String {
+    serde_json::to_string(message).unwrap_or_default()
+}
@@ -1,9 +1,97 @@
-use clap::{ Parser};
+use std::fmt;
+use clap::{ Parser, Subcommand, ValueEnum};
+use crate::llm::sampler::{SamplerKind, DEFAULT_SAMPLERS};
+use crate::llm::sampling_params::DEFAULT_MIROSTAT_ETA;
+
+
+/// Backend used to run the model, selected at startup
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelFamily {
+    /// Mistral gguf generated by candle tensor-tools
+    Mistral,
+    /// Phi-2 gguf generated by candle tensor-tools
+    #[value(name = "phi-v2")]
+    PhiV2,
+    /// Any gguf following llama formalism ( llama.cpp )
+    Llama,
+}
+
+impl ModelFamily {
+    pub fn default_model_id(&self) -> &'static str {
+        match self {
+            ModelFamily::Mistral => "lmz/candle-mistral",
+            ModelFamily::PhiV2 => "lmz/candle-quantized-phi",
+            ModelFamily::Llama => "TheBloke/MetaMath-Cybertron-Starling-GGUF",
+        }
+    }
+
+    pub fn default_model_file(&self) -> &'static str {
+        match self {
+            ModelFamily::Mistral => "model-q4k.gguf",
+            ModelFamily::PhiV2 => "model-v2-q4k.gguf",
+            ModelFamily::Llama => "metamath-cybertron-starling.Q4_K_M.gguf",
+        }
+    }
+
+    pub fn default_tokenizer_id(&self) -> &'static str {
+        match self {
+            ModelFamily::Mistral => "lmz/candle-mistral",
+            ModelFamily::PhiV2 => "lmz/candle-quantized-phi",
+            ModelFamily::Llama => "mistralai/Mistral-7B-Instruct-v0.2",
+        }
+    }
+}
+
+impl fmt::Display for ModelFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelFamily::Mistral => write!(f, "mistral"),
+            ModelFamily::PhiV2 => write!(f, "phi-v2"),
+            ModelFamily::Llama => write!(f, "llama"),
+        }
+    }
+}
+
+
+/// Modes other than the HTTP server
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Run the requests of a JSONL file through the model, without starting the HTTP server
+    Batch(BatchArgs),
+    /// Chat with the model in the terminal, without starting the HTTP server
+    Chat(ChatArgs),
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct BatchArgs {
+    /// JSONL file of requests : an id, a prompt, and optionally a profile and sampling parameters
+    #[arg(long)]
+    pub input: String,
+
+    /// JSONL file receiving the results. Requests already answered in it are not run again
+    #[arg(long)]
+    pub output: String,
+
+    /// Number of requests running at the same time
+    #[arg(long, default_value_t = 1)]
+    pub parallelism: usize,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ChatArgs {
+    /// File keeping the lines typed in previous sessions
+    #[arg(long)]
+    pub history_file: Option<String>,
+}
 
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
+    /// Mode to run, the HTTP server when omitted
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Run on CPU rather than on GPU.
     #[arg(long,default_value_t=true)]
     pub cpu: bool,
@@ -23,6 +111,30 @@ pub struct Args {
     #[arg(long,default_value_t=0.3)]
     pub top_p: f64,
 
+    /// Only sample among the k most likely tokens ( 0 disables it )
+    #[arg(long, default_value_t = 0)]
+    pub top_k: usize,
+
+    /// Only sample among the tokens at least min-p times as likely as the most likely one ( 0 disables it )
+    #[arg(long, default_value_t = 0.)]
+    pub min_p: f64,
+
+    /// Locally typical sampling probability mass ( 1 disables it )
+    #[arg(long, default_value_t = 1.)]
+    pub typical_p: f64,
+
+    /// Order of the sampler chain, applied after the temperature
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = DEFAULT_SAMPLERS)]
+    pub samplers: Vec<SamplerKind>,
+
+    /// Mirostat v2 target surprise, in bits. When set, mirostat replaces the sampler chain
+    #[arg(long)]
+    pub mirostat_tau: Option<f64>,
+
+    /// Mirostat v2 learning rate
+    #[arg(long, default_value_t = DEFAULT_MIROSTAT_ETA)]
+    pub mirostat_eta: f64,
+
     /// The seed to use when generating random samples.
     #[arg(long, default_value_t = 299792458)]
     pub seed: u64,
@@ -31,58 +143,46 @@ pub struct Args {
     #[arg(long, short = 'n', default_value_t = 2000)]
     pub sample_len: usize,
 
+    /// Stop generating when this text is produced, the text itself is not returned.
+    /// Can be given several times
+    #[arg(long)]
+    pub stop: Vec<String>,
+
+    /// Maximum generation time of a request, in seconds
+    #[arg(long)]
+    pub max_time: Option<f64>,
 
-    ////////////////////////////////////////////////////////////////
 
-    #[cfg(feature = "mistral")]
-    #[arg(long, default_value = "lmz/candle-mistral")]
-    pub model_id: String,
+    ////////////////////////////////////////////////////////////////
 
-    #[cfg(feature = "phi-v2")]
-    //#[arg(long, default_value = "microsoft/phi-2")]
-    #[arg(long, default_value = "lmz/candle-quantized-phi")]
-    pub model_id: String,
+    /// Model family ( mistral, phi-v2 or llama ). When omitted, it is read from the gguf
+    /// metadata of the given model file, or phi-v2 is used.
+    #[arg(long, value_enum)]
+    pub model_family: Option<ModelFamily>,
 
-    #[cfg(feature = "llama")]
-    #[arg(long, default_value = "mistral")]
-    pub model_type: String,
+    /// Built-in chat format used by the llama family ( mistral, open_chat ). When omitted, the
+    /// chat template of the model is used if it ships one, the mistral format otherwise
+    #[arg(long)]
+    pub model_type: Option<String>,
 
-    #[cfg(feature = "llama")]
-    #[arg(long, default_value = "TheBloke/MetaMath-Cybertron-Starling-GGUF")]
-    pub model_id: String,
+    /// Huggingface model repo, defaults depend on the model family
+    #[arg(long)]
+    pub model_id: Option<String>,
 
     ////////////////////////////////////////////////////////////////
 
     #[arg(long, default_value = "main")]
     pub revision: String,
 
-    #[cfg(feature = "phi-v2")]
-    #[arg(long, default_value = "model-v2-q4k.gguf")]
-    pub model_file: Option<String>,
-
-    #[cfg(feature = "mistral")]
-    #[arg(long, default_value = "model-q4k.gguf")]
-    pub model_file: Option<String>,
-
-    #[cfg(feature = "llama")]
-    #[arg(long, default_value = "metamath-cybertron-starling.Q4_K_M.gguf")]
+    /// Quantized gguf file in the model repo, defaults depend on the model family
+    #[arg(long)]
     pub model_file: Option<String>,
 
     ////////////////////////////////////////////////////////////////
 
-    #[cfg(feature = "mistral")]
-    #[arg(long, default_value = "lmz/candle-mistral")]
-    pub tokenizer_id: String,
-
-    #[cfg(feature = "phi-v2")]
-    //#[arg(long, default_value = "microsoft/phi-2")]
-    #[arg(long, default_value = "lmz/candle-quantized-phi")]
-    pub tokenizer_id: String,
-
-
-    #[cfg(feature = "llama")]
-    #[arg(long, default_value = "mistralai/Mistral-7B-Instruct-v0.2")]
-    pub tokenizer_id: String,
+    /// Huggingface tokenizer repo, defaults depend on the model family
+    #[arg(long)]
+    pub tokenizer_id: Option<String>,
 
     ////////////////////////////////////////////////////////////////
 
@@ -91,16 +191,17 @@ pub struct Args {
 
     ////////////////////////////////////////////////////////////////
 
-    /// Future use, to be able to use a local model file
+    /// Local gguf model file, loaded without any access to the Hugging Face hub
     #[arg(long)]
     pub local_model_file: Option<String>,
 
-    /// Future use, to be able to use a local tokenizer file
+    /// Local tokenizer.json, loaded without any access to the Hugging Face hub
     #[arg(long)]
     pub local_tokenizer_file: Option<String>,
 
     ////////////////////////////////////////////////////////////////
 
+    /// Comma separated local weight files, the first one is loaded
     #[arg(long)]
     pub weight_files: Option<String>,
 
@@ -113,6 +214,14 @@ pub struct Args {
     #[arg(long, default_value_t = 64)]
     pub repeat_last_n: usize,
 
+    /// Penalty subtracted from the logit of a token for each time it was generated, 0. means no penalty.
+    #[arg(long, default_value_t = 0., allow_negative_numbers = true)]
+    pub frequency_penalty: f32,
+
+    /// Penalty subtracted from the logit of a token once it was generated, 0. means no penalty.
+    #[arg(long, default_value_t = 0., allow_negative_numbers = true)]
+    pub presence_penalty: f32,
+
     ////////////////////////////////////////////////////////////////
 
     /// Group-Query Attention, use 8 for the 70B version of LLaMAv2.
@@ -124,6 +233,49 @@ pub struct Args {
     #[arg(long, default_value = "general")]
     pub context_type: String,
 
+    ////////////////////////////////////////////////////////////////
+
+    /// Maximum number of generations running at the same time.
+    #[arg(long, default_value_t = 2)]
+    pub max_concurrent_generations: usize,
+
+    /// Maximum number of requests waiting for a generation slot, others get a 503.
+    #[arg(long, default_value_t = 16)]
+    pub max_queue_size: usize,
+
+    /// Retry-After value ( in seconds ) sent with a 503 when the queue is full.
+    #[arg(long, default_value_t = 10)]
+    pub queue_retry_after: u64,
+
+    ////////////////////////////////////////////////////////////////
+
+    /// Maximum number of conversation sessions kept on the server, the least recently used one is dropped beyond.
+    #[arg(long, default_value_t = 1000)]
+    pub max_sessions: usize,
+
+    /// Sessions unused for this many seconds are dropped.
+    #[arg(long, default_value_t = 3600)]
+    pub session_idle_timeout: u64,
+
+    ////////////////////////////////////////////////////////////////
+
+    /// Address the HTTP server listens on ( default 127.0.0.1 )
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Port the HTTP server listens on ( default 3030 )
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Unix domain socket the HTTP server listens on, instead of the host and port
+    #[arg(long)]
+    pub unix_socket: Option<String>,
+
+    /// TOML file with a [server] table ( host, port, unix_socket ), overridden by the command line.
+    /// ./config/server_config.toml is read when it exists
+    #[arg(long)]
+    pub server_config: Option<String>,
+
 }
 
 impl Args {
@@ -132,5 +284,19 @@ impl Args {
         let args_init = Args::parse();
         args_init
     }
+
+    /// Fill the model, model file and tokenizer repos left empty with the family defaults
+    pub fn apply_model_family_defaults(&mut self, model_family: ModelFamily) {
+        self.model_family = Some(model_family);
+        if self.model_id.is_none() {
+            self.model_id = Some(model_family.default_model_id().to_string());
+        }
+        if self.model_file.is_none() {
+            self.model_file = Some(model_family.default_model_file().to_string());
+        }
+        if self.tokenizer_id.is_none() {
+            self.tokenizer_id = Some(model_family.default_tokenizer_id().to_string());
+        }
+    }
 }
 
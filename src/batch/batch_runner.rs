@@ -0,0 +1,245 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::task::JoinSet;
+
+use crate::api::api_error::ApiError;
+use crate::api::generation_response::{collect_generation, GenerationResponse};
+use crate::args_init::args::BatchArgs;
+use crate::llm::sampling_params::SamplingOverrides;
+use crate::scheduler::generation_task::GenerationService;
+
+/// One line of the input file
+#[derive(Deserialize, Debug, Clone)]
+pub struct BatchRequest {
+    /// Identifies the result, the line number when omitted
+    #[serde(default)]
+    pub id: Option<Value>,
+    #[serde(alias = "query")]
+    pub prompt: String,
+    /// Prompt profile, the one given by `--context-type` when omitted
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Optional overrides of the profile and command line sampling parameters
+    #[serde(flatten)]
+    pub sampling: SamplingOverrides,
+}
+
+/// One line of the output file, the answer or why the request failed
+#[derive(Serialize, Debug, Clone)]
+pub struct BatchResult {
+    pub id: Value,
+    #[serde(flatten)]
+    pub response: Option<GenerationResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// From the start of the request to its last token, queueing included
+    pub duration_secs: f64,
+}
+
+/*****************************************************************/
+// Run the requests of the input file, `parallelism` at a time, and append their
+// results to the output file as they end
+/*****************************************************************/
+pub async fn run_batch(service: GenerationService, args: BatchArgs) -> Result<()> {
+    let requests = read_requests(args.input.as_str())?;
+    let answered = keep_answered(args.output.as_str())?;
+
+    let pending: Vec<(Value, BatchRequest)> = requests.into_iter().filter(|(id, _)| !answered.contains(&id.to_string())).collect();
+    let total = pending.len();
+    println!("batch: {} requests to run, {} already answered in `{}`", total, answered.len(), args.output);
+
+    let mut output = open_output(args.output.as_str())?;
+    let parallelism = args.parallelism.max(1);
+    let mut running = JoinSet::new();
+    let mut pending = pending.into_iter();
+    let mut done = 0usize;
+    let mut failed = 0usize;
+
+    loop {
+        while running.len() < parallelism {
+            match pending.next() {
+                Some((id, request)) => {
+                    running.spawn(run_request(service.clone(), id, request));
+                }
+                None => break,
+            }
+        }
+        let result = match running.join_next().await {
+            Some(result) => result.context("batch request panicked")?,
+            None => break,
+        };
+
+        // One line per result, flushed so that an interrupted batch can be resumed
+        writeln!(output, "{}", serde_json::to_string(&result)?)?;
+        output.flush()?;
+
+        done += 1;
+        if let Some(error) = &result.error {
+            failed += 1;
+            eprintln!("batch: request {} failed: {}", result.id, error);
+        }
+        println!("batch: {}/{} requests done in {:.2}s", done, total, result.duration_secs);
+    }
+
+    println!("batch: {} requests run, {} failed, results in `{}`", done, failed, args.output);
+    Ok(())
+}
+
+async fn run_request(service: GenerationService, id: Value, request: BatchRequest) -> BatchResult {
+    let start = Instant::now();
+
+    let response: Result<GenerationResponse, ApiError> = async {
+        let (context, sampling) = service.resolve(request.profile.as_deref(), &request.sampling)?;
+        let channels = service.spawn(request.prompt, sampling, context)?;
+        collect_generation(channels.tokens, channels.outcome, None).await
+    }
+    .await;
+
+    let duration_secs = start.elapsed().as_secs_f64();
+    match response {
+        Ok(response) => BatchResult { id, response: Some(response), error: None, duration_secs },
+        Err(err) => BatchResult { id, response: None, error: Some(err.to_string()), duration_secs },
+    }
+}
+
+/// Requests of the input file, along with their id. Lines which cannot be read are left out.
+fn read_requests(input: &str) -> Result<Vec<(Value, BatchRequest)>> {
+    let file = File::open(input).with_context(|| format!("Could not read file `{}`", input))?;
+
+    let mut requests = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<BatchRequest>(line.as_str()) {
+            Ok(request) => {
+                let id = request.id.clone().unwrap_or_else(|| Value::from(index + 1));
+                requests.push((id, request));
+            }
+            Err(e) => eprintln!("batch: line {} of `{}` left out: {}", index + 1, input, e),
+        }
+    }
+    Ok(requests)
+}
+
+/// Rewrite the output of a previous run with its answered requests only, and return their ids.
+/// The failed requests, and the line cut by an interrupted run, are left out so that each id
+/// has a single result once they are run again.
+fn keep_answered(output: &str) -> Result<HashSet<String>> {
+    let contents = match fs::read_to_string(output) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e).with_context(|| format!("Could not read file `{}`", output)),
+    };
+
+    let mut answered = HashSet::new();
+    let mut kept = String::new();
+    for line in contents.lines() {
+        let Ok(result) = serde_json::from_str::<Value>(line) else { continue };
+        match result.get("id") {
+            Some(id) if result.get("error").is_none() && answered.insert(id.to_string()) => {
+                kept.push_str(line);
+                kept.push('\n');
+            }
+            _ => {}
+        }
+    }
+
+    if kept != contents {
+        // Written aside then renamed, so that an interruption cannot lose the answered requests
+        let rewritten = format!("{}.tmp", output);
+        fs::write(rewritten.as_str(), kept).with_context(|| format!("Could not write file `{}`", rewritten))?;
+        fs::rename(rewritten.as_str(), output).with_context(|| format!("Could not replace file `{}`", output))?;
+    }
+    Ok(answered)
+}
+
+/// Open the output file for appending
+fn open_output(output: &str) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(output)
+        .with_context(|| format!("Could not open file `{}`", output))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("batch_{}_{}.jsonl", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn requests_are_numbered_by_line() {
+        let input = temp_file("requests", "{\"prompt\":\"a\"}\n\n{\"id\":\"q2\",\"query\":\"b\"}\nnot json\n{\"prompt\":\"c\"}\n");
+        let requests = read_requests(input.to_str().unwrap()).unwrap();
+        fs::remove_file(&input).unwrap();
+
+        let ids = requests.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids, vec![Value::from(1), Value::from("q2"), Value::from(5)]);
+        assert_eq!(requests[1].1.prompt, "b");
+    }
+
+    #[test]
+    fn resume_keeps_the_answered_requests_only() {
+        let output = temp_file(
+            "resume",
+            concat!(
+                "{\"id\":1,\"text\":\"one\",\"duration_secs\":1.0}\n",
+                "{\"id\":\"q2\",\"error\":\"queue full\",\"duration_secs\":0.1}\n",
+                "{\"id\":3,\"text\":\"three\",\"duration_secs\":1.0}\n",
+                "{\"id\":4,\"text\":\"fo",
+            ),
+        );
+        let answered = keep_answered(output.to_str().unwrap()).unwrap();
+        assert_eq!(answered, HashSet::from(["1".to_string(), "3".to_string()]));
+
+        // The result of a request run again is the only one of its id
+        let mut file = open_output(output.to_str().unwrap()).unwrap();
+        writeln!(file, "{{\"id\":\"q2\",\"text\":\"two\",\"duration_secs\":1.0}}").unwrap();
+        drop(file);
+        let contents = fs::read_to_string(&output).unwrap();
+        fs::remove_file(&output).unwrap();
+        assert_eq!(
+            contents,
+            concat!(
+                "{\"id\":1,\"text\":\"one\",\"duration_secs\":1.0}\n",
+                "{\"id\":3,\"text\":\"three\",\"duration_secs\":1.0}\n",
+                "{\"id\":\"q2\",\"text\":\"two\",\"duration_secs\":1.0}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn complete_output_is_left_untouched() {
+        let contents = "{\"id\":1,\"text\":\"one\",\"duration_secs\":1.0}\n";
+        let output = temp_file("untouched", contents);
+        let modified = fs::metadata(&output).unwrap().modified().unwrap();
+
+        let answered = keep_answered(output.to_str().unwrap()).unwrap();
+        assert_eq!(answered, HashSet::from(["1".to_string()]));
+        assert_eq!(fs::metadata(&output).unwrap().modified().unwrap(), modified);
+        assert_eq!(fs::read_to_string(&output).unwrap(), contents);
+        fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn no_previous_output() {
+        let output = std::env::temp_dir().join(format!("batch_missing_{}.jsonl", std::process::id()));
+        assert!(keep_answered(output.to_str().unwrap()).unwrap().is_empty());
+        assert!(!output.exists());
+    }
+}
@@ -0,0 +1 @@
+pub mod batch_runner;
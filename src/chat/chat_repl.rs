@@ -0,0 +1,205 @@
+use std::io::Write;
+
+use anyhow::Result;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use serde_json::{Map, Value};
+
+use crate::api::conversation::Conversation;
+use crate::args_init::args::ChatArgs;
+use crate::llm::generation_summary::FinishReason;
+use crate::llm::sampling_params::SamplingOverrides;
+use crate::scheduler::generation_task::GenerationService;
+
+const HELP: &str = "\
+/context <profile>   switch to another prompt profile, e.g. /context sql
+/set <name> <value>  change a sampling parameter, e.g. /set temperature 0.7
+/params              show the profile and the sampling parameters set
+/reset               clear the conversation
+/quit                leave, as does Ctrl-D
+Ctrl-C stops the answer being written";
+
+/// Profile and sampling parameters of the session, the ones set override the profile
+#[derive(Debug, Clone, Default)]
+struct ChatSettings {
+    profile: Option<String>,
+    sampling: SamplingOverrides,
+}
+
+enum ReplCommand {
+    Context(String),
+    Set(String, String),
+    Params,
+    Reset,
+    Help,
+}
+
+/*****************************************************************/
+// Read the prompts from the terminal, and stream the answers, each one following
+// the previous turns of the conversation
+/*****************************************************************/
+pub async fn run_chat(service: GenerationService, args: ChatArgs) -> Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    if let Some(history_file) = &args.history_file {
+        // The file does not exist before the first session
+        let _ = editor.load_history(history_file);
+    }
+
+    let mut settings = ChatSettings::default();
+    let mut conversation = Conversation::new();
+    println!("Chat with the model, /help lists the commands");
+
+    loop {
+        let line = match tokio::task::block_in_place(|| editor.readline(">> ")) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+
+        if line == "/quit" || line == "/exit" {
+            break;
+        }
+        if line.starts_with('/') {
+            match parse_command(line).and_then(|command| apply_command(command, &service, &mut settings, &mut conversation)) {
+                Ok(message) => println!("{}", message),
+                Err(e) => eprintln!("{}", e),
+            }
+            continue;
+        }
+
+        match ask(&service, &settings, &conversation, line).await {
+            Ok(Some(answer)) => conversation.push_exchange(line, answer.as_str()),
+            // The answer stopped by Ctrl-C is left out of the conversation
+            Ok(None) => {}
+            Err(e) => eprintln!("\ngeneration failed: {}", e),
+        }
+    }
+
+    if let Some(history_file) = &args.history_file {
+        if let Err(e) = editor.save_history(history_file) {
+            eprintln!("Unable to save the history to `{}`: {}", history_file, e);
+        }
+    }
+    Ok(())
+}
+
+/// Stream the answer to the terminal. Ctrl-C drops the token channel, which stops the generation,
+/// no answer is returned then
+async fn ask(service: &GenerationService, settings: &ChatSettings, conversation: &Conversation, query: &str) -> Result<Option<String>, String> {
+    let (context, sampling) = service.resolve(settings.profile.as_deref(), &settings.sampling).map_err(|e| e.to_string())?;
+    let channels = service
+        .spawn_conversation(conversation.history(), query.to_string(), sampling, context)
+        .map_err(|e| e.to_string())?;
+
+    let mut tokens = channels.tokens;
+    let mut answer = String::new();
+    let mut interrupted = false;
+    // Listen once for the whole answer, so that a ctrl-c between two tokens is not missed
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        tokio::select! {
+            token = tokens.recv() => match token {
+                Some(token) => {
+                    print!("{}", token);
+                    let _ = std::io::stdout().flush();
+                    answer.push_str(token.as_str());
+                }
+                // The token channel closes when the generation ends
+                None => break,
+            },
+            _ = &mut ctrl_c => {
+                interrupted = true;
+                break;
+            }
+        }
+    }
+    drop(tokens);
+
+    let summary = match channels.outcome.await {
+        Ok(Ok(summary)) => summary,
+        Ok(Err(message)) => return Err(message),
+        Err(_) => return Err("generation ended unexpectedly".to_string()),
+    };
+    println!(
+        "\n[{}, {} tokens, {:.2} token/s]",
+        summary.finish_reason, summary.completion_tokens, summary.tokens_per_second
+    );
+    Ok(Some(answer).filter(|_| !interrupted && summary.finish_reason != FinishReason::Cancelled))
+}
+
+fn parse_command(line: &str) -> Result<ReplCommand, String> {
+    let (name, argument) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let argument = argument.trim();
+
+    match name {
+        "/context" if !argument.is_empty() => Ok(ReplCommand::Context(argument.to_string())),
+        "/context" => Err("usage: /context <profile>".to_string()),
+        "/set" => match argument.split_once(char::is_whitespace) {
+            Some((parameter, value)) => Ok(ReplCommand::Set(parameter.to_string(), value.trim().to_string())),
+            None => Err("usage: /set <name> <value>".to_string()),
+        },
+        "/params" => Ok(ReplCommand::Params),
+        "/reset" => Ok(ReplCommand::Reset),
+        "/help" => Ok(ReplCommand::Help),
+        _ => Err(format!("unknown command `{}`, /help lists them", name)),
+    }
+}
+
+/// Apply a command, the parameters are checked before they replace the current ones
+fn apply_command(command: ReplCommand, service: &GenerationService, settings: &mut ChatSettings, conversation: &mut Conversation) -> Result<String, String> {
+    match command {
+        ReplCommand::Context(profile) => {
+            service.resolve(Some(profile.as_str()), &settings.sampling).map_err(|e| e.to_string())?;
+            let message = format!("profile `{}`, /reset clears the previous turns", profile);
+            settings.profile = Some(profile);
+            Ok(message)
+        }
+        ReplCommand::Set(name, value) => {
+            let sampling = set_parameter(&settings.sampling, name.as_str(), value.as_str())?;
+            service.resolve(settings.profile.as_deref(), &sampling).map_err(|e| e.to_string())?;
+            settings.sampling = sampling;
+            Ok(format!("{} = {}", name, value))
+        }
+        ReplCommand::Params => {
+            let set = match serde_json::to_value(&settings.sampling) {
+                Ok(Value::Object(fields)) => fields.into_iter().filter(|(_, value)| !value.is_null()).collect::<Map<_, _>>(),
+                _ => Map::new(),
+            };
+            Ok(format!(
+                "profile: {}\nparameters: {}",
+                settings.profile.as_deref().unwrap_or("default"),
+                Value::Object(set)
+            ))
+        }
+        ReplCommand::Reset => {
+            conversation.clear();
+            Ok("conversation cleared".to_string())
+        }
+        ReplCommand::Help => Ok(HELP.to_string()),
+    }
+}
+
+/// Sampling parameters with `name` set. Values are read as JSON, plain words as strings
+fn set_parameter(sampling: &SamplingOverrides, name: &str, value: &str) -> Result<SamplingOverrides, String> {
+    let value = serde_json::from_str::<Value>(value).unwrap_or_else(|_| Value::String(value.to_string()));
+    let mut fields = Map::new();
+    fields.insert(name.to_string(), value);
+    let overrides: SamplingOverrides = serde_json::from_value(Value::Object(fields)).map_err(|e| format!("invalid `{}`: {}", name, e))?;
+
+    // Unknown names are left out by the deserialization
+    let is_set = serde_json::to_value(&overrides)
+        .ok()
+        .and_then(|overrides| overrides.get(name).cloned())
+        .is_some_and(|value| !value.is_null());
+    if !is_set {
+        return Err(format!("unknown parameter `{}`", name));
+    }
+    Ok(overrides.or(sampling))
+}
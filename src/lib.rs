@@ -1,2 +1,8 @@
 pub mod llm;
 pub mod args_init;
+pub mod api;
+pub mod scheduler;
+pub mod prompt_config;
+pub mod server_config;
+pub mod batch;
+pub mod chat;
@@ -0,0 +1,273 @@
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use minijinja::{context, Environment, ErrorKind};
+use serde::Serialize;
+use serde_json::Value;
+use tokenizers::Tokenizer;
+
+use crate::llm::prompt_template::Exchange;
+
+/// Stands for the first user message, to find where the context part of the prompt ends
+const PROMPT_MARKER: &str = "PROMPT_MARKER_5b1e";
+
+/// A message of the conversation, as chat templates expect them
+#[derive(Serialize, Debug, Clone)]
+pub struct TemplateMessage {
+    pub role: &'static str,
+    pub content: String,
+}
+
+/// Jinja chat template shipped with a model, in its gguf metadata or its `tokenizer_config.json`
+pub struct ChatTemplate {
+    source: String,
+    bos_token: String,
+    eos_token: String,
+    /// The template accepts a system message, otherwise the context opens the first user message
+    system_role: bool,
+    /// Tokens ending an assistant turn
+    stop_tokens: Vec<String>,
+    environment: Environment<'static>,
+}
+
+impl ChatTemplate {
+    /// `special_tokens` are the special tokens of the tokenizer, the ones used by the template
+    /// end the assistant turns
+    pub fn new(source: String, bos_token: String, eos_token: String, special_tokens: &[String]) -> Result<Self> {
+        let mut environment = Environment::new();
+        // Same whitespace handling as the python transformers library
+        environment.set_trim_blocks(true);
+        environment.set_lstrip_blocks(true);
+        environment.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        environment.add_function("raise_exception", |message: String| -> Result<String, minijinja::Error> {
+            Err(minijinja::Error::new(ErrorKind::InvalidOperation, message))
+        });
+
+        let mut stop_tokens = vec![eos_token.clone()];
+        for token in special_tokens {
+            if !token.is_empty() && *token != bos_token && source.contains(token.as_str()) && !stop_tokens.contains(token) {
+                stop_tokens.push(token.clone());
+            }
+        }
+        stop_tokens.retain(|token| !token.is_empty());
+
+        let mut chat_template = Self { source, bos_token, eos_token, system_role: false, stop_tokens, environment };
+
+        let conversation = [
+            message("user", "Hello"),
+            message("assistant", "Hello, how can I help ?"),
+            message("user", "Where is located Paris ?"),
+        ];
+        chat_template
+            .render(&conversation, true)
+            .map_err(|e| anyhow!("invalid chat template: {}", e))?;
+
+        // Templates without system role usually raise an exception on one
+        chat_template.system_role = chat_template
+            .render(&[message("system", "You are a helpful assistant"), message("user", "Hello")], true)
+            .is_ok();
+
+        Ok(chat_template)
+    }
+
+    pub fn render(&self, messages: &[TemplateMessage], add_generation_prompt: bool) -> Result<String, minijinja::Error> {
+        self.environment.render_str(
+            self.source.as_str(),
+            context! {
+                messages => messages,
+                add_generation_prompt => add_generation_prompt,
+                bos_token => self.bos_token.as_str(),
+                eos_token => self.eos_token.as_str(),
+            },
+        )
+    }
+
+    /// Part of the prompt which only depends on the context, empty when the template
+    /// does not render the first user message verbatim
+    pub fn prefix(&self, context: &str) -> Result<String> {
+        let messages = self.messages(context.trim(), &[], PROMPT_MARKER);
+        let rendered = self.render(&messages, false).map_err(|e| anyhow!("chat template error: {}", e))?;
+        Ok(match rendered.find(PROMPT_MARKER) {
+            Some(end) => rendered[..end].to_string(),
+            None => String::new(),
+        })
+    }
+
+    /// Full prompt, ending with the opening of the assistant turn
+    pub fn render_conversation(&self, context: &str, history: &[Exchange], prompt: &str) -> Result<String> {
+        let messages = self.messages(context.trim(), history, prompt.trim());
+        self.render(&messages, true).map_err(|e| anyhow!("chat template error: {}", e))
+    }
+
+    pub fn stop_tokens(&self) -> &[String] {
+        &self.stop_tokens
+    }
+
+    fn messages(&self, context: &str, history: &[Exchange], prompt: &str) -> Vec<TemplateMessage> {
+        let mut messages = Vec::with_capacity(2 * history.len() + 2);
+        if self.system_role && !context.is_empty() {
+            messages.push(message("system", context));
+        }
+        for exchange in history {
+            messages.push(message("user", exchange.user.trim()));
+            messages.push(message("assistant", exchange.assistant.trim()));
+        }
+        messages.push(message("user", prompt));
+
+        if !self.system_role && !context.is_empty() {
+            messages[0].content = format!("{}\n\n{}", context, messages[0].content);
+        }
+        messages
+    }
+}
+
+impl fmt::Debug for ChatTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChatTemplate")
+            .field("source", &self.source)
+            .field("system_role", &self.system_role)
+            .field("stop_tokens", &self.stop_tokens)
+            .finish()
+    }
+}
+
+impl PartialEq for ChatTemplate {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source && self.bos_token == other.bos_token && self.eos_token == other.eos_token
+    }
+}
+
+fn message(role: &'static str, content: &str) -> TemplateMessage {
+    TemplateMessage { role, content: content.to_string() }
+}
+
+/// Chat template from the gguf metadata first, then from `tokenizer_config.json`.
+/// `bos_token_id` and `eos_token_id` come from the gguf metadata, the tokenizer config
+/// is used when they are absent.
+pub fn load_chat_template(
+    gguf_template: Option<&str>,
+    tokenizer_config: Option<&Path>,
+    tokenizer: &Tokenizer,
+    bos_token_id: Option<u32>,
+    eos_token_id: Option<u32>,
+) -> Result<Option<ChatTemplate>> {
+    let config = match tokenizer_config {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path)?;
+            serde_json::from_str::<Value>(contents.as_str())
+                .map_err(|e| anyhow!("invalid tokenizer config `{}`: {}", path.display(), e))?
+        }
+        None => Value::Null,
+    };
+
+    let source = match gguf_template {
+        Some(source) => source.to_string(),
+        None => match config_template(&config) {
+            Some(source) => source,
+            None => return Ok(None),
+        },
+    };
+
+    let token = |id: Option<u32>, key: &str| {
+        id.and_then(|id| tokenizer.id_to_token(id))
+            .or_else(|| config_token(&config, key))
+            .unwrap_or_default()
+    };
+    let bos_token = token(bos_token_id, "bos_token");
+    let eos_token = token(eos_token_id, "eos_token");
+
+    let special_tokens = tokenizer
+        .get_added_tokens_decoder()
+        .into_values()
+        .filter(|token| token.special)
+        .map(|token| token.content)
+        .collect::<Vec<_>>();
+
+    Ok(Some(ChatTemplate::new(source, bos_token, eos_token, &special_tokens)?))
+}
+
+/// `chat_template` is either the template, or a list of named templates
+fn config_template(config: &Value) -> Option<String> {
+    match config.get("chat_template")? {
+        Value::String(source) => Some(source.clone()),
+        Value::Array(templates) => templates
+            .iter()
+            .find(|template| template.get("name").and_then(Value::as_str) == Some("default"))
+            .and_then(|template| template.get("template"))
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        _ => None,
+    }
+}
+
+/// Special tokens are either their content, or an object with a `content` field
+fn config_token(config: &Value, key: &str) -> Option<String> {
+    match config.get(key)? {
+        Value::String(token) => Some(token.clone()),
+        Value::Object(token) => token.get("content").and_then(Value::as_str).map(str::to_string),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHATML: &str = "{% for message in messages %}{{ '<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n' }}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}";
+
+    /// Mistral instruct template, which raises an exception on a system message
+    const NO_SYSTEM: &str = "{{ bos_token }}{% for message in messages %}{% if message['role'] == 'system' %}{{ raise_exception('System role not supported') }}{% endif %}{% if message['role'] == 'user' %}{{ '[INST] ' + message['content'] + ' [/INST]' }}{% else %}{{ message['content'] + eos_token }}{% endif %}{% endfor %}";
+
+    fn chat_template(source: &str) -> ChatTemplate {
+        let special_tokens = ["<s>", "</s>", "<|im_start|>", "<|im_end|>"].map(str::to_string);
+        ChatTemplate::new(source.to_string(), "<s>".to_string(), "</s>".to_string(), &special_tokens).unwrap()
+    }
+
+    fn exchange(user: &str, assistant: &str) -> Exchange {
+        Exchange { user: user.to_string(), assistant: assistant.to_string() }
+    }
+
+    #[test]
+    fn chatml_context_is_a_system_message() {
+        let template = chat_template(CHATML);
+        assert!(template.system_role);
+        assert_eq!(template.stop_tokens(), ["</s>", "<|im_start|>", "<|im_end|>"]);
+
+        let rendered = template.render_conversation(" Be brief ", &[exchange("Hi", "Hello")], "Where is Paris ?").unwrap();
+        assert_eq!(
+            rendered,
+            "<|im_start|>system\nBe brief<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\nHello<|im_end|>\n<|im_start|>user\nWhere is Paris ?<|im_end|>\n<|im_start|>assistant\n"
+        );
+        let prefix = template.prefix("Be brief").unwrap();
+        assert_eq!(prefix, "<|im_start|>system\nBe brief<|im_end|>\n<|im_start|>user\n");
+        assert!(rendered.starts_with(prefix.as_str()));
+    }
+
+    #[test]
+    fn context_opens_the_first_user_message_without_system_role() {
+        let template = chat_template(NO_SYSTEM);
+        assert!(!template.system_role);
+
+        let rendered = template.render_conversation("Be brief", &[exchange("Hi", "Hello")], "Where is Paris ?").unwrap();
+        assert_eq!(rendered, "<s>[INST] Be brief\n\nHi [/INST]Hello</s>[INST] Where is Paris ? [/INST]");
+        assert_eq!(template.prefix("Be brief").unwrap(), "<s>[INST] Be brief\n\n");
+    }
+
+    #[test]
+    fn render_errors_are_returned() {
+        // Accepts the probe conversation of three messages, but no longer ones
+        let source = format!("{{% if messages|length > 3 %}}{{{{ raise_exception('Conversation too long') }}}}{{% endif %}}{}", CHATML);
+        let template = chat_template(source.as_str());
+        assert!(template.render_conversation("", &[exchange("Hi", "Hello")], "Where is Paris ?").is_ok());
+
+        let error = template.render_conversation("", &[exchange("Hi", "Hello"), exchange("Hi", "Hello")], "Where is Paris ?").unwrap_err();
+        assert!(error.to_string().contains("Conversation too long"), "{}", error);
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        let error = ChatTemplate::new("{% for message in messages %}".to_string(), String::new(), String::new(), &[]).unwrap_err();
+        assert!(error.to_string().starts_with("invalid chat template"), "{}", error);
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{bail, Error as E, Result};
+use candle::{DType, Tensor};
+use serde::Serialize;
+use tokenizers::Tokenizer;
+
+use crate::llm::prompt_template::encode_prompt;
+use crate::llm::sampling_params::InvalidParameter;
+
+/// Upper bound accepted for the number of labels of a request
+pub const MAX_LABELS: usize = 32;
+
+/// Probability of one label, normalized over the labels of the request
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LabelScore {
+    pub label: String,
+    /// From the log probability per token, so that long labels are not penalized for their length
+    pub probability: f64,
+    /// Log probability of the label tokens following the prompt, summed over the tokens
+    pub logprob: f64,
+    /// Number of tokens of the label
+    pub tokens: usize,
+}
+
+/// Labels ranked from the most to the least likely
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Classification {
+    /// Most likely label
+    pub label: String,
+    pub scores: Vec<LabelScore>,
+    pub prompt_tokens: usize,
+}
+
+/// Labels must be distinct, non blank, and at most MAX_LABELS
+pub fn validate_labels(labels: &[String]) -> Result<(), InvalidParameter> {
+    let invalid = |message: String| InvalidParameter { field: "labels", message };
+
+    if labels.is_empty() || labels.len() > MAX_LABELS {
+        return Err(invalid(format!("must hold between 1 and {} labels", MAX_LABELS)));
+    }
+    if labels.iter().any(|label| label.trim().is_empty()) {
+        return Err(invalid("labels cannot be blank".to_string()));
+    }
+    if labels.iter().enumerate().any(|(i, label)| labels[..i].contains(label)) {
+        return Err(invalid("each label can only appear once".to_string()));
+    }
+    Ok(())
+}
+
+/// The label as the model would write it after the prompt, separated by a space
+/// unless the prompt already ends with a whitespace
+pub fn label_continuation(prompt: &str, label: &str) -> String {
+    let label = label.trim();
+    if prompt.ends_with(char::is_whitespace) {
+        label.to_string()
+    } else {
+        format!(" {}", label)
+    }
+}
+
+/// Tokens of `label` following the prompt. The label is tokenized along with the prompt,
+/// or on its own when its first token merges with the end of the prompt.
+pub fn label_tokens(tokenizer: &Tokenizer, prompt: &str, prompt_tokens: &[u32], label: &str) -> Result<Vec<u32>> {
+    let continuation = label_continuation(prompt, label);
+
+    let tokens = encode_prompt(tokenizer, format!("{}{}", prompt, continuation).as_str())?;
+    if tokens.len() > prompt_tokens.len() && tokens.starts_with(prompt_tokens) {
+        return Ok(tokens[prompt_tokens.len()..].to_vec());
+    }
+
+    let tokens = tokenizer.encode(continuation, false).map_err(E::msg)?.get_ids().to_vec();
+    if tokens.is_empty() {
+        bail!("label `{}` has no token", label);
+    }
+    Ok(tokens)
+}
+
+/// Log probability of `token` given the logits of the next token
+pub fn token_logprob(logits: &Tensor, token: u32) -> Result<f64> {
+    let logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    let logit = match logits.get(token as usize) {
+        Some(logit) => *logit as f64,
+        None => bail!("token {} is not in the vocabulary", token),
+    };
+    let max = logits.iter().fold(f32::NEG_INFINITY, |max, l| max.max(*l)) as f64;
+    let sum = logits.iter().map(|l| (*l as f64 - max).exp()).sum::<f64>();
+    Ok(logit - max - sum.ln())
+}
+
+/// Normalize the log probabilities per token of the labels into probabilities, most likely label first.
+/// Labels of equal probability keep the order of the request
+pub fn rank(labels: &[String], logprobs: &[f64], token_counts: &[usize], prompt_tokens: usize) -> Classification {
+    let per_token = logprobs
+        .iter()
+        .zip(token_counts)
+        .map(|(logprob, count)| logprob / (*count).max(1) as f64)
+        .collect::<Vec<_>>();
+    let max = per_token.iter().fold(f64::NEG_INFINITY, |max, l| max.max(*l));
+    let sum = per_token.iter().map(|l| (l - max).exp()).sum::<f64>();
+
+    let mut scores = labels
+        .iter()
+        .zip(logprobs)
+        .zip(token_counts)
+        .zip(&per_token)
+        .map(|(((label, logprob), count), per_token)| LabelScore {
+            label: label.clone(),
+            probability: (per_token - max).exp() / sum,
+            logprob: *logprob,
+            tokens: *count,
+        })
+        .collect::<Vec<_>>();
+    scores.sort_by(|a, b| b.probability.total_cmp(&a.probability));
+
+    Classification {
+        label: scores.first().map(|score| score.label.clone()).unwrap_or_default(),
+        scores,
+        prompt_tokens,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn labels(labels: &[&str]) -> Vec<String> {
+        labels.iter().map(|label| label.to_string()).collect()
+    }
+
+    /// Byte pair tokenizer with sentencepiece like spaces, " w" merges into one token
+    fn tokenizer() -> Tokenizer {
+        let vocabulary = ["<unk>", "l", "o", "w", "▁", "lo", "low", "▁w"];
+        let vocab = vocabulary.iter().enumerate().map(|(i, token)| (token.to_string(), serde_json::Value::from(i))).collect::<serde_json::Map<_, _>>();
+        let json = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": { "type": "Replace", "pattern": { "String": " " }, "content": "▁" },
+            "pre_tokenizer": null,
+            "post_processor": null,
+            "decoder": null,
+            "model": { "type": "BPE", "vocab": vocab, "merges": ["l o", "lo w", "▁ w"], "unk_token": "<unk>" }
+        });
+        Tokenizer::from_str(json.to_string().as_str()).unwrap()
+    }
+
+    #[test]
+    fn labels_are_validated() {
+        assert!(validate_labels(&labels(&["yes", "no"])).is_ok());
+        assert!(validate_labels(&[]).is_err());
+        assert!(validate_labels(&vec!["label".to_string(); MAX_LABELS + 1]).is_err());
+        assert!(validate_labels(&labels(&["yes", " "])).is_err());
+        assert!(validate_labels(&labels(&["yes", "no", "yes"])).is_err());
+    }
+
+    #[test]
+    fn continuation_is_separated_by_one_space() {
+        assert_eq!(label_continuation("Category:", " Electronics "), " Electronics");
+        assert_eq!(label_continuation("Category:\n", "Electronics"), "Electronics");
+    }
+
+    #[test]
+    fn label_tokens_follow_the_prompt_tokens() {
+        let tokenizer = tokenizer();
+        let prompt_tokens = encode_prompt(&tokenizer, "lo").unwrap();
+        assert_eq!(prompt_tokens, vec![5]);
+        // "lo low" is "lo", "▁", "low"
+        assert_eq!(label_tokens(&tokenizer, "lo", &prompt_tokens, "low").unwrap(), vec![4, 6]);
+    }
+
+    #[test]
+    fn label_merging_with_the_prompt_is_tokenized_alone() {
+        let tokenizer = tokenizer();
+        let prompt_tokens = encode_prompt(&tokenizer, "lo ").unwrap();
+        assert_eq!(prompt_tokens, vec![5, 4]);
+        // "lo w" is "lo", "▁w" : the space of the prompt merges with the label
+        assert_eq!(label_tokens(&tokenizer, "lo ", &prompt_tokens, "w").unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn logprob_of_the_next_token() {
+        let logits = Tensor::new(&[0.5f32.ln(), 0.25f32.ln(), 0.25f32.ln()], &candle::Device::Cpu).unwrap();
+        assert!((token_logprob(&logits, 0).unwrap() - 0.5f64.ln()).abs() < 1e-6);
+        assert!((token_logprob(&logits, 2).unwrap() - 0.25f64.ln()).abs() < 1e-6);
+        assert!(token_logprob(&logits, 3).is_err());
+    }
+
+    #[test]
+    fn probabilities_are_normalized_over_the_labels() {
+        let classification = rank(&labels(&["a", "b", "c"]), &[0.2f64.ln(), 0.6f64.ln(), 0.2f64.ln()], &[1, 1, 1], 12);
+        assert_eq!(classification.label, "b");
+        assert_eq!(classification.prompt_tokens, 12);
+        let probabilities = classification.scores.iter().map(|score| score.probability).collect::<Vec<_>>();
+        for (probability, expected) in probabilities.iter().zip([0.6, 0.2, 0.2]) {
+            assert!((probability - expected).abs() < 1e-9, "{:?}", probabilities);
+        }
+    }
+
+    #[test]
+    fn ties_keep_the_request_order() {
+        let classification = rank(&labels(&["a", "b", "c"]), &[-2., -1., -1.], &[1, 1, 1], 0);
+        let ranked = classification.scores.iter().map(|score| score.label.as_str()).collect::<Vec<_>>();
+        assert_eq!(ranked, vec!["b", "c", "a"]);
+    }
+
+    #[test]
+    fn long_labels_are_ranked_per_token() {
+        // The three tokens label is less likely in total, but more likely per token
+        let classification = rank(&labels(&["short", "long"]), &[-1.2, -1.5], &[1, 3], 0);
+        assert_eq!(classification.label, "long");
+        assert_eq!(classification.scores[0].logprob, -1.5);
+        assert_eq!(classification.scores[0].tokens, 3);
+        let sum = classification.scores.iter().map(|score| score.probability).sum::<f64>();
+        assert!((sum - 1.).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::args_init::args::{Args, ModelFamily};
+use crate::llm::classification::Classification;
+use crate::llm::generation_summary::GenerationSummary;
+use crate::llm::llama_llm::llama_initialization;
+use crate::llm::mistral_llm::mistral_initialization;
+use crate::llm::model_files::{hub_file, local_model_path, read_gguf};
+use crate::llm::phi_v2_llm::phi_v2_initialization;
+use crate::llm::prefix_cache::PrefixCacheStats;
+use crate::llm::prompt_template::{Exchange, PromptTemplate};
+use crate::llm::sampling_params::SamplingParams;
+
+/// A loaded model, ready to serve generations, whatever its family
+pub trait LlmEngine: Send + Sync {
+    fn model_family(&self) -> ModelFamily;
+
+    /// Default sampling parameters, from the command line
+    fn default_sampling(&self) -> &SamplingParams;
+
+    /// Generate an answer to `prompt`, following the previous turns in `history`.
+    /// Tokens are sent on `tx` as they are produced.
+    /// Returns once the last token was sent, with the reason generation stopped.
+    fn generate(&self, prompt: &str, history: &[Exchange], sampling: &SamplingParams, tx: UnboundedSender<String>, context: &str) -> Result<GenerationSummary>;
+
+    /// Score each of `labels` as the answer to `text`, with probabilities normalized over the labels
+    fn classify(&self, text: &str, labels: &[String], context: &str) -> Result<Classification>;
+
+    /// Chat format laying out the context, the conversation and the prompt
+    fn prompt_template(&self) -> PromptTemplate;
+
+    /// Maximum number of tokens, prompt and generated ones, the model can attend to
+    fn context_length(&self) -> usize;
+
+    fn count_tokens(&self, text: &str) -> Result<usize>;
+
+    /// Number of tokens of the tokenizer, special tokens included
+    fn vocab_size(&self) -> usize;
+
+    /// Process the part of the prompt depending only on `context`, so that generations
+    /// using this context start from the cached kv state
+    fn warm_prefix_cache(&self, context: &str) -> Result<()>;
+
+    fn prefix_cache_stats(&self) -> PrefixCacheStats;
+}
+
+/// Select the model family, then load the model with the matching backend
+pub fn initialize_engine(args_init: Args) -> Result<Arc<dyn LlmEngine>> {
+    let model_family = select_model_family(&args_init)?;
+
+    println!("model family: {}", model_family);
+
+    let engine: Arc<dyn LlmEngine> = match model_family {
+        ModelFamily::Mistral => Arc::new(mistral_initialization::initialize(args_init)?),
+        ModelFamily::PhiV2 => Arc::new(phi_v2_initialization::initialize(args_init)?),
+        ModelFamily::Llama => Arc::new(llama_initialization::initialize(args_init)?),
+    };
+
+    Ok(engine)
+}
+
+/// Explicit `--model-family` first, then the gguf metadata of the given model, then phi-v2.
+/// Only llama.cpp gguf files carry the architecture, tensor-tools files need `--model-family`.
+pub fn select_model_family(args_init: &Args) -> Result<ModelFamily> {
+    if let Some(model_family) = args_init.model_family {
+        return Ok(model_family);
+    }
+
+    let model_path = match (local_model_path(args_init), &args_init.model_id, &args_init.model_file) {
+        (Some(path), _, _) => path,
+        (None, Some(model_id), Some(model_file)) => {
+            hub_file(Some(model_id.as_str()), Some(model_file.as_str()), args_init.revision.as_str(), "--local-model-file")?
+        }
+        (None, Some(_), None) | (None, None, Some(_)) => {
+            bail!("--model-id and --model-file are both needed to detect the model family, or use --model-family")
+        }
+        (None, None, None) => return Ok(ModelFamily::PhiV2),
+    };
+
+    let (_, content) = read_gguf(&model_path)?;
+
+    let architecture = match content.metadata.get("general.architecture") {
+        Some(value) => value.to_string()?.clone(),
+        None => bail!("no general.architecture in {:?}, please use --model-family", model_path),
+    };
+
+    model_family_from_architecture(architecture.as_str())
+}
+
+fn model_family_from_architecture(architecture: &str) -> Result<ModelFamily> {
+    match architecture {
+        "llama" | "mistral" => Ok(ModelFamily::Llama),
+        // llama.cpp phi-2 files use `blk.N.*` tensor names, the mixformer loader only knows tensor-tools ones
+        "phi2" => bail!("llama.cpp phi2 gguf files are not supported, please use a file generated by candle tensor-tools"),
+        "phi-msft" | "mixformer" => Ok(ModelFamily::PhiV2),
+        other => bail!("unsupported gguf architecture `{}`, please use --model-family", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn architecture_to_family() {
+        assert!(matches!(model_family_from_architecture("llama"), Ok(ModelFamily::Llama)));
+        assert!(matches!(model_family_from_architecture("mistral"), Ok(ModelFamily::Llama)));
+        assert!(matches!(model_family_from_architecture("phi-msft"), Ok(ModelFamily::PhiV2)));
+        assert!(matches!(model_family_from_architecture("mixformer"), Ok(ModelFamily::PhiV2)));
+
+        let error = model_family_from_architecture("phi2").unwrap_err().to_string();
+        assert!(error.contains("tensor-tools"), "{}", error);
+        assert!(model_family_from_architecture("gemma").is_err());
+    }
+}
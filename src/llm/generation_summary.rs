@@ -0,0 +1,83 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Why a generation ended
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model produced its end of sequence token
+    Eos,
+    /// `sample_len` tokens were generated
+    Length,
+    /// A stop sequence was generated
+    Stop,
+    /// The maximum generation time was reached
+    MaxTime,
+    /// The client went away, or asked to stop
+    Cancelled,
+}
+
+impl fmt::Display for FinishReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FinishReason::Eos => write!(f, "eos"),
+            FinishReason::Length => write!(f, "length"),
+            FinishReason::Stop => write!(f, "stop"),
+            FinishReason::MaxTime => write!(f, "max_time"),
+            FinishReason::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// Outcome of a generation, returned once its last token was sent
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct GenerationSummary {
+    pub finish_reason: FinishReason,
+    /// Stop sequence which ended the generation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequence: Option<String>,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Completion tokens per second, prompt processing excluded
+    pub tokens_per_second: f64,
+    /// Prompt tokens processed per second, the ones found in the prefix cache excluded
+    pub prompt_tokens_per_second: f64,
+    /// Seed of the sampler, to replay the generation
+    pub seed: u64,
+}
+
+impl GenerationSummary {
+    pub fn new(finish_reason: FinishReason, prompt_tokens: usize, completion_tokens: usize, generation_time: Duration) -> Self {
+        let secs = generation_time.as_secs_f64();
+        Self {
+            finish_reason,
+            stop_sequence: None,
+            prompt_tokens,
+            completion_tokens,
+            tokens_per_second: if secs > 0. { completion_tokens as f64 / secs } else { 0. },
+            prompt_tokens_per_second: 0.,
+            seed: 0,
+        }
+    }
+
+    pub fn with_prompt_processing(mut self, processed_tokens: usize, prompt_time: Duration) -> Self {
+        let secs = prompt_time.as_secs_f64();
+        self.prompt_tokens_per_second = if secs > 0. { processed_tokens as f64 / secs } else { 0. };
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_stop_sequence(mut self, stop_sequence: Option<&str>) -> Self {
+        self.stop_sequence = stop_sequence.map(str::to_string);
+        self
+    }
+}
+
+/// How a generation ended as seen by the client, errors are rendered as text
+pub type GenerationOutcome = Result<GenerationSummary, String>;
@@ -0,0 +1,219 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use candle::quantized::gguf_file::{self, Value};
+
+use crate::llm::model_files::read_gguf;
+
+/// Architecture parameters and special tokens stored in a gguf header.
+/// llama.cpp files carry them under `<architecture>.*` and `tokenizer.ggml.*` keys,
+/// files produced by candle tensor-tools usually carry none, every field is then `None`.
+#[derive(Debug, Clone, Default)]
+pub struct GgufMetadata {
+    pub architecture: Option<String>,
+    pub context_length: Option<usize>,
+    pub vocab_size: Option<usize>,
+    /// Number of tokens of the embedded tokenizer, the embeddings may be padded beyond it
+    pub token_count: Option<usize>,
+    pub embedding_length: Option<usize>,
+    pub feed_forward_length: Option<usize>,
+    pub block_count: Option<usize>,
+    pub head_count: Option<usize>,
+    pub head_count_kv: Option<usize>,
+    pub rope_dimension_count: Option<usize>,
+    pub rope_freq_base: Option<f64>,
+    pub rms_norm_epsilon: Option<f64>,
+    pub layer_norm_epsilon: Option<f64>,
+    pub sliding_window: Option<usize>,
+    pub bos_token_id: Option<u32>,
+    pub eos_token_id: Option<u32>,
+    /// Jinja chat template of the model
+    pub chat_template: Option<String>,
+    /// Tensors are named the llama.cpp way ( `blk.N.*` ) rather than the candle tensor-tools way
+    pub llama_cpp_layout: bool,
+}
+
+impl GgufMetadata {
+    pub fn read(path: &Path) -> Result<Self> {
+        let (_, content) = read_gguf(path)?;
+        Self::from_content(&content)
+    }
+
+    pub fn from_content(content: &gguf_file::Content) -> Result<Self> {
+        let metadata = &content.metadata;
+
+        let architecture = match metadata.get("general.architecture") {
+            Some(value) => Some(value.to_string()?.clone()),
+            None => None,
+        };
+
+        // Architecture keys are prefixed by the architecture name
+        let arch_key = |key: &str| format!("{}.{}", architecture.as_deref().unwrap_or_default(), key);
+        let get_usize = |key: &str| metadata.get(key).map(as_usize).transpose();
+        let get_f64 = |key: &str| metadata.get(key).map(as_f64).transpose();
+
+        let token_count = match metadata.get("tokenizer.ggml.tokens") {
+            Some(tokens) => Some(tokens.to_vec()?.len()),
+            None => None,
+        };
+
+        Ok(Self {
+            context_length: get_usize(arch_key("context_length").as_str())?,
+            vocab_size: get_usize(arch_key("vocab_size").as_str())?,
+            token_count,
+            embedding_length: get_usize(arch_key("embedding_length").as_str())?,
+            feed_forward_length: get_usize(arch_key("feed_forward_length").as_str())?,
+            block_count: get_usize(arch_key("block_count").as_str())?,
+            head_count: get_usize(arch_key("attention.head_count").as_str())?,
+            head_count_kv: get_usize(arch_key("attention.head_count_kv").as_str())?,
+            rope_dimension_count: get_usize(arch_key("rope.dimension_count").as_str())?,
+            rope_freq_base: get_f64(arch_key("rope.freq_base").as_str())?,
+            rms_norm_epsilon: get_f64(arch_key("attention.layer_norm_rms_epsilon").as_str())?,
+            layer_norm_epsilon: get_f64(arch_key("attention.layer_norm_epsilon").as_str())?,
+            sliding_window: get_usize(arch_key("attention.sliding_window").as_str())?,
+            bos_token_id: get_usize("tokenizer.ggml.bos_token_id")?.map(|id| id as u32),
+            eos_token_id: get_usize("tokenizer.ggml.eos_token_id")?.map(|id| id as u32),
+            chat_template: match metadata.get("tokenizer.chat_template") {
+                Some(value) => Some(value.to_string()?.clone()),
+                None => None,
+            },
+            llama_cpp_layout: content.tensor_infos.keys().any(|name| name.starts_with("blk.")),
+            architecture,
+        })
+    }
+
+    /// Fail early when the file holds another architecture than the ones the loader handles.
+    /// Files without architecture are accepted, the loader defaults then apply.
+    pub fn check_architecture(&self, supported: &[&str]) -> Result<()> {
+        match &self.architecture {
+            Some(architecture) if !supported.contains(&architecture.as_str()) => bail!(
+                "gguf architecture `{}` cannot be loaded by this model family, expected one of {:?}",
+                architecture,
+                supported
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// The mistral and phi-2 loaders only know the tensor names written by candle tensor-tools
+    pub fn check_tensor_tools_layout(&self, model_family: &str) -> Result<()> {
+        if self.llama_cpp_layout {
+            bail!(
+                "llama.cpp gguf files cannot be loaded by the {} family, it expects a file generated by candle tensor-tools",
+                model_family
+            );
+        }
+        Ok(())
+    }
+
+    pub fn log(&self) {
+        println!(
+            "gguf metadata: architecture {:?}, context length {:?}, heads {:?}/{:?}, rope base {:?}, bos {:?}, eos {:?}",
+            self.architecture,
+            self.context_length,
+            self.head_count,
+            self.head_count_kv,
+            self.rope_freq_base,
+            self.bos_token_id,
+            self.eos_token_id
+        );
+    }
+}
+
+/// gguf writers do not agree on integer widths, accept any of them
+fn as_usize(value: &Value) -> Result<usize> {
+    Ok(match value {
+        Value::U8(v) => *v as usize,
+        Value::U16(v) => *v as usize,
+        Value::U32(v) => *v as usize,
+        Value::U64(v) => *v as usize,
+        Value::I8(v) if *v >= 0 => *v as usize,
+        Value::I16(v) if *v >= 0 => *v as usize,
+        Value::I32(v) if *v >= 0 => *v as usize,
+        Value::I64(v) if *v >= 0 => *v as usize,
+        other => bail!("expected a positive integer in the gguf metadata, got {:?}", other),
+    })
+}
+
+fn as_f64(value: &Value) -> Result<f64> {
+    Ok(match value {
+        Value::F32(v) => *v as f64,
+        Value::F64(v) => *v,
+        other => as_usize(other)? as f64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle::quantized::gguf_file::{Content, TensorInfo, VersionedMagic};
+    use candle::quantized::GgmlDType;
+
+    use super::*;
+
+    fn content(metadata: Vec<(&str, Value)>, tensors: &[&str]) -> Content {
+        Content {
+            magic: VersionedMagic::GgufV3,
+            metadata: metadata.into_iter().map(|(key, value)| (key.to_string(), value)).collect(),
+            tensor_infos: tensors
+                .iter()
+                .map(|name| (name.to_string(), TensorInfo { ggml_dtype: GgmlDType::F32, shape: (1,).into(), offset: 0 }))
+                .collect::<HashMap<_, _>>(),
+            tensor_data_offset: 0,
+        }
+    }
+
+    #[test]
+    fn llama_cpp_keys_are_read() {
+        let content = content(
+            vec![
+                ("general.architecture", Value::String("llama".to_string())),
+                ("llama.context_length", Value::U32(4096)),
+                ("llama.attention.head_count", Value::U64(32)),
+                ("llama.attention.head_count_kv", Value::I32(8)),
+                ("llama.rope.freq_base", Value::F32(10000.)),
+                ("llama.attention.layer_norm_rms_epsilon", Value::F64(1e-5)),
+                ("tokenizer.ggml.bos_token_id", Value::U32(1)),
+                ("tokenizer.ggml.eos_token_id", Value::U32(2)),
+                ("tokenizer.ggml.tokens", Value::Array(vec![Value::String("<unk>".to_string()), Value::String("<s>".to_string())])),
+            ],
+            &["token_embd.weight", "blk.0.attn_q.weight"],
+        );
+        let metadata = GgufMetadata::from_content(&content).unwrap();
+        assert_eq!(metadata.architecture.as_deref(), Some("llama"));
+        assert_eq!(metadata.context_length, Some(4096));
+        assert_eq!(metadata.head_count, Some(32));
+        assert_eq!(metadata.head_count_kv, Some(8));
+        assert_eq!(metadata.rope_freq_base, Some(10000.));
+        assert_eq!(metadata.rms_norm_epsilon, Some(1e-5));
+        assert_eq!((metadata.bos_token_id, metadata.eos_token_id), (Some(1), Some(2)));
+        assert_eq!(metadata.token_count, Some(2));
+        assert_eq!(metadata.block_count, None);
+        assert!(metadata.llama_cpp_layout);
+        assert!(metadata.check_tensor_tools_layout("mistral").is_err());
+        assert!(metadata.check_architecture(&["llama", "mistral"]).is_ok());
+        assert!(metadata.check_architecture(&["phi2"]).is_err());
+    }
+
+    #[test]
+    fn tensor_tools_files_have_no_metadata() {
+        let content = content(Vec::new(), &["model.embed_tokens.weight", "model.layers.0.self_attn.q_proj.weight"]);
+        let metadata = GgufMetadata::from_content(&content).unwrap();
+        assert_eq!(metadata.architecture, None);
+        assert_eq!(metadata.context_length, None);
+        assert!(!metadata.llama_cpp_layout);
+        assert!(metadata.check_tensor_tools_layout("mistral").is_ok());
+        assert!(metadata.check_architecture(&["phi2"]).is_ok());
+    }
+
+    #[test]
+    fn integers_of_any_width() {
+        assert_eq!(as_usize(&Value::U8(7)).unwrap(), 7);
+        assert_eq!(as_usize(&Value::U16(7)).unwrap(), 7);
+        assert_eq!(as_usize(&Value::I64(7)).unwrap(), 7);
+        assert!(as_usize(&Value::I32(-1)).is_err());
+        assert!(as_usize(&Value::String("7".to_string())).is_err());
+        assert_eq!(as_f64(&Value::U32(3)).unwrap(), 3.);
+    }
+}
@@ -0,0 +1,673 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Result};
+
+/// Upper bound accepted for the bounds of a `{m,n}` repetition
+const MAX_REPETITION: usize = 1_000;
+
+/// Upper bound of the number of parses followed at the same time, ambiguous grammars beyond it are cut
+const MAX_STACKS: usize = 4_096;
+
+/// One step of an alternative
+#[derive(Debug, Clone, PartialEq)]
+pub enum Element {
+    /// A character in the ranges, or out of them when negated
+    Chars { ranges: Vec<(char, char)>, negated: bool },
+    /// Reference to a rule
+    Rule(usize),
+}
+
+impl Element {
+    fn char(c: char) -> Self {
+        Element::Chars { ranges: vec![(c, c)], negated: false }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Element::Chars { ranges, negated } => ranges.iter().any(|(lo, hi)| *lo <= c && c <= *hi) != *negated,
+            Element::Rule(_) => false,
+        }
+    }
+}
+
+type Alternative = Vec<Element>;
+
+/// Next element to match in an alternative of a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    rule: u32,
+    alternative: u32,
+    element: u32,
+}
+
+/// Positions still to match, innermost last. An empty stack has matched the whole grammar.
+pub type Stack = Vec<Position>;
+
+/// Context free grammar in the GBNF format of llama.cpp, matched one character at a time.
+/// The `root` rule is the start rule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grammar {
+    names: Vec<String>,
+    rules: Vec<Vec<Alternative>>,
+    root: usize,
+}
+
+impl Grammar {
+    pub fn parse(source: &str) -> Result<Self> {
+        Parser::new(source).parse()
+    }
+
+    /// Parses before the first character
+    pub fn initial_stacks(&self) -> Vec<Stack> {
+        let mut stacks = Vec::new();
+        for alternative in 0..self.rules[self.root].len() {
+            self.expand(vec![position(self.root, alternative, 0)], &mut stacks);
+        }
+        dedup(stacks)
+    }
+
+    /// Parses still alive after `c`, none when `c` does not follow the grammar
+    pub fn advance(&self, stacks: &[Stack], c: char) -> Vec<Stack> {
+        let mut advanced = Vec::new();
+        for stack in stacks {
+            let Some(top) = stack.last() else { continue };
+            if !self.element(top).is_some_and(|element| element.matches(c)) {
+                continue;
+            }
+            let mut stack = stack.clone();
+            next_element(&mut stack);
+            self.expand(stack, &mut advanced);
+            if advanced.len() > MAX_STACKS {
+                break;
+            }
+        }
+        dedup(advanced)
+    }
+
+    /// Whether the text matched so far is a complete sentence of the grammar
+    pub fn is_complete(stacks: &[Stack]) -> bool {
+        stacks.iter().any(|stack| stack.is_empty())
+    }
+
+    /// Whether the whole `text` is a sentence of the grammar
+    pub fn accepts(&self, text: &str) -> bool {
+        let mut stacks = self.initial_stacks();
+        for c in text.chars() {
+            stacks = self.advance(&stacks, c);
+            if stacks.is_empty() {
+                return false;
+            }
+        }
+        Self::is_complete(&stacks)
+    }
+
+    fn element(&self, position: &Position) -> Option<&Element> {
+        self.rules[position.rule as usize][position.alternative as usize].get(position.element as usize)
+    }
+
+    /// Push the rules referenced at the top of the stack until it waits for a character,
+    /// or is empty
+    fn expand(&self, mut stack: Stack, stacks: &mut Vec<Stack>) {
+        loop {
+            let Some(top) = stack.last() else {
+                stacks.push(stack);
+                return;
+            };
+            match self.element(top) {
+                // End of the alternative, back to the enclosing rule
+                None => {
+                    stack.pop();
+                }
+                Some(Element::Chars { .. }) => {
+                    stacks.push(stack);
+                    return;
+                }
+                Some(Element::Rule(rule)) => {
+                    let rule = *rule;
+                    next_element(&mut stack);
+                    for alternative in 0..self.rules[rule].len() {
+                        let mut expanded = stack.clone();
+                        expanded.push(position(rule, alternative, 0));
+                        self.expand(expanded, stacks);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Left recursive rules would be expanded forever
+    fn check_left_recursion(&self) -> Result<()> {
+        // Rules which can match the empty string
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (rule, alternatives) in self.rules.iter().enumerate() {
+                if !nullable[rule]
+                    && alternatives.iter().any(|alternative| {
+                        alternative.iter().all(|element| matches!(element, Element::Rule(r) if nullable[*r]))
+                    })
+                {
+                    nullable[rule] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        // Rules which can be expanded before any character is matched
+        let leftmost = self
+            .rules
+            .iter()
+            .map(|alternatives| {
+                let mut referenced = Vec::new();
+                for alternative in alternatives {
+                    for element in alternative {
+                        match element {
+                            Element::Rule(rule) => {
+                                referenced.push(*rule);
+                                if !nullable[*rule] {
+                                    break;
+                                }
+                            }
+                            Element::Chars { .. } => break,
+                        }
+                    }
+                }
+                referenced
+            })
+            .collect::<Vec<_>>();
+
+        // 0 not visited, 1 being visited, 2 done
+        let mut state = vec![0u8; self.rules.len()];
+        for start in 0..self.rules.len() {
+            if state[start] != 0 {
+                continue;
+            }
+            let mut path = vec![(start, 0usize)];
+            state[start] = 1;
+            while let Some((rule, next)) = path.last_mut() {
+                let rule = *rule;
+                match leftmost[rule].get(*next) {
+                    Some(&referenced) => {
+                        *next += 1;
+                        match state[referenced] {
+                            0 => {
+                                state[referenced] = 1;
+                                path.push((referenced, 0));
+                            }
+                            1 => bail!("left recursion in rule `{}`", self.names[referenced]),
+                            _ => {}
+                        }
+                    }
+                    None => {
+                        state[rule] = 2;
+                        path.pop();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn position(rule: usize, alternative: usize, element: usize) -> Position {
+    Position {
+        rule: rule as u32,
+        alternative: alternative as u32,
+        element: element as u32,
+    }
+}
+
+fn next_element(stack: &mut Stack) {
+    if let Some(top) = stack.last_mut() {
+        top.element += 1;
+    }
+}
+
+fn dedup(mut stacks: Vec<Stack>) -> Vec<Stack> {
+    stacks.sort();
+    stacks.dedup();
+    stacks
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    names: Vec<String>,
+    rules: Vec<Option<Vec<Alternative>>>,
+    ids: HashMap<String, usize>,
+}
+
+impl Parser {
+    fn new(source: &str) -> Self {
+        Self {
+            chars: source.chars().collect(),
+            pos: 0,
+            names: Vec::new(),
+            rules: Vec::new(),
+            ids: HashMap::new(),
+        }
+    }
+
+    fn parse(mut self) -> Result<Grammar> {
+        loop {
+            self.skip_space();
+            if self.peek().is_none() {
+                break;
+            }
+            let name = self.name()?;
+            self.skip_space();
+            self.expect("::=")?;
+            let rule = self.rule_id(name.as_str());
+            if self.rules[rule].is_some() {
+                return Err(self.error(format!("rule `{}` is defined twice", name)));
+            }
+            let alternatives = self.alternatives(name.as_str(), false)?;
+            self.rules[rule] = Some(alternatives);
+        }
+
+        let root = *self.ids.get("root").ok_or_else(|| anyhow!("grammar has no `root` rule"))?;
+        let mut rules = Vec::with_capacity(self.rules.len());
+        for (rule, alternatives) in self.rules.into_iter().enumerate() {
+            match alternatives {
+                Some(alternatives) => rules.push(alternatives),
+                None => bail!("rule `{}` is used but not defined", self.names[rule]),
+            }
+        }
+
+        let grammar = Grammar { names: self.names, rules, root };
+        grammar.check_left_recursion()?;
+        Ok(grammar)
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        let id = self.rules.len();
+        self.names.push(name.to_string());
+        self.rules.push(None);
+        self.ids.insert(name.to_string(), id);
+        id
+    }
+
+    /// Rule generated for a group or a repetition
+    fn anonymous(&mut self, parent: &str, alternatives: Option<Vec<Alternative>>) -> usize {
+        let id = self.rules.len();
+        self.names.push(format!("{}_{}", parent, id));
+        self.rules.push(alternatives);
+        id
+    }
+
+    fn alternatives(&mut self, name: &str, nested: bool) -> Result<Vec<Alternative>> {
+        let mut alternatives = vec![self.sequence(name, nested)?];
+        loop {
+            self.skip_space();
+            if self.peek() != Some('|') {
+                break;
+            }
+            self.pos += 1;
+            alternatives.push(self.sequence(name, nested)?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self, name: &str, nested: bool) -> Result<Alternative> {
+        let mut sequence = Vec::new();
+        loop {
+            self.skip_space();
+            let start = sequence.len();
+            match self.peek() {
+                None | Some('|') => break,
+                Some(')') if nested => break,
+                Some('"') => {
+                    self.pos += 1;
+                    loop {
+                        match self.peek() {
+                            None => return Err(self.error("unterminated literal".to_string())),
+                            Some('"') => {
+                                self.pos += 1;
+                                break;
+                            }
+                            Some(_) => {
+                                let c = self.literal_char()?;
+                                sequence.push(Element::char(c));
+                            }
+                        }
+                    }
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    sequence.push(self.char_class()?);
+                }
+                Some('.') => {
+                    self.pos += 1;
+                    sequence.push(Element::Chars { ranges: Vec::new(), negated: true });
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    let alternatives = self.alternatives(name, true)?;
+                    self.skip_space();
+                    self.expect(")")?;
+                    sequence.push(Element::Rule(self.anonymous(name, Some(alternatives))));
+                }
+                Some(c) if is_name_char(c) => {
+                    // The next rule definition ends this one
+                    if self.at_rule_definition() {
+                        break;
+                    }
+                    let referenced = self.name()?;
+                    sequence.push(Element::Rule(self.rule_id(referenced.as_str())));
+                }
+                Some(c) => return Err(self.error(format!("unexpected `{}`", c))),
+            }
+            let item = sequence.split_off(start);
+            let item = self.quantified(name, item)?;
+            sequence.extend(item);
+        }
+        Ok(sequence)
+    }
+
+    /// Apply the `*`, `+`, `?` or `{m,n}` following an item, if any
+    fn quantified(&mut self, name: &str, item: Vec<Element>) -> Result<Vec<Element>> {
+        let quantified = match self.peek() {
+            Some('*') => vec![Element::Rule(self.star(name, item))],
+            Some('+') => {
+                let star = self.star(name, item.clone());
+                let mut sequence = item;
+                sequence.push(Element::Rule(star));
+                sequence
+            }
+            Some('?') => vec![Element::Rule(self.anonymous(name, Some(vec![item, Vec::new()])))],
+            Some('{') => {
+                self.pos += 1;
+                let (min, max) = self.bounds()?;
+                return Ok(self.repeat(name, item, min, max));
+            }
+            _ => return Ok(item),
+        };
+        self.pos += 1;
+        Ok(quantified)
+    }
+
+    /// `item*` as `rule ::= item rule | `
+    fn star(&mut self, name: &str, item: Vec<Element>) -> usize {
+        let id = self.anonymous(name, None);
+        let mut repeated = item;
+        repeated.push(Element::Rule(id));
+        self.rules[id] = Some(vec![repeated, Vec::new()]);
+        id
+    }
+
+    /// `item{min,max}` as `min` copies of the item followed by nested optional ones
+    fn repeat(&mut self, name: &str, item: Vec<Element>, min: usize, max: Option<usize>) -> Vec<Element> {
+        let mut sequence = Vec::new();
+        for _ in 0..min {
+            sequence.extend(item.iter().cloned());
+        }
+        match max {
+            None => sequence.push(Element::Rule(self.star(name, item))),
+            Some(max) => {
+                let mut optional: Option<usize> = None;
+                for _ in min..max {
+                    let mut alternative = item.clone();
+                    if let Some(inner) = optional {
+                        alternative.push(Element::Rule(inner));
+                    }
+                    optional = Some(self.anonymous(name, Some(vec![alternative, Vec::new()])));
+                }
+                if let Some(optional) = optional {
+                    sequence.push(Element::Rule(optional));
+                }
+            }
+        }
+        sequence
+    }
+
+    /// `{m}`, `{m,}` or `{m,n}`, the opening brace already consumed
+    fn bounds(&mut self) -> Result<(usize, Option<usize>)> {
+        self.skip_space();
+        let min = self.number()?.unwrap_or(0);
+        self.skip_space();
+        let max = if self.peek() == Some(',') {
+            self.pos += 1;
+            self.skip_space();
+            self.number()?
+        } else {
+            Some(min)
+        };
+        self.skip_space();
+        self.expect("}")?;
+        if min > MAX_REPETITION || max.is_some_and(|max| max > MAX_REPETITION) {
+            return Err(self.error(format!("repetitions are limited to {}", MAX_REPETITION)));
+        }
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error(format!("invalid repetition {{{},{}}}", min, max.unwrap_or_default())));
+        }
+        Ok((min, max))
+    }
+
+    fn number(&mut self) -> Result<Option<usize>> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Ok(None);
+        }
+        let digits = self.chars[start..self.pos].iter().collect::<String>();
+        digits.parse().map(Some).map_err(|_| self.error(format!("invalid number `{}`", digits)))
+    }
+
+    /// `[...]` or `[^...]`, the opening bracket already consumed
+    fn char_class(&mut self) -> Result<Element> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated character class".to_string())),
+                Some(']') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(_) => {
+                    let lo = self.literal_char()?;
+                    let hi = if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|c| *c != ']') {
+                        self.pos += 1;
+                        self.literal_char()?
+                    } else {
+                        lo
+                    };
+                    if hi < lo {
+                        return Err(self.error(format!("invalid range {}-{}", lo, hi)));
+                    }
+                    ranges.push((lo, hi));
+                }
+            }
+        }
+        Ok(Element::Chars { ranges, negated })
+    }
+
+    /// A character of a literal or of a character class, escapes included
+    fn literal_char(&mut self) -> Result<char> {
+        let c = self.next().ok_or_else(|| self.error("unexpected end of grammar".to_string()))?;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let escaped = self.next().ok_or_else(|| self.error("unexpected end of grammar".to_string()))?;
+        match escaped {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            'x' => self.hex_char(2),
+            'u' => self.hex_char(4),
+            'U' => self.hex_char(8),
+            '\\' | '"' | '\'' | '[' | ']' | '-' | '/' | '^' => Ok(escaped),
+            other => Err(self.error(format!("unknown escape `\\{}`", other))),
+        }
+    }
+
+    fn hex_char(&mut self, digits: usize) -> Result<char> {
+        let end = self.pos + digits;
+        let hex = self.chars.get(self.pos..end).map(|chars| chars.iter().collect::<String>());
+        let c = hex
+            .as_deref()
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.error("invalid hexadecimal escape".to_string()))?;
+        self.pos = end;
+        Ok(c)
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_name_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(self.error("expected a rule name".to_string()));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    /// A rule name followed by `::=`
+    fn at_rule_definition(&self) -> bool {
+        let mut pos = self.pos;
+        while self.chars.get(pos).is_some_and(|c| is_name_char(*c)) {
+            pos += 1;
+        }
+        while self.chars.get(pos).is_some_and(|c| c.is_whitespace()) {
+            pos += 1;
+        }
+        self.chars.get(pos..pos + 3).is_some_and(|chars| chars == [':', ':', '='])
+    }
+
+    /// Whitespace and `#` comments
+    fn skip_space(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.pos += 1;
+                }
+            } else if c.is_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<()> {
+        for c in expected.chars() {
+            if self.peek() != Some(c) {
+                return Err(self.error(format!("expected `{}`", expected)));
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn error(&self, message: String) -> anyhow::Error {
+        let line = self.chars[..self.pos.min(self.chars.len())].iter().filter(|c| **c == '\n').count() + 1;
+        anyhow!("grammar line {}: {}", line, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals_and_alternatives() {
+        let grammar = Grammar::parse(r#"root ::= "yes" | "no""#).unwrap();
+        assert!(grammar.accepts("yes"));
+        assert!(grammar.accepts("no"));
+        assert!(!grammar.accepts("ye"));
+        assert!(!grammar.accepts("yess"));
+    }
+
+    #[test]
+    fn character_classes_and_repetitions() {
+        let grammar = Grammar::parse(
+            r#"
+            # an identifier, then digits
+            root ::= [a-z_] [a-z0-9_]* "-" digit{2,3}
+            digit ::= [0-9]
+            "#,
+        )
+        .unwrap();
+        assert!(grammar.accepts("a_1-42"));
+        assert!(grammar.accepts("x-123"));
+        assert!(!grammar.accepts("x-1"));
+        assert!(!grammar.accepts("x-1234"));
+        assert!(!grammar.accepts("1x-12"));
+    }
+
+    #[test]
+    fn negated_classes_groups_and_escapes() {
+        let grammar = Grammar::parse(r#"root ::= "\"" ( [^"\\] | "\\" ["\\n] )* "\"" "\n"?"#).unwrap();
+        assert!(grammar.accepts(r#""a b""#));
+        assert!(grammar.accepts("\"a\\\"b\"\n"));
+        assert!(!grammar.accepts(r#""a"b""#));
+    }
+
+    #[test]
+    fn rules_can_span_several_lines() {
+        let grammar = Grammar::parse(
+            r#"root ::= item
+                 ( "," item )+
+               item ::= "a"
+                 | "b""#,
+        )
+        .unwrap();
+        assert!(grammar.accepts("a,b,a"));
+        assert!(!grammar.accepts("a"));
+    }
+
+    #[test]
+    fn prefixes_are_alive_but_not_complete() {
+        let grammar = Grammar::parse(r#"root ::= "ab" "c"?"#).unwrap();
+        let stacks = grammar.advance(&grammar.initial_stacks(), 'a');
+        assert!(!stacks.is_empty());
+        assert!(!Grammar::is_complete(&stacks));
+        let stacks = grammar.advance(&stacks, 'b');
+        assert!(Grammar::is_complete(&stacks));
+        assert!(grammar.advance(&stacks, 'x').is_empty());
+    }
+
+    #[test]
+    fn invalid_grammars_are_rejected() {
+        assert!(Grammar::parse(r#"start ::= "a""#).is_err());
+        assert!(Grammar::parse(r#"root ::= missing"#).is_err());
+        assert!(Grammar::parse(r#"root ::= "a" root ::= "b""#).is_err());
+        assert!(Grammar::parse(r#"root ::= "a"#).is_err());
+        assert!(Grammar::parse(r#"root ::= [b-a]"#).is_err());
+        assert!(Grammar::parse(r#"root ::= "a"{3,2}"#).is_err());
+    }
+
+    #[test]
+    fn left_recursion_is_rejected() {
+        let error = Grammar::parse(r#"root ::= expr  expr ::= expr "+" "1" | "1""#).unwrap_err();
+        assert!(error.to_string().contains("left recursion"));
+        assert!(Grammar::parse(r#"root ::= ws root "a" | "b"  ws ::= " "?"#).is_err());
+        assert!(Grammar::parse(r#"root ::= "(" root ")" | "x""#).is_ok());
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Value};
+
+/// Rules shared by every converted schema. Whitespace is bounded so that the model cannot
+/// pad the output forever.
+const COMMON_RULES: &[(&str, &str)] = &[
+    ("value", "object | array | string | number | boolean | null"),
+    ("object", r#""{" ws ( string ws ":" ws value ( ws "," ws string ws ":" ws value )* )? ws "}""#),
+    ("array", r#""[" ws ( value ( ws "," ws value )* )? ws "]""#),
+    ("string", r#""\"" char* "\"""#),
+    ("char", r#"[^"\\\x7F\x00-\x1F] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F]{4} )"#),
+    ("number", r#"integer ( "." [0-9]{1,16} )? ( [eE] [-+]? [0-9]{1,4} )?"#),
+    ("integer", r#""-"? ( "0" | [1-9] [0-9]{0,15} )"#),
+    ("boolean", r#""true" | "false""#),
+    ("null", r#""null""#),
+    ("ws", r#"( " " | "\n" [ \t]{0,20} )?"#),
+];
+
+/// GBNF grammar of the JSON documents valid against `schema`.
+///
+/// Supported keywords are type, properties, required, items, minItems, maxItems,
+/// minLength, maxLength, enum, const, anyOf, oneOf, and local $ref to $defs or definitions.
+/// Properties are generated in the order of the schema, additional properties are not generated.
+pub fn json_schema_to_gbnf(schema: &Value) -> Result<String> {
+    let mut converter = Converter { root: schema, rules: BTreeMap::new() };
+    let root = converter.visit(schema, "root")?;
+    converter.rules.insert("root".to_string(), root);
+
+    let mut grammar = String::new();
+    for (name, body) in &converter.rules {
+        grammar.push_str(&format!("{} ::= {}\n", name, body));
+    }
+    for (name, body) in COMMON_RULES {
+        grammar.push_str(&format!("{} ::= {}\n", name, body));
+    }
+    Ok(grammar)
+}
+
+struct Converter<'a> {
+    root: &'a Value,
+    /// Rules generated for the schema, by name
+    rules: BTreeMap<String, String>,
+}
+
+impl Converter<'_> {
+    /// Expression matching the documents valid against `schema`, `name` names the rules it needs
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".to_string()),
+            Value::Bool(false) => bail!("`{}` accepts no value", name),
+            Value::Object(schema) => schema,
+            _ => bail!("`{}` is not a schema", name),
+        };
+
+        if let Some(reference) = schema.get("$ref") {
+            return self.reference(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(literal(&serde_json::to_string(value)?));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = values.as_array().ok_or_else(|| anyhow!("`{}`: enum must be an array", name))?;
+            if values.is_empty() {
+                bail!("`{}`: enum cannot be empty", name);
+            }
+            let alternatives = values
+                .iter()
+                .map(|value| Ok(literal(&serde_json::to_string(value)?)))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(format!("( {} )", alternatives.join(" | ")));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(keyword) {
+                return self.alternatives(schemas, name, keyword);
+            }
+        }
+        if let Some(all_of) = schema.get("allOf") {
+            match all_of.as_array().map(Vec::as_slice) {
+                Some([single]) => return self.visit(single, name),
+                _ => bail!("`{}`: allOf is only supported with a single schema", name),
+            }
+        }
+
+        match schema.get("type") {
+            None => Ok("value".to_string()),
+            Some(Value::String(kind)) => self.typed(schema, kind, name),
+            Some(Value::Array(kinds)) => {
+                let alternatives = kinds
+                    .iter()
+                    .map(|kind| match kind {
+                        Value::String(kind) => self.typed(schema, kind, format!("{}-{}", name, kind).as_str()),
+                        _ => bail!("`{}`: type must be a string", name),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(format!("( {} )", alternatives.join(" | ")))
+            }
+            Some(_) => bail!("`{}`: type must be a string or an array of strings", name),
+        }
+    }
+
+    fn typed(&mut self, schema: &Map<String, Value>, kind: &str, name: &str) -> Result<String> {
+        match kind {
+            "object" => self.object(schema, name),
+            "array" => self.array(schema, name),
+            "string" => string(schema, name),
+            "number" | "integer" | "boolean" | "null" => Ok(kind.to_string()),
+            other => bail!("`{}`: unknown type `{}`", name, other),
+        }
+    }
+
+    fn alternatives(&mut self, schemas: &Value, name: &str, keyword: &str) -> Result<String> {
+        let schemas = match schemas.as_array() {
+            Some(schemas) if !schemas.is_empty() => schemas,
+            _ => bail!("`{}`: {} must be a non empty array", name, keyword),
+        };
+        let alternatives = schemas
+            .iter()
+            .enumerate()
+            .map(|(i, schema)| self.visit(schema, format!("{}-{}", name, i).as_str()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(format!("( {} )", alternatives.join(" | ")))
+    }
+
+    /// Properties in the order of the schema, each optional property can be left out
+    fn object(&mut self, schema: &Map<String, Value>, name: &str) -> Result<String> {
+        let properties = match schema.get("properties") {
+            Some(Value::Object(properties)) if !properties.is_empty() => properties,
+            Some(Value::Object(_)) | None => return Ok("object".to_string()),
+            Some(_) => bail!("`{}`: properties must be an object", name),
+        };
+        let required = match schema.get("required") {
+            Some(Value::Array(required)) => required.iter().filter_map(Value::as_str).collect::<Vec<_>>(),
+            Some(_) => bail!("`{}`: required must be an array", name),
+            None => Vec::new(),
+        };
+        if let Some(missing) = required.iter().find(|property| !properties.contains_key(**property)) {
+            bail!("`{}`: required property `{}` is not defined", name, missing);
+        }
+
+        let mut pairs = Vec::with_capacity(properties.len());
+        for (property, property_schema) in properties {
+            let value = self.visit(property_schema, format!("{}-{}", name, rule_name(property)).as_str())?;
+            let pair = format!("{} ws \":\" ws {}", literal(&serde_json::to_string(property)?), value);
+            pairs.push((pair, required.contains(&property.as_str())));
+        }
+
+        // `{name}-from-{i}` matches the properties from the i-th one when none was generated before,
+        // built from the last property to the first one
+        let mut from_next = String::new();
+        let mut rest = String::new();
+        for (i, (pair, required)) in pairs.iter().enumerate().rev() {
+            let rule = format!("{}-from-{}", name, i);
+            let body = if *required {
+                format!("{} {}", pair, rest)
+            } else if from_next.is_empty() {
+                format!("( {} {} )?", pair, rest)
+            } else {
+                format!("{} {} | {}", pair, rest, from_next)
+            };
+            self.rules.insert(rule.clone(), body.trim_end().to_string());
+            from_next = rule;
+            rest = if *required {
+                format!("ws \",\" ws {} {}", pair, rest)
+            } else {
+                format!("( ws \",\" ws {} )? {}", pair, rest)
+            };
+        }
+
+        Ok(format!("\"{{\" ws {} ws \"}}\"", from_next))
+    }
+
+    fn array(&mut self, schema: &Map<String, Value>, name: &str) -> Result<String> {
+        let item = match schema.get("items") {
+            Some(items) => self.visit(items, format!("{}-item", name).as_str())?,
+            None => "value".to_string(),
+        };
+        let min = count(schema, "minItems", name)?.unwrap_or(0);
+        let max = count(schema, "maxItems", name)?;
+        if max.is_some_and(|max| max < min) {
+            bail!("`{}`: maxItems is lower than minItems", name);
+        }
+
+        let next = format!("( ws \",\" ws {} )", item);
+        let items = match (min, max) {
+            (_, Some(0)) => String::new(),
+            (0, None) => format!("( {} {}* )?", item, next),
+            (0, Some(max)) => format!("( {} {}{{0,{}}} )?", item, next, max - 1),
+            (min, None) => format!("{} {}{{{},}}", item, next, min - 1),
+            (min, Some(max)) => format!("{} {}{{{},{}}}", item, next, min - 1, max - 1),
+        };
+        let rule = format!("{}-array", name);
+        self.rules.insert(rule.clone(), format!("\"[\" ws {} ws \"]\"", items));
+        Ok(rule)
+    }
+
+    /// `#/$defs/<name>` and `#/definitions/<name>`, converted once into a rule of their own
+    fn reference(&mut self, reference: &Value) -> Result<String> {
+        let reference = reference.as_str().ok_or_else(|| anyhow!("$ref must be a string"))?;
+        let (section, definition) = match reference.strip_prefix("#/$defs/") {
+            Some(definition) => ("$defs", definition),
+            None => match reference.strip_prefix("#/definitions/") {
+                Some(definition) => ("definitions", definition),
+                None => bail!("only local references to $defs or definitions are supported, not `{}`", reference),
+            },
+        };
+        let rule = format!("ref-{}", rule_name(definition));
+        if self.rules.contains_key(&rule) {
+            return Ok(rule);
+        }
+        let schema = self
+            .root
+            .get(section)
+            .and_then(|definitions| definitions.get(definition))
+            .ok_or_else(|| anyhow!("`{}` is not defined", reference))?;
+
+        // Registered before the visit, for recursive definitions
+        self.rules.insert(rule.clone(), String::new());
+        let body = self.visit(schema, rule.as_str())?;
+        self.rules.insert(rule.clone(), body);
+        Ok(rule)
+    }
+}
+
+fn string(schema: &Map<String, Value>, name: &str) -> Result<String> {
+    let min = count(schema, "minLength", name)?.unwrap_or(0);
+    let max = count(schema, "maxLength", name)?;
+    Ok(match (min, max) {
+        (0, None) => "string".to_string(),
+        (min, None) => format!("\"\\\"\" char{{{},}} \"\\\"\"", min),
+        (min, Some(max)) if max >= min => format!("\"\\\"\" char{{{},{}}} \"\\\"\"", min, max),
+        _ => bail!("`{}`: maxLength is lower than minLength", name),
+    })
+}
+
+fn count(schema: &Map<String, Value>, keyword: &str, name: &str) -> Result<Option<usize>> {
+    match schema.get(keyword) {
+        None => Ok(None),
+        Some(value) => match value.as_u64() {
+            Some(count) => Ok(Some(count as usize)),
+            None => bail!("`{}`: {} must be a positive integer", name, keyword),
+        },
+    }
+}
+
+/// GBNF literal matching `text` exactly
+fn literal(text: &str) -> String {
+    let mut literal = String::with_capacity(text.len() + 2);
+    literal.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c if c.is_control() => literal.push_str(&format!("\\x{:02X}", c as u32)),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn rule_name(text: &str) -> String {
+    text.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '-' }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::llm::grammar::gbnf::Grammar;
+
+    fn grammar(schema: Value) -> Grammar {
+        let gbnf = json_schema_to_gbnf(&schema).unwrap();
+        Grammar::parse(gbnf.as_str()).unwrap_or_else(|e| panic!("{}\n{}", e, gbnf))
+    }
+
+    #[test]
+    fn any_json_value() {
+        let grammar = grammar(json!({}));
+        assert!(grammar.accepts(r#"{"a": [1, -2.5e3, "x\n\"y", true, null], "b": {}}"#));
+        assert!(grammar.accepts("42"));
+        assert!(!grammar.accepts("{'a': 1}"));
+        assert!(!grammar.accepts("[1,]"));
+        assert!(!grammar.accepts("012"));
+    }
+
+    #[test]
+    fn objects_with_required_and_optional_properties() {
+        let grammar = grammar(json!({
+            "type": "object",
+            "properties": {
+                "a": { "type": "integer" },
+                "b": { "type": "string" },
+                "c": { "type": "boolean" }
+            },
+            "required": ["b"]
+        }));
+        assert!(grammar.accepts(r#"{"b": "x"}"#));
+        assert!(grammar.accepts(r#"{"a": 1, "b": "x", "c": true}"#));
+        assert!(grammar.accepts("{\n  \"a\": 1,\n  \"b\": \"x\"\n}"));
+        assert!(grammar.accepts(r#"{"b":"x","c":false}"#));
+        assert!(!grammar.accepts(r#"{"a": 1}"#));
+        assert!(!grammar.accepts(r#"{"b": 1}"#));
+        assert!(!grammar.accepts(r#"{"b": "x", "d": 1}"#));
+        assert!(!grammar.accepts(r#"{, "b": "x"}"#));
+    }
+
+    #[test]
+    fn objects_with_optional_properties_only() {
+        let grammar = grammar(json!({
+            "type": "object",
+            "properties": { "a": { "type": "integer" }, "b": { "type": "integer" } }
+        }));
+        assert!(grammar.accepts("{}"));
+        assert!(grammar.accepts(r#"{"b": 2}"#));
+        assert!(grammar.accepts(r#"{"a": 1, "b": 2}"#));
+        assert!(!grammar.accepts(r#"{"a": 1,}"#));
+    }
+
+    #[test]
+    fn arrays_enums_and_bounds() {
+        let grammar = grammar(json!({
+            "type": "array",
+            "items": { "enum": ["red", "green", 3] },
+            "minItems": 1,
+            "maxItems": 2
+        }));
+        assert!(grammar.accepts(r#"["red"]"#));
+        assert!(grammar.accepts(r#"["green", 3]"#));
+        assert!(!grammar.accepts("[]"));
+        assert!(!grammar.accepts(r#"["red", "red", "red"]"#));
+        assert!(!grammar.accepts(r#"["blue"]"#));
+    }
+
+    #[test]
+    fn strings_lengths_consts_and_unions() {
+        let grammar = grammar(json!({
+            "anyOf": [
+                { "type": "string", "minLength": 2, "maxLength": 3 },
+                { "const": { "kind": "none" } },
+                { "type": ["integer", "null"] }
+            ]
+        }));
+        assert!(grammar.accepts(r#""ab""#));
+        assert!(!grammar.accepts(r#""a""#));
+        assert!(!grammar.accepts(r#""abcd""#));
+        assert!(grammar.accepts(r#"{"kind":"none"}"#));
+        assert!(grammar.accepts("null"));
+        assert!(grammar.accepts("-7"));
+        assert!(!grammar.accepts("1.5"));
+    }
+
+    #[test]
+    fn recursive_references() {
+        let grammar = grammar(json!({
+            "$ref": "#/$defs/node",
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "value": { "type": "integer" },
+                        "children": { "type": "array", "items": { "$ref": "#/$defs/node" } }
+                    },
+                    "required": ["value"]
+                }
+            }
+        }));
+        assert!(grammar.accepts(r#"{"value": 1, "children": [{"value": 2}, {"value": 3, "children": []}]}"#));
+        assert!(!grammar.accepts(r#"{"value": 1, "children": [{}]}"#));
+    }
+
+    #[test]
+    fn unsupported_schemas_are_rejected() {
+        assert!(json_schema_to_gbnf(&json!({ "type": "date" })).is_err());
+        assert!(json_schema_to_gbnf(&json!({ "$ref": "https://example.com/schema" })).is_err());
+        assert!(json_schema_to_gbnf(&json!({ "type": "object", "properties": { "a": {} }, "required": ["b"] })).is_err());
+        assert!(json_schema_to_gbnf(&json!({ "enum": [] })).is_err());
+    }
+}
@@ -0,0 +1,3 @@
+pub mod gbnf;
+pub mod json_schema;
+pub mod token_constraint;
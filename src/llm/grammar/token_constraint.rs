@@ -0,0 +1,323 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Error as E, Result};
+use candle::{DType, Tensor};
+use tokenizers::Tokenizer;
+
+use crate::llm::grammar::gbnf::{Grammar, Stack};
+
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: Vec<(char, usize)>,
+    /// Tokens whose text ends at this node
+    tokens: Vec<u32>,
+}
+
+/// Text of the tokens of a tokenizer, in a trie so that tokens sharing a prefix are
+/// matched against the grammar together
+#[derive(Debug)]
+pub struct Vocabulary {
+    nodes: Vec<TrieNode>,
+    texts: HashMap<u32, String>,
+}
+
+impl Vocabulary {
+    /// Tokens with an empty text are left out, they could be repeated forever
+    pub fn new(tokens: Vec<(u32, String)>) -> Self {
+        let mut nodes = vec![TrieNode::default()];
+        let mut texts = HashMap::with_capacity(tokens.len());
+        for (token, text) in tokens {
+            if text.is_empty() {
+                continue;
+            }
+            let mut node = 0;
+            for c in text.chars() {
+                node = match nodes[node].children.iter().find(|(child, _)| *child == c) {
+                    Some((_, next)) => *next,
+                    None => {
+                        nodes.push(TrieNode::default());
+                        let next = nodes.len() - 1;
+                        nodes[node].children.push((c, next));
+                        next
+                    }
+                };
+            }
+            nodes[node].tokens.push(token);
+            texts.insert(token, text);
+        }
+        Self { nodes, texts }
+    }
+
+    /// Text of each token as it appears in the middle of a generation. Special tokens and
+    /// tokens holding part of a multi byte character are left out.
+    pub fn from_tokenizer(tokenizer: &Tokenizer) -> Result<Self> {
+        let special = tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(_, token)| token.special)
+            .map(|(id, _)| id)
+            .collect::<HashSet<_>>();
+
+        // Decoders drop the leading space of the first token, decode each token after another one
+        let anchor = match tokenizer.encode("a", false).map_err(E::msg)?.get_ids().first() {
+            Some(anchor) => *anchor,
+            None => bail!("cannot tokenize the anchor text of the vocabulary"),
+        };
+        let anchor_text = tokenizer.decode(&[anchor], false).map_err(E::msg)?;
+
+        let mut tokens = Vec::new();
+        for token in 0..tokenizer.get_vocab_size(true) as u32 {
+            if special.contains(&token) {
+                continue;
+            }
+            let text = tokenizer.decode(&[anchor, token], false).map_err(E::msg)?;
+            if let Some(text) = text.strip_prefix(anchor_text.as_str()) {
+                if !text.contains(char::REPLACEMENT_CHARACTER) {
+                    tokens.push((token, text.to_string()));
+                }
+            }
+        }
+        Ok(Self::new(tokens))
+    }
+
+    pub fn text(&self, token: u32) -> Option<&str> {
+        self.texts.get(&token).map(String::as_str)
+    }
+
+    /// Tokens whose whole text follows the grammar from `stacks`
+    fn allowed_tokens(&self, grammar: &Grammar, stacks: &[Stack]) -> Vec<u32> {
+        let mut allowed = Vec::new();
+        self.visit(0, grammar, stacks, &mut allowed);
+        allowed
+    }
+
+    fn visit(&self, node: usize, grammar: &Grammar, stacks: &[Stack], allowed: &mut Vec<u32>) {
+        for (c, child) in &self.nodes[node].children {
+            let advanced = grammar.advance(stacks, *c);
+            if advanced.is_empty() {
+                continue;
+            }
+            allowed.extend_from_slice(&self.nodes[*child].tokens);
+            self.visit(*child, grammar, &advanced, allowed);
+        }
+    }
+}
+
+/// Vocabulary built on the first constrained generation, then shared by the next ones
+#[derive(Debug, Default)]
+pub struct VocabularyCache {
+    vocabulary: Mutex<Option<Arc<Vocabulary>>>,
+}
+
+impl VocabularyCache {
+    pub fn get(&self, tokenizer: &Tokenizer) -> Result<Arc<Vocabulary>> {
+        let mut vocabulary = self.vocabulary.lock().unwrap();
+        if let Some(vocabulary) = vocabulary.as_ref() {
+            return Ok(vocabulary.clone());
+        }
+        let start = std::time::Instant::now();
+        let built = Arc::new(Vocabulary::from_tokenizer(tokenizer)?);
+        println!("vocabulary of {} tokens built for constrained decoding in {:?}", built.texts.len(), start.elapsed());
+        *vocabulary = Some(built.clone());
+        Ok(built)
+    }
+}
+
+/// Masks the tokens which would take the generated text out of the grammar, so that the
+/// text generated once the grammar is complete is a sentence of the grammar
+pub struct TokenConstraint {
+    grammar: Arc<Grammar>,
+    vocabulary: Arc<Vocabulary>,
+    /// End of sequence tokens, only allowed once the grammar is complete
+    stop_tokens: Vec<u32>,
+    stacks: Vec<Stack>,
+}
+
+impl TokenConstraint {
+    pub fn new(grammar: Arc<Grammar>, vocabulary: Arc<Vocabulary>, stop_tokens: Vec<u32>) -> Self {
+        let stacks = grammar.initial_stacks();
+        Self { grammar, vocabulary, stop_tokens, stacks }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        Grammar::is_complete(&self.stacks)
+    }
+
+    pub fn allowed_tokens(&self) -> Vec<u32> {
+        let mut allowed = self.vocabulary.allowed_tokens(&self.grammar, &self.stacks);
+        if self.is_complete() {
+            allowed.extend_from_slice(&self.stop_tokens);
+        }
+        allowed
+    }
+
+    /// Set the logits of the disallowed tokens to -inf
+    pub fn mask(&self, logits: &Tensor) -> Result<Tensor> {
+        let allowed = self.allowed_tokens();
+        if allowed.is_empty() {
+            bail!("no token of the vocabulary can continue the grammar");
+        }
+        let values = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        let mut masked = vec![f32::NEG_INFINITY; values.len()];
+        for token in allowed {
+            if let Some(value) = values.get(token as usize) {
+                masked[token as usize] = *value;
+            }
+        }
+        let len = masked.len();
+        Ok(Tensor::from_vec(masked, len, logits.device())?)
+    }
+
+    /// Move the grammar past the text of `token`
+    pub fn accept(&mut self, token: u32) -> Result<()> {
+        if self.stop_tokens.contains(&token) {
+            if !self.is_complete() {
+                bail!("end of sequence token before the end of the grammar");
+            }
+            return Ok(());
+        }
+        let Some(text) = self.vocabulary.text(token) else {
+            bail!("token {} cannot be used with a grammar", token);
+        };
+        let mut stacks = std::mem::take(&mut self.stacks);
+        for c in text.chars() {
+            stacks = self.grammar.advance(&stacks, c);
+            if stacks.is_empty() {
+                bail!("token {} does not follow the grammar", token);
+            }
+        }
+        self.stacks = stacks;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::SeedableRng;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::llm::grammar::json_schema::json_schema_to_gbnf;
+
+    const EOS: u32 = 0;
+
+    fn vocabulary() -> Arc<Vocabulary> {
+        let texts = [
+            "{", "}", "[", "]", "\"", ",", ":", " ", "\n", "a", "b", "name", "age", "\"name\"", "\"age\":", "1", "0",
+            "42", "-", ".", "true", "false", "null", "\"}", "x", "{\"", "t", "g", "s", "e", "",
+        ];
+        let tokens = texts.iter().enumerate().map(|(i, text)| (i as u32 + 1, text.to_string())).collect();
+        Arc::new(Vocabulary::new(tokens))
+    }
+
+    fn token(vocabulary: &Vocabulary, text: &str) -> u32 {
+        *vocabulary.texts.iter().find(|(_, t)| t.as_str() == text).unwrap().0
+    }
+
+    /// Pick random allowed tokens, like a sampler would, until the end of sequence token
+    fn generate(constraint: &mut TokenConstraint, seed: u64) -> String {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut text = String::new();
+        for _ in 0..200 {
+            let allowed = constraint.allowed_tokens();
+            // Favor the end of sequence so that generations end
+            let next = if allowed.contains(&EOS) { EOS } else { *allowed.choose(&mut rng).unwrap_or_else(|| panic!("dead end after {}", text)) };
+            constraint.accept(next).unwrap();
+            if next == EOS {
+                return text;
+            }
+            text.push_str(constraint.vocabulary.text(next).unwrap());
+        }
+        panic!("generation did not end: {}", text);
+    }
+
+    #[test]
+    fn empty_tokens_are_left_out() {
+        let vocabulary = vocabulary();
+        assert_eq!(vocabulary.texts.len(), 30);
+    }
+
+    #[test]
+    fn only_tokens_following_the_grammar_are_allowed() {
+        let vocabulary = vocabulary();
+        let grammar = Arc::new(Grammar::parse(r#"root ::= "{" "\"name\"" ":" "true" "}""#).unwrap());
+        let mut constraint = TokenConstraint::new(grammar, vocabulary.clone(), vec![EOS]);
+
+        let mut allowed = constraint.allowed_tokens();
+        allowed.sort();
+        let mut expected = vec![token(&vocabulary, "{"), token(&vocabulary, "{\"")];
+        expected.sort();
+        assert_eq!(allowed, expected);
+
+        constraint.accept(token(&vocabulary, "{\"")).unwrap();
+        assert_eq!(constraint.allowed_tokens(), vec![token(&vocabulary, "name")]);
+        assert!(constraint.accept(token(&vocabulary, "x")).is_err());
+    }
+
+    #[test]
+    fn end_of_sequence_only_once_complete() {
+        let vocabulary = vocabulary();
+        let grammar = Arc::new(Grammar::parse(r#"root ::= "a" "b"?"#).unwrap());
+        let mut constraint = TokenConstraint::new(grammar, vocabulary.clone(), vec![EOS]);
+        assert!(!constraint.allowed_tokens().contains(&EOS));
+        assert!(constraint.accept(EOS).is_err());
+
+        constraint.accept(token(&vocabulary, "a")).unwrap();
+        let allowed = constraint.allowed_tokens();
+        assert!(allowed.contains(&EOS));
+        assert!(allowed.contains(&token(&vocabulary, "b")));
+    }
+
+    #[test]
+    fn masked_logits_keep_the_allowed_tokens() {
+        let vocabulary = vocabulary();
+        let grammar = Arc::new(Grammar::parse(r#"root ::= "true" | "false""#).unwrap());
+        let constraint = TokenConstraint::new(grammar, vocabulary.clone(), vec![EOS]);
+
+        let logits = Tensor::new(&[1f32; 32], &candle::Device::Cpu).unwrap();
+        let masked = constraint.mask(&logits).unwrap().to_vec1::<f32>().unwrap();
+        let kept = (0..masked.len() as u32).filter(|t| masked[*t as usize].is_finite()).collect::<Vec<_>>();
+        // `t` could start `true`
+        let mut expected = vec![token(&vocabulary, "true"), token(&vocabulary, "false"), token(&vocabulary, "t")];
+        expected.sort();
+        assert_eq!(kept, expected);
+    }
+
+    #[test]
+    fn generated_text_follows_the_json_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer" },
+                "tags": { "type": "array", "items": { "enum": ["a", "b"] }, "maxItems": 3 }
+            },
+            "required": ["name", "age"]
+        });
+        let grammar = Arc::new(Grammar::parse(json_schema_to_gbnf(&schema).unwrap().as_str()).unwrap());
+        let vocabulary = vocabulary();
+
+        for seed in 0..20 {
+            let mut constraint = TokenConstraint::new(grammar.clone(), vocabulary.clone(), vec![EOS]);
+            let text = generate(&mut constraint, seed);
+            let value: Value = serde_json::from_str(text.as_str()).unwrap_or_else(|e| panic!("{}: {}", text, e));
+            assert!(value["name"].is_string(), "{}", text);
+            assert!(value["age"].as_f64().is_some_and(|age| age.fract() == 0.0), "{}", text);
+            if let Some(tags) = value.get("tags") {
+                assert!(tags.as_array().unwrap().len() <= 3, "{}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn dead_ends_are_reported() {
+        let vocabulary = vocabulary();
+        let grammar = Arc::new(Grammar::parse(r#"root ::= "z""#).unwrap());
+        let constraint = TokenConstraint::new(grammar, vocabulary, vec![EOS]);
+        let logits = Tensor::new(&[0f32; 32], &candle::Device::Cpu).unwrap();
+        assert!(constraint.mask(&logits).is_err());
+    }
+}
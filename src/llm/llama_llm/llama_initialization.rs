@@ -1,144 +1,158 @@
 #![feature(const_trait_impl)]
 
-use std::path::PathBuf;
-use anyhow::{Error as E, Result};
+use std::sync::Arc;
+use anyhow::Result;
 
 
 use candle::{Device};
-use candle::quantized::gguf_file;
-use candle_transformers::models::quantized_llama::ModelWeights;
-use hf_hub::{api::sync::Api, Repo, RepoType};
-use hf_hub::api::sync::ApiRepo;
-use tokenizers::Tokenizer;
-use crate::args_init::args::Args;
+use crate::llm::llama_llm::quantized_llama::{self as model, ModelWeights};
+use crate::args_init::args::{Args, ModelFamily};
 use crate::llm::device::device;
-use crate::llm::quantized_llm::{QuantizedLLM, QuantizedLlmPackage};
-
-
-
-
-
-pub struct QuantizedLlmModel;
-
-impl QuantizedLLM for QuantizedLlmModel {
-    fn initialize(&self, args_init: Args) -> Result<QuantizedLlmPackage> {
-
-        /**********************************************************************/
-        // Tracing Initialization
-        /**********************************************************************/
-
-        println!(
-            "avx: {}, neon: {}, simd128: {}, f16c: {}",
-            candle::utils::with_avx(),
-            candle::utils::with_neon(),
-            candle::utils::with_simd128(),
-            candle::utils::with_f16c()
-        );
-        println!(
-            "temp: {:.2} repeat-penalty: {:.2} repeat-last-n: {}",
-            args_init.temperature,
-            args_init.repeat_penalty,
-            args_init.repeat_last_n
-        );
-
-        /**********************************************************************/
-        // End Initialization
-        /**********************************************************************/
-
-        /**********************************************************************/
-        // Retrieve Model Files and Tokenizer
-        /**********************************************************************/
-        let start = std::time::Instant::now();
-
-        let api = Api::new()?;
-
-        let repo_model = api.repo(Repo::with_revision(
-            args_init.model_id,
-            RepoType::Model,
-            args_init.revision.clone(),
-        ));
-
-        let model_filenames = get_filenames_model(&repo_model, args_init.local_model_file, args_init.model_file)?;
-
-        let repo_tokenizer = api.repo(Repo::with_revision(
-            args_init.tokenizer_id,
-            RepoType::Model,
-            args_init.revision,
-        ));
-
-        let tokenizer_filename = repo_tokenizer.get(args_init.tokenizer_file.as_str())?;
-
-        println!("retrieved the files in {:?}", start.elapsed());
-
-        /**********************************************************************/
-        // End Retrieval Model Files and Tokenizer Files
-        /**********************************************************************/
-
-        /**********************************************************************/
-        // Construction LLM Package
-        /**********************************************************************/
-
-        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
-
-        let start = std::time::Instant::now();
-
-
-        let model_path = model_filenames[0].clone();
-
-
-        let mut file = std::fs::File::open(&model_path)?;
-
-        let gguf_model_content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(model_path))?;
-        let mut total_size_in_bytes = 0;
-        for (_, tensor) in gguf_model_content.tensor_infos.iter() {
-            let elem_count = tensor.shape.elem_count();
-            total_size_in_bytes +=
-                elem_count * tensor.ggml_dtype.type_size() / tensor.ggml_dtype.block_size();
+use crate::llm::chat_template::load_chat_template;
+use crate::llm::gguf_metadata::GgufMetadata;
+use crate::llm::model_files::{load_tokenizer, read_gguf, retrieve_model_files};
+use crate::llm::sampling_params::SamplingParams;
+use crate::llm::prompt_template::PromptTemplate;
+use crate::llm::quantized_llm::QuantizedLlmPackage;
+
+
+
+
+
+pub fn initialize(mut args_init: Args) -> Result<QuantizedLlmPackage> {
+
+    /**********************************************************************/
+    // Tracing Initialization
+    /**********************************************************************/
+
+    println!(
+        "avx: {}, neon: {}, simd128: {}, f16c: {}",
+        candle::utils::with_avx(),
+        candle::utils::with_neon(),
+        candle::utils::with_simd128(),
+        candle::utils::with_f16c()
+    );
+    println!(
+        "temp: {:.2} repeat-penalty: {:.2} repeat-last-n: {}",
+        args_init.temperature,
+        args_init.repeat_penalty,
+        args_init.repeat_last_n
+    );
+
+    // Repos and files not given on the command line
+    args_init.apply_model_family_defaults(ModelFamily::Llama);
+
+    // Default sampling parameters, requests can override them
+    let sampling = SamplingParams::from_args(&args_init);
+
+    /**********************************************************************/
+    // End Initialization
+    /**********************************************************************/
+
+    /**********************************************************************/
+    // Retrieve Model Files and Tokenizer
+    /**********************************************************************/
+    let start = std::time::Instant::now();
+
+    // Local files bypass the hub entirely
+    let model_files = retrieve_model_files(&args_init)?;
+
+    println!("retrieved the files in {:?}", start.elapsed());
+
+    /**********************************************************************/
+    // End Retrieval Model Files and Tokenizer Files
+    /**********************************************************************/
+
+    /**********************************************************************/
+    // Construction LLM Package
+    /**********************************************************************/
+
+    let tokenizer = load_tokenizer(&model_files.tokenizer)?;
+
+    let start = std::time::Instant::now();
+
+
+    let (mut file, gguf_model_content) = read_gguf(&model_files.model)?;
+
+    // Architecture parameters are read by the model itself, keep the context length and special tokens
+    let metadata = GgufMetadata::from_content(&gguf_model_content)?;
+    metadata.log();
+    metadata.check_architecture(&["llama", "mistral"])?;
+
+    // An explicit --model-type wins over the chat template shipped with the model
+    let prompt_template = match &args_init.model_type {
+        Some(model_type) => PromptTemplate::for_model(ModelFamily::Llama, model_type.as_str()),
+        None => match load_chat_template(
+            metadata.chat_template.as_deref(),
+            model_files.tokenizer_config.as_deref(),
+            &tokenizer,
+            metadata.bos_token_id,
+            metadata.eos_token_id,
+        )? {
+            Some(chat_template) => PromptTemplate::Chat(Arc::new(chat_template)),
+            None => PromptTemplate::for_model(ModelFamily::Llama, "mistral"),
+        },
+    };
+
+    let mut stop_tokens = metadata.eos_token_id.into_iter().collect::<Vec<_>>();
+    for token in prompt_template.stop_tokens() {
+        match tokenizer.token_to_id(token.as_str()) {
+            Some(id) if !stop_tokens.contains(&id) => stop_tokens.push(id),
+            Some(_) => {}
+            None => println!("stop token {} is not in the vocabulary", token),
         }
+    }
+    if stop_tokens.is_empty() {
+        anyhow::bail!("no end of sequence token found, please use --model-type");
+    }
+    println!("chat format: {}", prompt_template.name());
+
+    let mut total_size_in_bytes = 0;
+    for (_, tensor) in gguf_model_content.tensor_infos.iter() {
+        let elem_count = tensor.shape.elem_count();
+        total_size_in_bytes +=
+            elem_count * tensor.ggml_dtype.type_size() / tensor.ggml_dtype.block_size();
+    }
 
-        println!(
-            "loaded {:?} tensors ({}) in {:.2}s",
-            gguf_model_content.tensor_infos.len(),
-            &format_size(total_size_in_bytes),
-            start.elapsed().as_secs_f32(),
-        );
+    println!(
+        "loaded {:?} tensors ({}) in {:.2}s",
+        gguf_model_content.tensor_infos.len(),
+        &format_size(total_size_in_bytes),
+        start.elapsed().as_secs_f32(),
+    );
 
-        /*
-        // CPU
-        let device = device(true)?;
+    /*
+    // CPU
+    let device = device(true)?;
 
-        let (model_weights, device) = (ModelWeights::from_gguf(gguf_model_content, &mut file, &device)?, Device::Cpu);
-        */
+    let (model_weights, device) = (ModelWeights::from_gguf(gguf_model_content, &mut file, &device)?, Device::Cpu);
+    */
 
-        let device_model = device(false)?;
-        let (model_weights, device_model) = (ModelWeights::from_gguf(gguf_model_content, &mut file, &device_model)?, Device::Cpu);
+    let device_model = device(false)?;
+    let (model_weights, device_model) = (ModelWeights::from_gguf(gguf_model_content, &mut file, &device_model)?, Device::Cpu);
 
 
 
-        /**********************************************************************/
-        // End Construction LLM Package
-        /**********************************************************************/
+    /**********************************************************************/
+    // End Construction LLM Package
+    /**********************************************************************/
 
-        println!("loaded the model in {:?}", start.elapsed());
+    println!("loaded the model in {:?}", start.elapsed());
 
-        Ok(QuantizedLlmPackage {
-            model_type:args_init.model_type,
-            model_weights,
-            device:device_model,
-            tokenizer,
-            seed: args_init.seed,
-            temperature: args_init.temperature,
-            top_p: args_init.top_p,
-            repeat_penalty: args_init.repeat_penalty,
-            repeat_last_n: args_init.repeat_last_n,
-            sample_len: args_init.sample_len,
-        })
-    }
+    Ok(QuantizedLlmPackage {
+        prompt_template,
+        model_weights,
+        device:device_model,
+        tokenizer,
+        sampling,
+        // The rotary tables of the model stop at MAX_SEQ_LEN
+        context_length: metadata.context_length.unwrap_or(model::MAX_SEQ_LEN).min(model::MAX_SEQ_LEN),
+        stop_tokens,
+        prefix_cache: Default::default(),
+        vocabulary: Default::default(),
+    })
 }
 
-fn get_filenames_model(repo:&ApiRepo, weight_files:Option<String>,model_file:Option<String>) -> Result<Vec<PathBuf>> {
-    Ok( vec![repo.get(model_file.unwrap().as_str())?])
-}
 
 
 
@@ -152,4 +166,4 @@ fn format_size(size_in_bytes: usize) -> String {
     } else {
         format!("{:.2}GB", size_in_bytes as f64 / 1e9)
     }
-}
\ No newline at end of file
+}
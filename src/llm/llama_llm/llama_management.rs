@@ -1,61 +1,75 @@
 use anyhow::{ Result};
 use candle::{ Device, Tensor};
-use candle_transformers::generation::LogitsProcessor;
 
 
 use tokenizers::Tokenizer;
 use crate::llm::token_output_stream::TokenOutputStream;
 use tokio::sync::mpsc::{UnboundedSender};
 
+use crate::llm::generation_summary::{FinishReason, GenerationSummary};
+use crate::llm::classification::{label_tokens, rank, token_logprob, Classification};
+use crate::llm::grammar::token_constraint::{TokenConstraint, VocabularyCache};
+use crate::llm::logit_transforms::LogitTransforms;
+use crate::llm::sampler::Sampler;
+use crate::llm::prefix_cache::PrefixCache;
+use crate::llm::prompt_template::{encode_prompt, Exchange, PromptTemplate};
 use crate::llm::quantized_llm::QuantizedTextGeneration;
+use crate::llm::sampling_params::SamplingParams;
 
-use candle_transformers::models::quantized_llama as model;
+use crate::llm::llama_llm::quantized_llama as model;
 use model::ModelWeights;
 
 impl QuantizedTextGeneration {
     #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
-        model_type:String,
+        prompt_template:PromptTemplate,
         model_weights: ModelWeights,
         tokenizer: Tokenizer,
-        seed: u64,
-        temp: Option<f64>,
-        top_p: Option<f64>,
+        stop_tokens: Vec<u32>,
+        sampler: Sampler,
         repeat_penalty: f32,
         repeat_last_n: usize,
         device: &Device,
     ) -> Self {
 
-        let logits_processor = LogitsProcessor::new(seed, temp, top_p);
-
         Self {
-            model_type,
+            prompt_template,
             model_weights,
             tokenizer: TokenOutputStream::new(tokenizer),
-            logits_processor,
+            stop_tokens,
+            sampler,
             repeat_penalty,
             repeat_last_n,
             device: device.clone(),
         }
     }
 
-    pub(crate) fn run(&mut self, prompt: &str, sample_len: usize,seed:u64,temperature:Option<f64>,top_p:Option<f64>, tx:UnboundedSender<String>,context:&str) -> Result<()> {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn run(&mut self, prompt: &str, history:&[Exchange], sampling:&SamplingParams, tx:UnboundedSender<String>,context:&str,prefix_cache:&PrefixCache<ModelWeights>,vocabulary:&VocabularyCache) -> Result<GenerationSummary> {
 
+        let start_run = std::time::Instant::now();
         self.tokenizer.clear();
+        self.tokenizer.set_stop_sequences(sampling.stop.clone());
+        let logit_transforms = LogitTransforms::new(sampling);
+
+        // Only the tokens following the grammar can be sampled
+        let mut constraint = match &sampling.grammar {
+            Some(grammar) => Some(TokenConstraint::new(grammar.clone(), vocabulary.get(self.tokenizer.tokenizer())?, self.stop_tokens.clone())),
+            None => None,
+        };
 
-        let mut pre_prompt_tokens = vec![];
+        let pre_prompt_tokens = vec![];
 
-        // Text Generation Prompt for Mistral
-        let prompt=format!("<s>[INST]{}[/INST]",prompt.trim());
+        // Text Generation Prompt, following the chat format of the model type
+        let template=self.prompt_template.clone();
+        let prefix=template.prefix(context)?;
+        let prompt=template.render_conversation(context,history,prompt)?;
 
-        let tokens = self.tokenizer
-            .tokenizer()
-            .encode(prompt, true)
-            .map_err(anyhow::Error::msg)?;
+        let tokens = self.encode(prompt.as_str())?;
 
-        let prompt_tokens = [&pre_prompt_tokens, tokens.get_ids()].concat();
+        let prompt_tokens = [&pre_prompt_tokens, tokens.as_slice()].concat();
 
-        let to_sample = sample_len.saturating_sub(1);
+        let to_sample = sampling.sample_len.saturating_sub(1);
 
         let prompt_tokens = if prompt_tokens.len() + to_sample > model::MAX_SEQ_LEN - 10 {
             let to_remove = prompt_tokens.len() + to_sample + 10 - model::MAX_SEQ_LEN;
@@ -65,16 +79,24 @@ impl QuantizedTextGeneration {
         };
 
         let mut all_tokens = vec![];
-        let mut logits_processor = LogitsProcessor::new(seed, temperature, top_p);
 
         let start_prompt_processing = std::time::Instant::now();
 
+        // Prompt tokens already in the kv cache
+        let processed = self.reuse_prefix(prefix.as_str(), &prompt_tokens, prefix_cache)?;
+
         let mut next_token = {
-            let input = Tensor::new(prompt_tokens.as_slice(), &Device::Cpu)?.unsqueeze(0)?;
-            let logits = self.model_weights.forward(&input, 0)?;
-            let logits = logits.squeeze(0)?;
-            logits_processor.sample(&logits)?
+            let logits = self.forward(&prompt_tokens[processed..], processed)?;
+            let logits = logit_transforms.apply(&logits, &all_tokens)?;
+            let logits = match &constraint {
+                Some(constraint) => constraint.mask(&logits)?,
+                None => logits,
+            };
+            self.sampler.sample(&logits)?
         };
+        if let Some(constraint) = constraint.as_mut() {
+            constraint.accept(next_token)?;
+        }
 
         let prompt_dt = start_prompt_processing.elapsed();
 
@@ -84,14 +106,29 @@ impl QuantizedTextGeneration {
             let _ = tx.send(t.to_string());
         }
 
-        // Retrieve eos token
-        let eos_token = get_eos_token(self.model_type.clone()).unwrap();
-        let eos_token = *self.tokenizer.tokenizer().get_vocab(true).get(eos_token.as_str()).unwrap();
-
         let start_post_prompt = std::time::Instant::now();
         let mut sampled = 0;
+        let mut finish_reason = self.finish_reason(next_token);
 
         for index in 0..to_sample {
+
+            // Eos token or stop sequence
+            if finish_reason != FinishReason::Length {
+                break;
+            }
+            if sampling.max_time.is_some_and(|max_time| start_run.elapsed() >= max_time) {
+                finish_reason = FinishReason::MaxTime;
+                break;
+            }
+
+            // Stop as soon as the client went away
+            if tx.is_closed() {
+                println!("\nrequest cancelled by the client after {} tokens", all_tokens.len());
+                return Ok(GenerationSummary::new(FinishReason::Cancelled, prompt_tokens.len(), all_tokens.len(), start_post_prompt.elapsed())
+                    .with_prompt_processing(prompt_tokens.len() - processed, prompt_dt)
+                    .with_seed(sampling.seed));
+            }
+
             let input = Tensor::new(&[next_token], &Device::Cpu)?.unsqueeze(0)?;
             let logits = self.model_weights.forward(&input, prompt_tokens.len() + index)?;
             let logits = logits.squeeze(0)?;
@@ -105,16 +142,22 @@ impl QuantizedTextGeneration {
                     &all_tokens[start_at..],
                 )?
             };
-            next_token = logits_processor.sample(&logits)?;
+            let logits = logit_transforms.apply(&logits, &all_tokens)?;
+            let logits = match &constraint {
+                Some(constraint) => constraint.mask(&logits)?,
+                None => logits,
+            };
+            next_token = self.sampler.sample(&logits)?;
+            if let Some(constraint) = constraint.as_mut() {
+                constraint.accept(next_token)?;
+            }
             all_tokens.push(next_token);
 
             if let Some(t) =  self.tokenizer.next_token(next_token)? {
                 let _ = tx.send(t.to_string());
             }
             sampled += 1;
-            if next_token == eos_token {
-                break;
-            };
+            finish_reason = self.finish_reason(next_token);
         }
         if let Some(rest) =  self.tokenizer.decode_rest().map_err(candle::Error::msg)? {
             let _ = tx.send(rest.to_string());
@@ -132,19 +175,100 @@ impl QuantizedTextGeneration {
             sampled as f64 / dt.as_secs_f64(),
         );
 
-        Ok(())
+        Ok(GenerationSummary::new(finish_reason, prompt_tokens.len(), all_tokens.len(), dt)
+            .with_stop_sequence(self.tokenizer.stop_sequence())
+            .with_prompt_processing(prompt_tokens.len() - processed, prompt_dt)
+            .with_seed(sampling.seed))
 
     }
 
 }
 
-fn get_eos_token(which:String) -> Result<String> {
+impl QuantizedTextGeneration {
+    /// Score each label as the answer to `text`, every label starting from the model state
+    /// after the prompt. No token is sampled.
+    pub(crate) fn classify(&mut self, text:&str, labels:&[String], context:&str, prefix_cache:&PrefixCache<ModelWeights>) -> Result<Classification> {
+        let prefix=self.prompt_template.prefix(context)?;
+        let prompt=self.prompt_template.render_conversation(context,&[],text)?;
+
+        let tokens = self.encode(prompt.as_str())?;
+
+        // Prompt tokens already in the kv cache
+        let processed = self.reuse_prefix(prefix.as_str(), &tokens, prefix_cache)?;
+        let prompt_logits = self.forward(&tokens[processed..], processed)?;
+        let prompt_model = self.model_weights.clone();
+
+        let mut logprobs = Vec::with_capacity(labels.len());
+        let mut token_counts = Vec::with_capacity(labels.len());
+        for label in labels {
+            let label_tokens = label_tokens(self.tokenizer.tokenizer(), prompt.as_str(), &tokens, label)?;
+            self.model_weights = prompt_model.clone();
+            let mut logits = prompt_logits.clone();
+            let mut logprob = 0.;
+            for (index, token) in label_tokens.iter().enumerate() {
+                logprob += token_logprob(&logits, *token)?;
+                if index + 1 < label_tokens.len() {
+                    logits = self.forward(std::slice::from_ref(token), tokens.len() + index)?;
+                }
+            }
+            logprobs.push(logprob);
+            token_counts.push(label_tokens.len());
+        }
+
+        Ok(rank(labels, &logprobs, &token_counts, tokens.len()))
+    }
+
+    /// Process the prompt prefix for `context` once, so that requests can start from it
+    pub(crate) fn warm_prefix(&mut self, context:&str, prefix_cache:&PrefixCache<ModelWeights>) -> Result<()> {
+        let prefix=self.prompt_template.prefix(context)?;
+        if prefix_cache.contains(prefix.as_str()) {
+            return Ok(());
+        }
+        let prefix_tokens = self.encode(prefix.as_str())?;
+        self.forward(&prefix_tokens, 0)?;
+        prefix_cache.insert(prefix.as_str(), prefix_tokens, self.model_weights.clone());
+        Ok(())
+    }
+
+    /// Start from the cached state of the prompt prefix when possible, otherwise process
+    /// the prefix on its own and cache the resulting state.
+    /// Returns the number of prompt tokens already processed.
+    fn reuse_prefix(&mut self, prefix:&str, tokens:&[u32], prefix_cache:&PrefixCache<ModelWeights>) -> Result<usize> {
+        if let Some((model_weights, processed)) = prefix_cache.lookup(prefix, tokens) {
+            self.model_weights = model_weights;
+            return Ok(processed);
+        }
 
-    let eos_token=match which.as_str() {
-        "mistral" => "</s>".to_string(),
-        "open_chat" => "<|end_of_turn|>".to_string(),
-        _ => "</s>".to_string(),
-    };
+        let prefix_tokens = self.encode(prefix)?;
+        if prefix_tokens.is_empty() || prefix_tokens.len() >= tokens.len() || !tokens.starts_with(&prefix_tokens) {
+            return Ok(0);
+        }
+
+        self.forward(&prefix_tokens, 0)?;
+        let processed = prefix_tokens.len();
+        prefix_cache.insert(prefix, prefix_tokens, self.model_weights.clone());
+        Ok(processed)
+    }
 
-    Ok(eos_token.to_string())
-}
\ No newline at end of file
+    /// Whether generation ends after `token`
+    fn finish_reason(&self, token:u32) -> FinishReason {
+        if self.stop_tokens.contains(&token) {
+            FinishReason::Eos
+        } else if self.tokenizer.stop_sequence().is_some() {
+            FinishReason::Stop
+        } else {
+            FinishReason::Length
+        }
+    }
+
+    fn encode(&self, text:&str) -> Result<Vec<u32>> {
+        encode_prompt(self.tokenizer.tokenizer(), text)
+    }
+
+    /// Logits of the last token, `index_pos` being the position of the first token
+    fn forward(&mut self, tokens:&[u32], index_pos:usize) -> Result<Tensor> {
+        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        let logits = self.model_weights.forward(&input, index_pos)?;
+        Ok(logits.squeeze(0)?)
+    }
+}
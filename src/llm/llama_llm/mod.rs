@@ -1,4 +1,5 @@
-
 pub mod llama_initialization;
 
-pub mod llama_management;
\ No newline at end of file
+pub mod llama_management;
+
+pub mod quantized_llama;
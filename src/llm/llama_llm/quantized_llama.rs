@@ -0,0 +1,366 @@
+//! Quantized llama model, adapted from `candle_transformers::models::quantized_llama`.
+//!
+//! The candle model builds its causal mask as if the kv cache were empty, several tokens can
+//! only be processed at once from position 0. Here the mask covers the cached positions too,
+//! so that the rest of a prompt is processed in a single forward after a cached prefix.
+//! Only gguf files are loaded, the tracing spans are left out.
+
+use candle::quantized::{gguf_file, QMatMul, QTensor};
+use candle::{DType, Device, IndexOp, Result, Tensor};
+use candle_nn::{Embedding, Module};
+use candle_transformers::quantized_nn::RmsNorm;
+
+pub const MAX_SEQ_LEN: usize = 4096;
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    feed_forward_w1: QMatMul,
+    feed_forward_w2: QMatMul,
+    feed_forward_w3: QMatMul,
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let w1 = self.feed_forward_w1.forward(xs)?;
+        let w3 = self.feed_forward_w3.forward(xs)?;
+        self.feed_forward_w2.forward(&(candle_nn::ops::silu(&w1)? * w3)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum MlpOrMoe {
+    Mlp(Mlp),
+    MoE {
+        n_expert_used: usize,
+        feed_forward_gate_inp: QMatMul,
+        experts: Vec<Mlp>,
+    },
+}
+
+impl Module for MlpOrMoe {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::MoE { feed_forward_gate_inp, experts, n_expert_used } => {
+                let (b_size, seq_len, hidden_dim) = xs.dims3()?;
+                let xs = xs.reshape(((), hidden_dim))?;
+                let router_logits = feed_forward_gate_inp.forward(&xs)?;
+                let routing_weights = candle_nn::ops::softmax_last_dim(&router_logits)?;
+                let routing_weights = routing_weights.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+
+                // Rows evaluated by each expert, along with their normalized routing weight
+                let mut top_x = vec![vec![]; experts.len()];
+                let mut selected_rws = vec![vec![]; experts.len()];
+                for (row_idx, rw) in routing_weights.iter().enumerate() {
+                    let mut dst = (0..rw.len() as u32).collect::<Vec<u32>>();
+                    dst.sort_by(|&i, &j| rw[j as usize].total_cmp(&rw[i as usize]));
+                    let sum_routing_weights: f32 = dst.iter().take(*n_expert_used).map(|&i| rw[i as usize]).sum();
+                    for &expert_idx in dst.iter().take(*n_expert_used) {
+                        let expert_idx = expert_idx as usize;
+                        top_x[expert_idx].push(row_idx as u32);
+                        selected_rws[expert_idx].push(rw[expert_idx] / sum_routing_weights)
+                    }
+                }
+
+                let mut ys = xs.zeros_like()?;
+                for (expert_idx, expert_layer) in experts.iter().enumerate() {
+                    let top_x = &top_x[expert_idx];
+                    if top_x.is_empty() {
+                        continue;
+                    }
+                    let top_x = Tensor::new(top_x.as_slice(), xs.device())?;
+                    let selected_rws = Tensor::new(selected_rws[expert_idx].as_slice(), xs.device())?.reshape(((), 1))?;
+                    let current_state = xs.index_select(&top_x, 0)?.reshape(((), hidden_dim))?;
+                    let current_hidden_states = expert_layer.forward(&current_state)?.broadcast_mul(&selected_rws)?;
+                    ys = ys.index_add(&top_x, &current_hidden_states, 0)?;
+                }
+
+                ys.reshape((b_size, seq_len, hidden_dim))
+            }
+            Self::Mlp(mlp) => mlp.forward(xs),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+    attention_wq: QMatMul,
+    attention_wk: QMatMul,
+    attention_wv: QMatMul,
+    attention_wo: QMatMul,
+    attention_norm: RmsNorm,
+    mlp_or_moe: MlpOrMoe,
+    ffn_norm: RmsNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    cos: Tensor,
+    sin: Tensor,
+    neg_inf: Tensor,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: &Tensor) -> Result<Tensor> {
+    let shape = mask.shape();
+    mask.where_cond(&on_true.broadcast_as(shape.dims())?, on_false)
+}
+
+impl LayerWeights {
+    fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, _n_head, seq_len, _n_embd) = x.dims4()?;
+        let cos = self.cos.narrow(0, index_pos, seq_len)?;
+        let sin = self.sin.narrow(0, index_pos, seq_len)?;
+        candle_nn::rotary_emb::rope_i(&x.contiguous()?, &cos, &sin)
+    }
+
+    fn forward_attn(&mut self, x: &Tensor, mask: Option<&Tensor>, index_pos: usize) -> Result<Tensor> {
+        let (b_sz, seq_len, n_embd) = x.dims3()?;
+        let q = self.attention_wq.forward(x)?;
+        let k = self.attention_wk.forward(x)?;
+        let v = self.attention_wv.forward(x)?;
+
+        let q = q.reshape((b_sz, seq_len, self.n_head, self.head_dim))?.transpose(1, 2)?;
+        let k = k.reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?.transpose(1, 2)?;
+        let v = v.reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?.transpose(1, 2)?.contiguous()?;
+
+        let q = self.apply_rotary_emb(&q, index_pos)?;
+        let k = self.apply_rotary_emb(&k, index_pos)?;
+
+        let (k, v) = match &self.kv_cache {
+            Some((k_cache, v_cache)) if index_pos > 0 => (Tensor::cat(&[k_cache, &k], 2)?, Tensor::cat(&[v_cache, &v], 2)?),
+            _ => (k, v),
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
+
+        // Grouped query attention
+        let k = candle_transformers::utils::repeat_kv(k, self.n_head / self.n_kv_head)?;
+        let v = candle_transformers::utils::repeat_kv(v, self.n_head / self.n_kv_head)?;
+
+        let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+        let att = match mask {
+            None => att,
+            Some(mask) => masked_fill(&att, &mask.broadcast_as(att.shape())?, &self.neg_inf)?,
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        let y = att.matmul(&v.contiguous()?)?;
+
+        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
+        self.attention_wo.forward(&y)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: RmsNorm,
+    output: QMatMul,
+}
+
+fn precomput_freqs_cis(head_dim: usize, freq_base: f32, device: &Device) -> Result<(Tensor, Tensor)> {
+    let theta: Vec<_> = (0..head_dim)
+        .step_by(2)
+        .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
+        .collect();
+    let theta = Tensor::new(theta.as_slice(), device)?;
+    let idx_theta = Tensor::arange(0, MAX_SEQ_LEN as u32, device)?
+        .to_dtype(DType::F32)?
+        .reshape((MAX_SEQ_LEN, 1))?
+        .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+    Ok((idx_theta.cos()?, idx_theta.sin()?))
+}
+
+/// Causal mask of `seq_len` new tokens following `index_pos` cached ones, 1 where attention is not allowed
+fn causal_mask(seq_len: usize, index_pos: usize, device: &Device) -> Result<Tensor> {
+    let mask: Vec<_> = (0..seq_len)
+        .flat_map(|i| (0..index_pos + seq_len).map(move |j| u8::from(j > index_pos + i)))
+        .collect();
+    Tensor::from_slice(&mask, (seq_len, index_pos + seq_len), device)
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: std::io::Seek + std::io::Read>(ct: gguf_file::Content, reader: &mut R, device: &Device) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+
+        let n_expert = md_get("llama.expert_count").and_then(|v| v.to_u32()).unwrap_or(0) as usize;
+        let n_expert_used = md_get("llama.expert_used_count").and_then(|v| v.to_u32()).unwrap_or(0) as usize;
+        let head_count = md_get("llama.attention.head_count")?.to_u32()? as usize;
+        let head_count_kv = md_get("llama.attention.head_count_kv")?.to_u32()? as usize;
+        let block_count = md_get("llama.block_count")?.to_u32()? as usize;
+        let embedding_length = md_get("llama.embedding_length")?.to_u32()? as usize;
+        let rope_dim = md_get("llama.rope.dimension_count")?.to_u32()? as usize;
+        let rms_norm_eps = md_get("llama.attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
+        let rope_freq_base = md_get("llama.rope.freq_base").and_then(|m| m.to_f32()).unwrap_or(10000f32);
+
+        let (cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base, device)?;
+        let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
+
+        let tok_embeddings_q = ct.tensor(reader, "token_embd.weight", device)?;
+        let tok_embeddings = tok_embeddings_q.dequantize(device)?;
+        let norm = RmsNorm::from_qtensor(ct.tensor(reader, "output_norm.weight", device)?, rms_norm_eps)?;
+        // Tied embeddings when there is no output tensor
+        let output = match ct.tensor(reader, "output.weight", device) {
+            Ok(tensor) => tensor,
+            Err(_) => tok_embeddings_q,
+        };
+
+        let mut tensor = |name: String| -> Result<QTensor> { ct.tensor(reader, &name, device) };
+        let mut layers = Vec::with_capacity(block_count);
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            let mut mlp = |suffix: &str| -> Result<Mlp> {
+                Ok(Mlp {
+                    feed_forward_w1: QMatMul::from_qtensor(tensor(format!("{prefix}.ffn_gate{suffix}.weight"))?)?,
+                    feed_forward_w2: QMatMul::from_qtensor(tensor(format!("{prefix}.ffn_down{suffix}.weight"))?)?,
+                    feed_forward_w3: QMatMul::from_qtensor(tensor(format!("{prefix}.ffn_up{suffix}.weight"))?)?,
+                })
+            };
+            let mlp_or_moe = if n_expert <= 1 {
+                MlpOrMoe::Mlp(mlp("")?)
+            } else {
+                let experts = (0..n_expert).map(|i| mlp(&format!(".{i}"))).collect::<Result<Vec<_>>>()?;
+                MlpOrMoe::MoE {
+                    n_expert_used,
+                    feed_forward_gate_inp: QMatMul::from_qtensor(tensor(format!("{prefix}.ffn_gate_inp.weight"))?)?,
+                    experts,
+                }
+            };
+            layers.push(LayerWeights {
+                attention_wq: QMatMul::from_qtensor(tensor(format!("{prefix}.attn_q.weight"))?)?,
+                attention_wk: QMatMul::from_qtensor(tensor(format!("{prefix}.attn_k.weight"))?)?,
+                attention_wv: QMatMul::from_qtensor(tensor(format!("{prefix}.attn_v.weight"))?)?,
+                attention_wo: QMatMul::from_qtensor(tensor(format!("{prefix}.attn_output.weight"))?)?,
+                attention_norm: RmsNorm::from_qtensor(tensor(format!("{prefix}.attn_norm.weight"))?, rms_norm_eps)?,
+                mlp_or_moe,
+                ffn_norm: RmsNorm::from_qtensor(tensor(format!("{prefix}.ffn_norm.weight"))?, rms_norm_eps)?,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim: embedding_length / head_count,
+                cos: cos.clone(),
+                sin: sin.clone(),
+                neg_inf: neg_inf.clone(),
+                kv_cache: None,
+            })
+        }
+
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output: QMatMul::from_qtensor(output)?,
+        })
+    }
+
+    /// Logits of the last token of `x`, `index_pos` being the number of tokens already in the kv cache
+    pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let mask = if seq_len == 1 {
+            None
+        } else {
+            Some(causal_mask(seq_len, index_pos, x.device())?)
+        };
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        for layer in self.layers.iter_mut() {
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attention_norm.forward(&x)?;
+            let attn = layer.forward_attn(&x, mask.as_ref(), index_pos)?;
+            let x = (attn + residual)?;
+
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+            let x = layer.mlp_or_moe.forward(&x)?;
+            layer_in = (x + residual)?;
+        }
+        let x = self.norm.forward(&layer_in)?;
+        let x = x.i((.., seq_len - 1, ..))?;
+        self.output.forward(&x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle::quantized::gguf_file::Value;
+
+    use super::*;
+
+    /// Two layers with random weights, 2 query heads sharing 1 kv head
+    fn tiny_model() -> ModelWeights {
+        let (vocab, embd, hidden, head_dim) = (16, 8, 16, 4);
+        let random = |shape: &[usize]| QTensor::quantize(&Tensor::randn(0f32, 0.5, shape, &Device::Cpu).unwrap(), candle::quantized::GgmlDType::F32).unwrap();
+        let mut tensors = vec![
+            ("token_embd.weight".to_string(), random(&[vocab, embd])),
+            ("output_norm.weight".to_string(), random(&[embd])),
+            ("output.weight".to_string(), random(&[vocab, embd])),
+        ];
+        for layer in 0..2 {
+            for (name, shape) in [
+                ("attn_q", vec![embd, embd]),
+                ("attn_k", vec![head_dim, embd]),
+                ("attn_v", vec![head_dim, embd]),
+                ("attn_output", vec![embd, embd]),
+                ("ffn_gate", vec![hidden, embd]),
+                ("ffn_down", vec![embd, hidden]),
+                ("ffn_up", vec![hidden, embd]),
+                ("attn_norm", vec![embd]),
+                ("ffn_norm", vec![embd]),
+            ] {
+                tensors.push((format!("blk.{layer}.{name}.weight"), random(&shape)));
+            }
+        }
+        let metadata = [
+            ("llama.attention.head_count", Value::U32(2)),
+            ("llama.attention.head_count_kv", Value::U32(1)),
+            ("llama.block_count", Value::U32(2)),
+            ("llama.embedding_length", Value::U32(embd as u32)),
+            ("llama.rope.dimension_count", Value::U32(head_dim as u32)),
+            ("llama.attention.layer_norm_rms_epsilon", Value::F32(1e-5)),
+        ];
+
+        let mut buffer = std::io::Cursor::new(Vec::new());
+        gguf_file::write(
+            &mut buffer,
+            &metadata.iter().map(|(key, value)| (*key, value)).collect::<Vec<_>>(),
+            &tensors.iter().map(|(name, tensor)| (name.as_str(), tensor)).collect::<Vec<_>>(),
+        )
+        .unwrap();
+        buffer.set_position(0);
+        let content = gguf_file::Content::read(&mut buffer).unwrap();
+        ModelWeights::from_gguf(content, &mut buffer, &Device::Cpu).unwrap()
+    }
+
+    fn max_difference(a: &Tensor, b: &Tensor) -> f32 {
+        (a - b).unwrap().abs().unwrap().flatten_all().unwrap().max(0).unwrap().to_scalar::<f32>().unwrap()
+    }
+
+    #[test]
+    fn prompt_after_a_cached_prefix_matches_a_single_pass() {
+        let tokens = Tensor::new(&[[1u32, 5, 7, 2, 9, 3, 11]], &Device::Cpu).unwrap();
+        let mut model = tiny_model();
+        let mut whole = model.clone();
+        let expected = whole.forward(&tokens, 0).unwrap();
+
+        // Prefix of 3 tokens, then the 4 remaining ones in one forward
+        model.forward(&tokens.narrow(1, 0, 3).unwrap(), 0).unwrap();
+        let mut token_by_token = model.clone();
+        let logits = model.forward(&tokens.narrow(1, 3, 4).unwrap(), 3).unwrap();
+        assert!(max_difference(&logits, &expected) < 1e-4);
+
+        let mut logits = None;
+        for index in 3..7 {
+            logits = Some(token_by_token.forward(&tokens.narrow(1, index, 1).unwrap(), index).unwrap());
+        }
+        assert!(max_difference(&logits.unwrap(), &expected) < 1e-4);
+    }
+
+    #[test]
+    fn mask_covers_the_cached_positions() {
+        let mask = causal_mask(2, 3, &Device::Cpu).unwrap().to_vec2::<u8>().unwrap();
+        assert_eq!(mask, vec![vec![0, 0, 0, 0, 1], vec![0, 0, 0, 0, 0]]);
+
+        let mask = causal_mask(3, 0, &Device::Cpu).unwrap().to_vec2::<u8>().unwrap();
+        assert_eq!(mask, vec![vec![0, 1, 1], vec![0, 0, 1], vec![0, 0, 0]]);
+    }
+}
@@ -1,17 +1,30 @@
-use anyhow::{Error as E, Result};
+use std::sync::Arc;
+use anyhow::Result;
 use candle::Device;
-use candle_transformers::generation::LogitsProcessor;
 use tokenizers::Tokenizer;
 use tokio::sync::mpsc::UnboundedSender;
-use crate::llm::token_output_stream::TokenOutputStream;
-use crate::args_init::args::Args;
+use crate::args_init::args::ModelFamily;
+use crate::llm::engine::LlmEngine;
+use crate::llm::classification::Classification;
+use crate::llm::generation_summary::GenerationSummary;
+use crate::llm::grammar::token_constraint::VocabularyCache;
+use crate::llm::prefix_cache::{PrefixCache, PrefixCacheStats};
+use crate::llm::prompt_template::{encode_prompt, Exchange, PromptTemplate};
+use crate::llm::sampler::Sampler;
+use crate::llm::sampling_params::SamplingParams;
 
-// todo: to be put under feature to spot right LLM
-#[cfg(feature = "mistral")]
-use crate::llm::mistral_llm::mistral_initialization::Model;
+use crate::llm::mistral_llm::mistral_initialization;
+use crate::llm::mistral_llm::mistral_management::MistralTextGeneration;
+use crate::llm::phi_v2_llm::phi_v2_initialization;
+use crate::llm::phi_v2_llm::phi_v2_management::PhiV2TextGeneration;
 
-#[cfg(feature = "phi-v2")]
-use crate::llm::phi_v2_llm::phi_v2_initialization::Model;
+
+/// Models generated by candle tensor-tools
+#[derive(Debug, Clone)]
+pub enum Model {
+    Mistral(mistral_initialization::Model),
+    PhiV2(phi_v2_initialization::Model),
+}
 
 
 #[derive( Debug,Clone)]
@@ -19,43 +32,143 @@ pub struct LlmPackage {
     pub model:Model,
     pub device:Device,
     pub tokenizer:Tokenizer,
-    pub seed:u64,
-    pub temperature:f64,
-    pub top_p:f64,
-    pub repeat_penalty:f32,
-    pub repeat_last_n:usize,
-    pub sample_len:usize,
+    /// Default sampling parameters, from the command line
+    pub sampling:SamplingParams,
+    /// Maximum number of tokens, prompt and generated ones, the model can attend to
+    pub context_length:usize,
+    /// End of sequence token from the gguf metadata, looked up in the tokenizer when absent
+    pub eos_token:Option<u32>,
+    /// Model states after the context part of the prompt, shared by all clones
+    pub prefix_cache:Arc<PrefixCache<Model>>,
+    /// Token texts for constrained decoding, shared by all clones
+    pub vocabulary:Arc<VocabularyCache>,
 }
 
 
-pub struct TextGeneration {
-    pub model: Model,
-    pub device: Device,
-    #[cfg(not(feature = "phi-v2"))]
-    pub tokenizer: TokenOutputStream,
-    #[cfg(feature = "phi-v2")]
-    pub tokenizer: Tokenizer,
-    pub logits_processor: LogitsProcessor,
-    pub repeat_penalty: f32,
-    pub repeat_last_n: usize,
+pub fn generate( llm_package:LlmPackage,prompt:&str,history:&[Exchange],sampling:&SamplingParams,tx:UnboundedSender<String>,context:&str) -> Result<GenerationSummary> {
+    match llm_package.model {
+        Model::Mistral(model) => {
+            let mut pipeline = MistralTextGeneration::new(
+                model,
+                llm_package.tokenizer,
+                llm_package.eos_token,
+                Sampler::new(sampling),
+                sampling.repeat_penalty,
+                sampling.repeat_last_n,
+                &llm_package.device,
+            );
+            pipeline.run(prompt, history, sampling,tx,context,&llm_package.prefix_cache,&llm_package.vocabulary)
+        }
+        Model::PhiV2(model) => {
+            let mut pipeline = PhiV2TextGeneration::new(
+                model,
+                llm_package.tokenizer,
+                llm_package.eos_token,
+                Sampler::new(sampling),
+                sampling.repeat_penalty,
+                sampling.repeat_last_n,
+                &llm_package.device,
+            );
+            pipeline.run(prompt, history, sampling,tx,context,&llm_package.prefix_cache,&llm_package.vocabulary)
+        }
+    }
 }
 
-pub trait LLM {
-    fn initialize(&self,args_init: Args) ->  Result<LlmPackage> ;
+pub fn classify( llm_package:LlmPackage,text:&str,labels:&[String],context:&str) -> Result<Classification> {
+    let sampling = &llm_package.sampling;
+    match llm_package.model {
+        Model::Mistral(model) => {
+            let mut pipeline = MistralTextGeneration::new(
+                model,
+                llm_package.tokenizer,
+                llm_package.eos_token,
+                Sampler::new(sampling),
+                sampling.repeat_penalty,
+                sampling.repeat_last_n,
+                &llm_package.device,
+            );
+            pipeline.classify(text, labels, context, &llm_package.prefix_cache)
+        }
+        Model::PhiV2(model) => {
+            let mut pipeline = PhiV2TextGeneration::new(
+                model,
+                llm_package.tokenizer,
+                llm_package.eos_token,
+                Sampler::new(sampling),
+                sampling.repeat_penalty,
+                sampling.repeat_last_n,
+                &llm_package.device,
+            );
+            pipeline.classify(text, labels, context, &llm_package.prefix_cache)
+        }
+    }
 }
 
 
-pub fn generate( llm_package:LlmPackage,prompt:&str,tx:UnboundedSender<String>,context:&str) -> Result<()> {
-    let mut pipeline = TextGeneration::new(
-        llm_package.model,
-        llm_package.tokenizer,
-        llm_package.seed,
-        Some(llm_package.temperature),
-        Some(llm_package.top_p),
-        llm_package.repeat_penalty,
-        llm_package.repeat_last_n,
-        &llm_package.device,
-    );
-    pipeline.run(prompt, llm_package.sample_len,tx,context)?;
-    Ok(())
-}
\ No newline at end of file
+impl LlmEngine for LlmPackage {
+    fn model_family(&self) -> ModelFamily {
+        match self.model {
+            Model::Mistral(_) => ModelFamily::Mistral,
+            Model::PhiV2(_) => ModelFamily::PhiV2,
+        }
+    }
+
+    fn default_sampling(&self) -> &SamplingParams {
+        &self.sampling
+    }
+
+    fn generate(&self, prompt: &str, history: &[Exchange], sampling: &SamplingParams, tx: UnboundedSender<String>, context: &str) -> Result<GenerationSummary> {
+        // Each generation works on its own copy of the model ( and of its kv cache )
+        generate(self.clone(), prompt, history, sampling, tx, context)
+    }
+
+    fn classify(&self, text: &str, labels: &[String], context: &str) -> Result<Classification> {
+        classify(self.clone(), text, labels, context)
+    }
+
+    fn prompt_template(&self) -> PromptTemplate {
+        PromptTemplate::for_model(self.model_family(), "")
+    }
+
+    fn context_length(&self) -> usize {
+        self.context_length
+    }
+
+    fn count_tokens(&self, text: &str) -> Result<usize> {
+        Ok(encode_prompt(&self.tokenizer, text)?.len())
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.tokenizer.get_vocab_size(true)
+    }
+
+    fn warm_prefix_cache(&self, context: &str) -> Result<()> {
+        let sampling = &self.sampling;
+        match self.model.clone() {
+            Model::Mistral(model) => MistralTextGeneration::new(
+                model,
+                self.tokenizer.clone(),
+                self.eos_token,
+                Sampler::new(sampling),
+                sampling.repeat_penalty,
+                sampling.repeat_last_n,
+                &self.device,
+            )
+            .warm_prefix(context, &self.prefix_cache),
+            Model::PhiV2(model) => PhiV2TextGeneration::new(
+                model,
+                self.tokenizer.clone(),
+                self.eos_token,
+                Sampler::new(sampling),
+                sampling.repeat_penalty,
+                sampling.repeat_last_n,
+                &self.device,
+            )
+            .warm_prefix(context, &self.prefix_cache),
+        }
+    }
+
+    fn prefix_cache_stats(&self) -> PrefixCacheStats {
+        self.prefix_cache.stats()
+    }
+}
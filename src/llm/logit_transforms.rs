@@ -0,0 +1,134 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Result;
+use candle::{DType, Tensor};
+
+use crate::llm::sampling_params::SamplingParams;
+
+/// A bias at or below this value bans the token
+const BAN_BIAS: f32 = -100.;
+
+/// Frequency and presence penalties, and logit bias, applied to the logits before sampling
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogitTransforms {
+    /// Subtracted once per occurrence of the token in the generated text
+    frequency_penalty: f32,
+    /// Subtracted once when the token appears in the generated text
+    presence_penalty: f32,
+    /// Added to the logit of the token
+    logit_bias: BTreeMap<u32, f32>,
+}
+
+impl LogitTransforms {
+    pub fn new(sampling: &SamplingParams) -> Self {
+        Self {
+            frequency_penalty: sampling.frequency_penalty,
+            presence_penalty: sampling.presence_penalty,
+            logit_bias: sampling.logit_bias.clone(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frequency_penalty == 0. && self.presence_penalty == 0. && self.logit_bias.is_empty()
+    }
+
+    /// `generated` are the tokens generated so far, the prompt is not penalized
+    pub fn apply(&self, logits: &Tensor, generated: &[u32]) -> Result<Tensor> {
+        if self.is_empty() {
+            return Ok(logits.clone());
+        }
+        let mut values = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        self.apply_to(&mut values, generated);
+        let len = values.len();
+        Ok(Tensor::from_vec(values, len, logits.device())?)
+    }
+
+    fn apply_to(&self, logits: &mut [f32], generated: &[u32]) {
+        if self.frequency_penalty != 0. || self.presence_penalty != 0. {
+            let mut counts = HashMap::new();
+            for token in generated {
+                *counts.entry(*token).or_insert(0usize) += 1;
+            }
+            for (token, count) in counts {
+                if let Some(logit) = logits.get_mut(token as usize) {
+                    *logit -= count as f32 * self.frequency_penalty + self.presence_penalty;
+                }
+            }
+        }
+
+        // The tokens are checked against the vocabulary when the request is accepted
+        for (token, bias) in &self.logit_bias {
+            debug_assert!((*token as usize) < logits.len(), "logit_bias: token {} is not in the vocabulary", token);
+            match logits.get_mut(*token as usize) {
+                Some(logit) if *bias <= BAN_BIAS => *logit = f32::NEG_INFINITY,
+                Some(logit) => *logit += bias,
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transforms(frequency_penalty: f32, presence_penalty: f32, logit_bias: &[(u32, f32)]) -> LogitTransforms {
+        LogitTransforms {
+            frequency_penalty,
+            presence_penalty,
+            logit_bias: logit_bias.iter().copied().collect(),
+        }
+    }
+
+    fn applied(transforms: &LogitTransforms, generated: &[u32]) -> Vec<f32> {
+        let mut logits = vec![1.; 5];
+        transforms.apply_to(&mut logits, generated);
+        logits
+    }
+
+    #[test]
+    fn frequency_penalty_counts_every_occurrence() {
+        let logits = applied(&transforms(0.5, 0., &[]), &[1, 3, 3, 3]);
+        assert_eq!(logits, vec![1., 0.5, 1., -0.5, 1.]);
+    }
+
+    #[test]
+    fn presence_penalty_counts_once() {
+        let logits = applied(&transforms(0., 0.5, &[]), &[1, 3, 3, 3]);
+        assert_eq!(logits, vec![1., 0.5, 1., 0.5, 1.]);
+    }
+
+    #[test]
+    fn both_penalties_add_up() {
+        let logits = applied(&transforms(0.25, 1., &[]), &[2, 2]);
+        assert_eq!(logits, vec![1., 1., -0.5, 1., 1.]);
+    }
+
+    #[test]
+    fn bias_is_added() {
+        let logits = applied(&transforms(0., 0., &[(0, 2.), (4, -0.5)]), &[]);
+        assert_eq!(logits, vec![3., 1., 1., 1., 0.5]);
+    }
+
+    #[test]
+    fn bias_of_minus_100_bans_the_token() {
+        let logits = applied(&transforms(0., 0., &[(1, -100.), (2, -99.)]), &[]);
+        assert_eq!(logits[1], f32::NEG_INFINITY);
+        assert_eq!(logits[2], -98.);
+    }
+
+    #[test]
+    fn bias_applies_after_the_penalties() {
+        let logits = applied(&transforms(1., 0., &[(3, -100.), (1, 1.)]), &[1, 3]);
+        assert_eq!(logits, vec![1., 1., 1., f32::NEG_INFINITY, 1.]);
+    }
+
+    #[test]
+    fn no_transform_keeps_the_logits() {
+        let transforms = transforms(0., 0., &[]);
+        assert!(transforms.is_empty());
+        let logits = Tensor::new(&[0.5f32, -1., 2.], &candle::Device::Cpu).unwrap();
+        let applied = transforms.apply(&logits, &[0, 0, 1]).unwrap();
+        assert_eq!(applied.to_vec1::<f32>().unwrap(), vec![0.5, -1., 2.]);
+    }
+}
@@ -1,18 +1,17 @@
 #![feature(const_trait_impl)]
 
-use std::path::PathBuf;
-use anyhow::{Error as E, Result};
+use anyhow::Result;
 
 use candle_transformers::models::mistral::{Config};
 use candle_transformers::models::quantized_mistral::Model as QMistral;
 
 use candle::{Device};
-use hf_hub::{api::sync::Api, Repo, RepoType};
-use hf_hub::api::sync::ApiRepo;
-use tokenizers::Tokenizer;
-use crate::args_init::args::Args;
+use crate::args_init::args::{Args, ModelFamily};
 use crate::llm::device::device;
-use crate::llm::llm::{LLM, LlmPackage};
+use crate::llm::gguf_metadata::GgufMetadata;
+use crate::llm::model_files::{load_tokenizer, retrieve_model_files};
+use crate::llm::sampling_params::SamplingParams;
+use crate::llm::llm::{self, LlmPackage};
 
 #[derive(Debug, Clone)]
 pub enum Model {
@@ -20,114 +19,111 @@ pub enum Model {
 }
 
 
-pub struct LlmModel;
+pub fn initialize(mut args_init: Args) -> Result<LlmPackage> {
 
-impl LLM for LlmModel {
-    fn initialize(&self, args_init: Args) -> Result<LlmPackage> {
+    /**********************************************************************/
+    // Tracing Initialization
+    /**********************************************************************/
 
-        /**********************************************************************/
-        // Tracing Initialization
-        /**********************************************************************/
+    println!(
+        "avx: {}, neon: {}, simd128: {}, f16c: {}",
+        candle::utils::with_avx(),
+        candle::utils::with_neon(),
+        candle::utils::with_simd128(),
+        candle::utils::with_f16c()
+    );
+    println!(
+        "temp: {:.2} repeat-penalty: {:.2} repeat-last-n: {}",
+        args_init.temperature,
+        args_init.repeat_penalty,
+        args_init.repeat_last_n
+    );
 
-        println!(
-            "avx: {}, neon: {}, simd128: {}, f16c: {}",
-            candle::utils::with_avx(),
-            candle::utils::with_neon(),
-            candle::utils::with_simd128(),
-            candle::utils::with_f16c()
-        );
-        println!(
-            "temp: {:.2} repeat-penalty: {:.2} repeat-last-n: {}",
-            args_init.temperature,
-            args_init.repeat_penalty,
-            args_init.repeat_last_n
-        );
+    // Repos and files not given on the command line
+    args_init.apply_model_family_defaults(ModelFamily::Mistral);
 
-        /**********************************************************************/
-        // End Initialization
-        /**********************************************************************/
+    // Default sampling parameters, requests can override them
+    let sampling = SamplingParams::from_args(&args_init);
 
-        /**********************************************************************/
-        // Retrieve Model Files and Tokenizer
-        /**********************************************************************/
-        let start = std::time::Instant::now();
+    /**********************************************************************/
+    // End Initialization
+    /**********************************************************************/
 
-        let api = Api::new()?;
+    /**********************************************************************/
+    // Retrieve Model Files and Tokenizer
+    /**********************************************************************/
+    let start = std::time::Instant::now();
 
-        let repo_model = api.repo(Repo::with_revision(
-            args_init.model_id,
-            RepoType::Model,
-            args_init.revision.clone(),
-        ));
+    // Local files bypass the hub entirely
+    let model_files = retrieve_model_files(&args_init)?;
 
-        let model_filenames = get_filenames_model(&repo_model, args_init.weight_files, args_init.model_file)?;
+    println!("retrieved the files in {:?}", start.elapsed());
 
-        let repo_tokenizer = api.repo(Repo::with_revision(
-            args_init.tokenizer_id,
-            RepoType::Model,
-            args_init.revision,
-        ));
+    /**********************************************************************/
+    // End Retrieval Model Files and Tokenizer Files
+    /**********************************************************************/
 
-        let tokenizer_filename = repo_tokenizer.get(args_init.tokenizer_file.as_str())?;
+    /**********************************************************************/
+    // Construction LLM Package
+    /**********************************************************************/
 
-        println!("retrieved the files in {:?}", start.elapsed());
+    let tokenizer = load_tokenizer(&model_files.tokenizer)?;
 
-        /**********************************************************************/
-        // End Retrieval Model Files and Tokenizer Files
-        /**********************************************************************/
+    let start = std::time::Instant::now();
 
-        /**********************************************************************/
-        // Construction LLM Package
-        /**********************************************************************/
+    // Architecture parameters from the gguf header, mistral 7b v0.1 ones when absent.
+    // Tensor-tools files usually carry none, llama.cpp files run with the llama family
+    let metadata = GgufMetadata::read(&model_files.model)?;
+    metadata.log();
+    metadata.check_architecture(&["llama", "mistral"])?;
+    metadata.check_tensor_tools_layout("mistral ( llama.cpp files run with --model-family llama )")?;
+    let config = mistral_config(&metadata, args_init.use_flash_attn);
 
-        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
 
-        let start = std::time::Instant::now();
 
-        let config = Config::config_7b_v0_1(args_init.use_flash_attn);
+    // We will only process quantized models
+    let (model, device_model) = {
+        // putting CUDA by default. to be optimized
+        let device_model = device(false)?;
+        let filename = &model_files.model;
+        let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(filename,&device_model)?;
+        let model = QMistral::new(&config, vb)?;
 
+        (Model::Quantized(model), device_model)
+    };
 
 
-        // We will only process quantized models
-        let (model, device_model) = {
-            // putting CUDA by default. to be optimized
-            let device_model = device(false)?;
-            let filename = &model_filenames[0];
-            let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(filename,&device_model)?;
-            let model = QMistral::new(&config, vb)?;
-
-            (Model::Quantized(model), device_model)
-        };
 
+    println!("loaded the model in {:?}", start.elapsed());
 
+    Ok(LlmPackage {
+        model: llm::Model::Mistral(model),
+        device:device_model,
+        tokenizer,
+        sampling,
+        context_length: config.max_position_embeddings,
+        eos_token: metadata.eos_token_id,
+        prefix_cache: Default::default(),
+        vocabulary: Default::default(),
+    })
+}
 
-        println!("loaded the model in {:?}", start.elapsed());
 
-        Ok(LlmPackage {
-            model,
-            device:device_model,
-            tokenizer,
-            seed: args_init.seed,
-            temperature: args_init.temperature,
-            top_p: args_init.top_p,
-            repeat_penalty: args_init.repeat_penalty,
-            repeat_last_n: args_init.repeat_last_n,
-            sample_len: args_init.sample_len,
-        })
+fn mistral_config(metadata:&GgufMetadata, use_flash_attn:bool) -> Config {
+    let default = Config::config_7b_v0_1(use_flash_attn);
+    Config {
+        vocab_size: metadata.vocab_size.or(metadata.token_count).unwrap_or(default.vocab_size),
+        hidden_size: metadata.embedding_length.unwrap_or(default.hidden_size),
+        intermediate_size: metadata.feed_forward_length.unwrap_or(default.intermediate_size),
+        num_hidden_layers: metadata.block_count.unwrap_or(default.num_hidden_layers),
+        num_attention_heads: metadata.head_count.unwrap_or(default.num_attention_heads),
+        head_dim: metadata.rope_dimension_count.or(default.head_dim),
+        // The kv head count is omitted when there is no grouped query attention
+        num_key_value_heads: metadata.head_count_kv.or(metadata.head_count).unwrap_or(default.num_key_value_heads),
+        max_position_embeddings: metadata.context_length.unwrap_or(default.max_position_embeddings),
+        rms_norm_eps: metadata.rms_norm_epsilon.unwrap_or(default.rms_norm_eps),
+        rope_theta: metadata.rope_freq_base.unwrap_or(default.rope_theta),
+        sliding_window: metadata.sliding_window.or(default.sliding_window),
+        ..default
     }
-
-
 }
-
-fn get_filenames_model(repo:&ApiRepo, weight_files:Option<String>,model_file:Option<String>) -> Result<Vec<PathBuf>> {
-    Ok(match weight_files {
-        Some(files) => files
-            .split(',')
-            .map(std::path::PathBuf::from)
-            .collect::<Vec<_>>(),
-        None => {
-                vec![repo.get(model_file.unwrap().as_str())?]
-        }
-    })
-}
-
@@ -1,4 +1,2 @@
-#[cfg(feature = "mistral")]
 pub mod mistral_initialization;
-#[cfg(feature = "mistral")]
-pub mod mistral_management;
\ No newline at end of file
+pub mod mistral_management;
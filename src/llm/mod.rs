@@ -1,18 +1,26 @@
 pub mod device;
 pub mod token_output_stream;
+pub mod stop_sequences;
+pub mod sql_output;
+pub mod sampling_params;
+pub mod sampler;
+pub mod logit_transforms;
+pub mod classification;
+pub mod grammar;
+pub mod generation_summary;
+pub mod engine;
+pub mod prefix_cache;
+pub mod prompt_template;
+pub mod chat_template;
+pub mod model_files;
+pub mod gguf_metadata;
 
 
-
-#[cfg(not(feature = "llama"))]
 pub mod llm;
 
-#[cfg(feature = "mistral")]
 pub mod mistral_llm;
 
-#[cfg(feature = "phi-v2")]
 pub mod phi_v2_llm;
 
-#[cfg(feature = "llama")]
 pub mod llama_llm;
-#[cfg(feature = "llama")]
-pub mod quantized_llm;
\ No newline at end of file
+pub mod quantized_llm;
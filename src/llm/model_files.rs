@@ -0,0 +1,110 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use candle::quantized::gguf_file;
+use hf_hub::{api::sync::Api, Repo, RepoType};
+use tokenizers::Tokenizer;
+
+use crate::args_init::args::Args;
+
+/// Model weights and tokenizer of the model to load
+pub struct ModelFiles {
+    pub model: PathBuf,
+    pub tokenizer: PathBuf,
+    /// `tokenizer_config.json` next to the tokenizer, when there is one
+    pub tokenizer_config: Option<PathBuf>,
+}
+
+const TOKENIZER_CONFIG_FILE: &str = "tokenizer_config.json";
+
+/// Local files given on the command line are used as is, without any access to the
+/// Hugging Face hub. The others are downloaded from the hub, or taken from its cache.
+pub fn retrieve_model_files(args_init: &Args) -> Result<ModelFiles> {
+    let model = match local_model_path(args_init) {
+        Some(path) => path,
+        None => hub_file(
+            args_init.model_id.as_deref(),
+            args_init.model_file.as_deref(),
+            args_init.revision.as_str(),
+            "--local-model-file",
+        )?,
+    };
+    check_gguf(&model)?;
+
+    let tokenizer = match &args_init.local_tokenizer_file {
+        Some(path) => {
+            let path = PathBuf::from(path);
+            if !path.is_file() {
+                bail!("tokenizer file `{}` not found", path.display());
+            }
+            path
+        }
+        None => hub_file(
+            args_init.tokenizer_id.as_deref(),
+            Some(args_init.tokenizer_file.as_str()),
+            args_init.revision.as_str(),
+            "--local-tokenizer-file",
+        )?,
+    };
+
+    // Optional, it carries the chat template of the model
+    let tokenizer_config = match &args_init.local_tokenizer_file {
+        Some(_) => tokenizer
+            .parent()
+            .map(|dir| dir.join(TOKENIZER_CONFIG_FILE))
+            .filter(|path| path.is_file()),
+        None => hub_file(
+            args_init.tokenizer_id.as_deref(),
+            Some(TOKENIZER_CONFIG_FILE),
+            args_init.revision.as_str(),
+            "--local-tokenizer-file",
+        )
+        .ok(),
+    };
+
+    Ok(ModelFiles { model, tokenizer, tokenizer_config })
+}
+
+/// `--local-model-file`, or the first of `--weight-files`
+pub fn local_model_path(args_init: &Args) -> Option<PathBuf> {
+    match (&args_init.local_model_file, &args_init.weight_files) {
+        (Some(file), _) => Some(PathBuf::from(file)),
+        (None, Some(files)) => files.split(',').next().map(PathBuf::from),
+        (None, None) => None,
+    }
+}
+
+/// Download `file` from the `repo_id` hub repo, or take it from the hub cache
+pub fn hub_file(repo_id: Option<&str>, file: Option<&str>, revision: &str, local_option: &str) -> Result<PathBuf> {
+    let (repo_id, file) = match (repo_id, file) {
+        (Some(repo_id), Some(file)) => (repo_id, file),
+        _ => bail!("no hub repo and file to retrieve, use {} for a local file", local_option),
+    };
+    let api = Api::new()?;
+    let repo = api.repo(Repo::with_revision(repo_id.to_string(), RepoType::Model, revision.to_string()));
+    repo.get(file).with_context(|| {
+        format!(
+            "Unable to retrieve `{}` from the `{}` hub repo, use {} to start without hub access",
+            file, repo_id, local_option
+        )
+    })
+}
+
+/// Read the gguf header of `path`, so that a missing or invalid file is reported before loading
+pub fn read_gguf(path: &Path) -> Result<(std::fs::File, gguf_file::Content)> {
+    if !path.is_file() {
+        bail!("model file `{}` not found", path.display());
+    }
+    let mut file = std::fs::File::open(path).with_context(|| format!("Unable to open `{}`", path.display()))?;
+    let content = gguf_file::Content::read(&mut file)
+        .map_err(|e| anyhow!("`{}` is not a valid gguf file: {}", path.display(), e))?;
+    Ok((file, content))
+}
+
+pub fn check_gguf(path: &Path) -> Result<()> {
+    read_gguf(path).map(|_| ())
+}
+
+pub fn load_tokenizer(path: &Path) -> Result<Tokenizer> {
+    Tokenizer::from_file(path).map_err(|e| anyhow!("Unable to load the tokenizer `{}`: {}", path.display(), e))
+}
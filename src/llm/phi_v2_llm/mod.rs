@@ -1,4 +1,5 @@
-
 pub mod phi_v2_initialization;
 
-pub mod phi_v2_management;
\ No newline at end of file
+pub mod phi_v2_management;
+
+pub mod quantized_mixformer;
@@ -1,19 +1,18 @@
 #![feature(const_trait_impl)]
 
-use std::path::PathBuf;
-use anyhow::{Error as E, Result};
+use anyhow::Result;
 
 
-use candle_transformers::models::mixformer::Config;
-use candle_transformers::models::quantized_mixformer::MixFormerSequentialForCausalLM as QMixFormer;
+use candle_nn::Activation;
 
 use candle::{Device};
-use hf_hub::{api::sync::Api, Repo, RepoType};
-use hf_hub::api::sync::ApiRepo;
-use tokenizers::Tokenizer;
-use crate::args_init::args::Args;
+use crate::args_init::args::{Args, ModelFamily};
 use crate::llm::device::device;
-use crate::llm::llm::{LLM, LlmPackage};
+use crate::llm::gguf_metadata::GgufMetadata;
+use crate::llm::model_files::{load_tokenizer, retrieve_model_files};
+use crate::llm::sampling_params::SamplingParams;
+use crate::llm::llm::{self, LlmPackage};
+use crate::llm::phi_v2_llm::quantized_mixformer::{Config, MixFormerSequentialForCausalLM as QMixFormer, MAX_SEQ_LEN};
 
 
 #[derive(Debug, Clone)]
@@ -21,118 +20,116 @@ pub enum Model {
     Quantized(QMixFormer),
 }
 
+/// Positions phi-2 was trained on
+const PHI_V2_CONTEXT_LENGTH: usize = 2048;
 
 
-pub struct LlmModel;
 
-impl LLM for LlmModel {
-    fn initialize(&self, args_init: Args) -> Result<LlmPackage> {
+pub fn initialize(mut args_init: Args) -> Result<LlmPackage> {
 
-        /**********************************************************************/
-        // Tracing Initialization
-        /**********************************************************************/
+    /**********************************************************************/
+    // Tracing Initialization
+    /**********************************************************************/
 
-        println!(
-            "avx: {}, neon: {}, simd128: {}, f16c: {}",
-            candle::utils::with_avx(),
-            candle::utils::with_neon(),
-            candle::utils::with_simd128(),
-            candle::utils::with_f16c()
-        );
-        println!(
-            "temp: {:.2} repeat-penalty: {:.2} repeat-last-n: {}",
-            args_init.temperature,
-            args_init.repeat_penalty,
-            args_init.repeat_last_n
-        );
+    println!(
+        "avx: {}, neon: {}, simd128: {}, f16c: {}",
+        candle::utils::with_avx(),
+        candle::utils::with_neon(),
+        candle::utils::with_simd128(),
+        candle::utils::with_f16c()
+    );
+    println!(
+        "temp: {:.2} repeat-penalty: {:.2} repeat-last-n: {}",
+        args_init.temperature,
+        args_init.repeat_penalty,
+        args_init.repeat_last_n
+    );
 
-        /**********************************************************************/
-        // End Initialization
-        /**********************************************************************/
+    // Repos and files not given on the command line
+    args_init.apply_model_family_defaults(ModelFamily::PhiV2);
 
-        /**********************************************************************/
-        // Retrieve Model Files and Tokenizer
-        /**********************************************************************/
-        let start = std::time::Instant::now();
+    // Default sampling parameters, requests can override them
+    let sampling = SamplingParams::from_args(&args_init);
 
-        let api = Api::new()?;
+    /**********************************************************************/
+    // End Initialization
+    /**********************************************************************/
 
-        let repo_model = api.repo(Repo::with_revision(
-            args_init.model_id,
-            RepoType::Model,
-            args_init.revision.clone(),
-        ));
+    /**********************************************************************/
+    // Retrieve Model Files and Tokenizer
+    /**********************************************************************/
+    let start = std::time::Instant::now();
 
-        let model_filenames = get_filenames_model(&repo_model, args_init.weight_files, args_init.model_file)?;
+    // Local files bypass the hub entirely
+    let model_files = retrieve_model_files(&args_init)?;
 
-        let repo_tokenizer = api.repo(Repo::with_revision(
-            args_init.tokenizer_id,
-            RepoType::Model,
-            args_init.revision,
-        ));
+    println!("retrieved the files in {:?}", start.elapsed());
 
-        let tokenizer_filename = repo_tokenizer.get(args_init.tokenizer_file.as_str())?;
+    /**********************************************************************/
+    // End Retrieval Model Files and Tokenizer Files
+    /**********************************************************************/
 
-        println!("retrieved the files in {:?}", start.elapsed());
+    /**********************************************************************/
+    // Construction LLM Package
+    /**********************************************************************/
 
-        /**********************************************************************/
-        // End Retrieval Model Files and Tokenizer Files
-        /**********************************************************************/
+    let tokenizer = load_tokenizer(&model_files.tokenizer)?;
 
-        /**********************************************************************/
-        // Construction LLM Package
-        /**********************************************************************/
+    let start = std::time::Instant::now();
 
-        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
 
-        let start = std::time::Instant::now();
+    // Architecture parameters from the gguf header, phi-2 ones when absent.
+    // Tensor-tools files usually carry none, llama.cpp phi-2 files are not supported
+    let metadata = GgufMetadata::read(&model_files.model)?;
+    metadata.log();
+    metadata.check_architecture(&["phi2", "phi-msft", "mixformer"])?;
+    metadata.check_tensor_tools_layout("phi-v2")?;
+    let config = phi_v2_config(&metadata);
 
+    // We will only process quantized models
+    let (model, device_model) = {
+        let device_model = device(false)?;
+        let filename = &model_files.model;
+        let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(filename,&device_model)?;
+        let model = QMixFormer::new_v2(&config, vb)?;
 
-        let config = Config::v2();
+        (Model::Quantized(model), device_model)
+    };
 
-        // We will only process quantized models
-        let (model, device_model) = {
-            let device_model = device(false)?;
-            let filename = &model_filenames[0];
-            let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(filename,&device_model)?;
-            let model = QMixFormer::new_v2(&config, vb)?;
 
-            (Model::Quantized(model), device_model)
-        };
+    /**********************************************************************/
+    // End Construction LLM Package
+    /**********************************************************************/
 
 
-        /**********************************************************************/
-        // End Construction LLM Package
-        /**********************************************************************/
 
+    println!("loaded the model in {:?}", start.elapsed());
 
+    Ok(LlmPackage {
+        model: llm::Model::PhiV2(model),
+        device:device_model,
+        tokenizer,
+        sampling,
+        context_length: metadata.context_length.unwrap_or(PHI_V2_CONTEXT_LENGTH).min(MAX_SEQ_LEN),
+        eos_token: metadata.eos_token_id,
+        prefix_cache: Default::default(),
+        vocabulary: Default::default(),
+    })
+}
 
-        println!("loaded the model in {:?}", start.elapsed());
 
-        Ok(LlmPackage {
-            model,
-            device:device_model,
-            tokenizer,
-            seed: args_init.seed,
-            temperature: args_init.temperature,
-            top_p: args_init.top_p,
-            repeat_penalty: args_init.repeat_penalty,
-            repeat_last_n: args_init.repeat_last_n,
-            sample_len: args_init.sample_len,
-        })
+/// Values absent from the metadata are the phi-2 ones
+fn phi_v2_config(metadata:&GgufMetadata) -> Config {
+    let n_embd = metadata.embedding_length.unwrap_or(2560);
+    let n_head = metadata.head_count.unwrap_or(32);
+    Config {
+        vocab_size: metadata.vocab_size.unwrap_or(51200),
+        n_embd,
+        n_layer: metadata.block_count.unwrap_or(32),
+        n_inner: metadata.feed_forward_length,
+        n_head,
+        rotary_dim: metadata.rope_dimension_count.unwrap_or(usize::min(32, n_embd / n_head)),
+        activation_function: Activation::Gelu,
+        layer_norm_epsilon: metadata.layer_norm_epsilon.unwrap_or(1e-5),
     }
-
-
 }
-
-fn get_filenames_model(repo:&ApiRepo, weight_files:Option<String>, model_file:Option<String>) -> Result<Vec<PathBuf>> {
-    Ok(match weight_files {
-        Some(files) => files
-            .split(',')
-            .map(std::path::PathBuf::from)
-            .collect::<Vec<_>>(),
-        None => {
-                vec![repo.get(model_file.unwrap().as_str())?]
-            }
-    })
-}
\ No newline at end of file
@@ -1,54 +1,76 @@
 use anyhow::{Error as E, Result};
 use candle::{DType, Device, Tensor};
-use candle_transformers::generation::LogitsProcessor;
 
 use tokenizers::Tokenizer;
+use crate::llm::token_output_stream::TokenOutputStream;
 use tokio::sync::mpsc::{UnboundedSender};
-use crate::llm::llm::TextGeneration;
 
 
+use crate::llm::generation_summary::{FinishReason, GenerationSummary};
+use crate::llm::llm;
 use crate::llm::phi_v2_llm::phi_v2_initialization::{ Model};
+use crate::llm::classification::{label_tokens, rank, token_logprob, Classification};
+use crate::llm::grammar::token_constraint::{TokenConstraint, VocabularyCache};
+use crate::llm::logit_transforms::LogitTransforms;
+use crate::llm::sampler::Sampler;
+use crate::llm::prefix_cache::PrefixCache;
+use crate::llm::prompt_template::{encode_prompt, Exchange, PromptTemplate};
+use crate::llm::sampling_params::SamplingParams;
+
+const TEMPLATE: PromptTemplate = PromptTemplate::Phi2;
+
+
+pub struct PhiV2TextGeneration {
+    pub model: Model,
+    pub device: Device,
+    pub tokenizer: TokenOutputStream,
+    pub eos_token: Option<u32>,
+    pub sampler: Sampler,
+    pub repeat_penalty: f32,
+    pub repeat_last_n: usize,
+}
 
 
-
-impl TextGeneration {
+impl PhiV2TextGeneration {
     #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         model: Model,
         tokenizer: Tokenizer,
-        seed: u64,
-        temp: Option<f64>,
-        top_p: Option<f64>,
+        eos_token: Option<u32>,
+        sampler: Sampler,
         repeat_penalty: f32,
         repeat_last_n: usize,
         device: &Device,
     ) -> Self {
 
-        let logits_processor = LogitsProcessor::new(seed, temp, top_p);
-
         Self {
             model,
-            tokenizer,
-            logits_processor,
+            tokenizer: TokenOutputStream::new(tokenizer),
+            eos_token,
+            sampler,
             repeat_penalty,
             repeat_last_n,
             device: device.clone(),
         }
     }
 
-    pub(crate) fn run(&mut self, prompt: &str, sample_len: usize, tx:UnboundedSender<String>,context:&str) -> Result<()> {
-
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn run(&mut self, prompt: &str, history:&[Exchange], sampling:&SamplingParams, tx:UnboundedSender<String>,context:&str,prefix_cache:&PrefixCache<llm::Model>,vocabulary:&VocabularyCache) -> Result<GenerationSummary> {
+        let start_run = std::time::Instant::now();
+        self.tokenizer.clear();
+        self.tokenizer.set_stop_sequences(sampling.stop.clone());
+        let logit_transforms = LogitTransforms::new(sampling);
 
         // Text Generation Prompt for phi-2
-        let prompt=format!("Context:{}.\nInstruct: {}.\nOutput:",context.trim(),prompt.trim());
+        let prefix=TEMPLATE.prefix(context)?;
+        let prompt=TEMPLATE.render_conversation(context,history,prompt)?;
+
 
+        let mut tokens = self.encode(prompt.as_str())?;
+        let prompt_tokens = tokens.len();
 
-        let mut tokens = self
-            .tokenizer
-            .encode(prompt, true)
-            .map_err(E::msg)?
-            .get_ids()
-            .to_vec();
+        // Prompt tokens already in the kv cache
+        let processed = self.reuse_prefix(prefix.as_str(), &tokens, prefix_cache)?;
 
 
 
@@ -56,23 +78,47 @@ impl TextGeneration {
 
 
 
-        let eos_token = match self.tokenizer.get_vocab(true).get("<|endoftext|>") {
-            Some(token) => *token,
+        let eos_token = match self.eos_token.or_else(|| self.tokenizer.get_token("<|endoftext|>")) {
+            Some(token) => token,
             None => anyhow::bail!("cannot find the endoftext token"),
         };
 
+        // Only the tokens following the grammar can be sampled
+        let mut constraint = match &sampling.grammar {
+            Some(grammar) => Some(TokenConstraint::new(grammar.clone(), vocabulary.get(self.tokenizer.tokenizer())?, vec![eos_token])),
+            None => None,
+        };
+
 
         let start_gen = std::time::Instant::now();
+        // Time of the first forward, which processes the prompt
+        let mut prompt_dt = std::time::Duration::ZERO;
+        let mut finish_reason = FinishReason::Length;
+
 
+        for index in 0..sampling.sample_len {
 
-        for index in 0..sample_len {
-            let context_size = if index > 0 { 1 } else { tokens.len() };
-            let ctxt = &tokens[tokens.len().saturating_sub(context_size)..];
-            let input = Tensor::new(ctxt, &self.device)?.unsqueeze(0)?;
-            let logits = match &mut self.model {
-                Model::Quantized(m) => m.forward(&input)?,
+            // Stop as soon as the client went away
+            if tx.is_closed() {
+                println!("\nrequest cancelled by the client after {generated_tokens} tokens");
+                return Ok(GenerationSummary::new(FinishReason::Cancelled, prompt_tokens, generated_tokens, start_gen.elapsed().saturating_sub(prompt_dt))
+                    .with_prompt_processing(prompt_tokens - processed, prompt_dt)
+                    .with_seed(sampling.seed));
+            }
+            if sampling.max_time.is_some_and(|max_time| start_run.elapsed() >= max_time) {
+                finish_reason = FinishReason::MaxTime;
+                break;
+            }
+
+            let logits = if index > 0 {
+                self.forward(&tokens[tokens.len() - 1..])?
+            } else {
+                // The prompt tokens not already in the kv cache
+                self.forward(&tokens[processed..])?
             };
-            let logits = logits.squeeze(0)?.to_dtype(DType::F32)?;
+            if index == 0 {
+                prompt_dt = start_gen.elapsed();
+            }
             let logits = if self.repeat_penalty == 1. {
                 logits
             } else {
@@ -83,35 +129,138 @@ impl TextGeneration {
                     &tokens[start_at..],
                 )?
             };
+            let logits = logit_transforms.apply(&logits, &tokens[prompt_tokens..])?;
+            let logits = match &constraint {
+                Some(constraint) => constraint.mask(&logits)?,
+                None => logits,
+            };
 
-            let next_token = self.logits_processor.sample(&logits)?;
+            let next_token = self.sampler.sample(&logits)?;
+            if let Some(constraint) = constraint.as_mut() {
+                constraint.accept(next_token)?;
+            }
 
 
             tokens.push(next_token);
             generated_tokens += 1;
 
             if next_token == eos_token {
+                finish_reason = FinishReason::Eos;
                 break;
             }
 
 
-            if let t = self.tokenizer.decode(&[next_token], true).map_err(E::msg)? {
+            if let Some(t) = self.tokenizer.next_token(next_token)? {
                 let _ = tx.send(t.to_string());
             }
+            if self.tokenizer.stop_sequence().is_some() {
+                finish_reason = FinishReason::Stop;
+                break;
+            }
 
 
 
         }
 
-            let dt = start_gen.elapsed();
+            let dt = start_gen.elapsed() - prompt_dt;
+
+            if let Some(rest) = self.tokenizer.decode_rest().map_err(E::msg)? {
+                let _ = tx.send(rest.to_string());
+            }
 
             println!(
-                "\n{generated_tokens} tokens generated ({:.2} token/s)",
+                "\n{:4} prompt tokens processed: {:.2} token/s",
+                prompt_tokens - processed,
+                (prompt_tokens - processed) as f64 / prompt_dt.as_secs_f64(),
+            );
+            println!(
+                "{generated_tokens} tokens generated ({:.2} token/s)",
                 generated_tokens as f64 / dt.as_secs_f64(),
             );
 
-            Ok(())
+            Ok(GenerationSummary::new(finish_reason, prompt_tokens, generated_tokens, dt)
+                .with_stop_sequence(self.tokenizer.stop_sequence())
+                .with_prompt_processing(prompt_tokens - processed, prompt_dt)
+                .with_seed(sampling.seed))
+
+    }
 
+    /// Score each label as the answer to `text`, every label starting from the model state
+    /// after the prompt. No token is sampled.
+    pub(crate) fn classify(&mut self, text:&str, labels:&[String], context:&str, prefix_cache:&PrefixCache<llm::Model>) -> Result<Classification> {
+        let prefix=TEMPLATE.prefix(context)?;
+        let prompt=TEMPLATE.render_conversation(context,&[],text)?;
+
+        let tokens = self.encode(prompt.as_str())?;
+
+        // Prompt tokens already in the kv cache
+        let processed = self.reuse_prefix(prefix.as_str(), &tokens, prefix_cache)?;
+        let prompt_logits = self.forward(&tokens[processed..])?;
+        let prompt_model = self.model.clone();
+
+        let mut logprobs = Vec::with_capacity(labels.len());
+        let mut token_counts = Vec::with_capacity(labels.len());
+        for label in labels {
+            let label_tokens = label_tokens(self.tokenizer.tokenizer(), prompt.as_str(), &tokens, label)?;
+            self.model = prompt_model.clone();
+            let mut logits = prompt_logits.clone();
+            let mut logprob = 0.;
+            for (index, token) in label_tokens.iter().enumerate() {
+                logprob += token_logprob(&logits, *token)?;
+                if index + 1 < label_tokens.len() {
+                    logits = self.forward(std::slice::from_ref(token))?;
+                }
+            }
+            logprobs.push(logprob);
+            token_counts.push(label_tokens.len());
+        }
+
+        Ok(rank(labels, &logprobs, &token_counts, tokens.len()))
+    }
+
+    /// Process the prompt prefix for `context` once, so that requests can start from it
+    pub(crate) fn warm_prefix(&mut self, context:&str, prefix_cache:&PrefixCache<llm::Model>) -> Result<()> {
+        let prefix=TEMPLATE.prefix(context)?;
+        if prefix_cache.contains(prefix.as_str()) {
+            return Ok(());
+        }
+        let prefix_tokens = self.encode(prefix.as_str())?;
+        self.forward(&prefix_tokens)?;
+        prefix_cache.insert(prefix.as_str(), prefix_tokens, llm::Model::PhiV2(self.model.clone()));
+        Ok(())
+    }
+
+    /// Start from the cached state of the prompt prefix when possible, otherwise process
+    /// the prefix on its own and cache the resulting state.
+    /// Returns the number of prompt tokens already processed.
+    fn reuse_prefix(&mut self, prefix:&str, tokens:&[u32], prefix_cache:&PrefixCache<llm::Model>) -> Result<usize> {
+        if let Some((llm::Model::PhiV2(model), processed)) = prefix_cache.lookup(prefix, tokens) {
+            self.model = model;
+            return Ok(processed);
+        }
+
+        let prefix_tokens = self.encode(prefix)?;
+        if prefix_tokens.is_empty() || prefix_tokens.len() >= tokens.len() || !tokens.starts_with(&prefix_tokens) {
+            return Ok(0);
+        }
+
+        self.forward(&prefix_tokens)?;
+        let processed = prefix_tokens.len();
+        prefix_cache.insert(prefix, prefix_tokens, llm::Model::PhiV2(self.model.clone()));
+        Ok(processed)
+    }
+
+    fn encode(&self, text:&str) -> Result<Vec<u32>> {
+        encode_prompt(self.tokenizer.tokenizer(), text)
+    }
+
+    /// Logits of the last token, positions follow the kv cache
+    fn forward(&mut self, tokens:&[u32]) -> Result<Tensor> {
+        let input = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
+        let logits = match &mut self.model {
+            Model::Quantized(m) => m.forward(&input)?,
+        };
+        Ok(logits.squeeze(0)?.to_dtype(DType::F32)?)
     }
 
 }
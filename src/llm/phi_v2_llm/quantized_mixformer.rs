@@ -0,0 +1,340 @@
+//! Quantized phi-2 model, adapted from `candle_transformers::models::quantized_mixformer`.
+//!
+//! The candle model builds its causal mask as if the kv cache were empty, several tokens can
+//! only be processed at once from position 0. Here the mask covers the cached positions too,
+//! so that the rest of a prompt is processed in a single forward after a cached prefix.
+//! Only the phi-2 layout of candle tensor-tools files is loaded, the tracing spans are left out.
+
+use candle::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_nn::Activation;
+use candle_transformers::quantized_nn::{layer_norm, linear, Linear};
+use candle_transformers::quantized_var_builder::VarBuilder;
+
+pub const MAX_SEQ_LEN: usize = 4096;
+
+/// Architecture parameters, the candle mixformer config fields being private
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub n_embd: usize,
+    pub n_layer: usize,
+    pub n_inner: Option<usize>,
+    pub n_head: usize,
+    pub rotary_dim: usize,
+    pub activation_function: Activation,
+    pub layer_norm_epsilon: f64,
+}
+
+/// Causal mask of `seq_len` new tokens following `offset` cached ones, 1 where attention is not allowed
+fn causal_mask(seq_len: usize, offset: usize, device: &Device) -> Result<Tensor> {
+    let mask: Vec<_> = (0..seq_len)
+        .flat_map(|i| (0..offset + seq_len).map(move |j| u8::from(j > offset + i)))
+        .collect();
+    Tensor::from_slice(&mask, (seq_len, offset + seq_len), device)
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
+    let shape = mask.shape();
+    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
+    mask.where_cond(&on_true, on_false)
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(dim: usize, max_seq_len: usize, dev: &Device) -> Result<Self> {
+        let inv_freq: Vec<_> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / 10000f32.powf(i as f32 / dim as f32))
+            .collect();
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((max_seq_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self { sin: freqs.sin()?, cos: freqs.cos()? })
+    }
+
+    fn apply_rotary_emb_qkv(&self, qkv: &Tensor, seqlen_offset: usize) -> Result<(Tensor, Tensor, Tensor)> {
+        let (_b_size, seqlen, three, _, _headdim) = qkv.dims5()?;
+        if three != 3 {
+            candle::bail!("unexpected shape for qkv {:?}", qkv.shape())
+        }
+        let (_rotary_seqlen, rotary_dim) = self.cos.dims2()?;
+        let rotary_dim = rotary_dim * 2;
+        let q_rot = qkv.i((.., .., 0, .., ..rotary_dim))?;
+        let q_pass = qkv.i((.., .., 0, .., rotary_dim..))?;
+        let k_rot = qkv.i((.., .., 1, .., ..rotary_dim))?;
+        let k_pass = qkv.i((.., .., 1, .., rotary_dim..))?;
+        let q12 = q_rot.chunk(2, D::Minus1)?;
+        let k12 = k_rot.chunk(2, D::Minus1)?;
+        let (q1, q2) = (&q12[0], &q12[1]);
+        let (k1, k2) = (&k12[0], &k12[1]);
+        let c = self.cos.narrow(0, seqlen_offset, seqlen)?.unsqueeze(1)?;
+        let s = self.sin.narrow(0, seqlen_offset, seqlen)?.unsqueeze(1)?;
+        let q_rot = Tensor::cat(
+            &[(q1.broadcast_mul(&c)? - q2.broadcast_mul(&s)?)?, (q1.broadcast_mul(&s)? + q2.broadcast_mul(&c)?)?],
+            D::Minus1,
+        )?;
+        let k_rot = Tensor::cat(
+            &[(k1.broadcast_mul(&c)? - k2.broadcast_mul(&s)?)?, (k1.broadcast_mul(&s)? + k2.broadcast_mul(&c)?)?],
+            D::Minus1,
+        )?;
+        let q = Tensor::cat(&[&q_rot, &q_pass], D::Minus1)?;
+        let k = Tensor::cat(&[&k_rot, &k_pass], D::Minus1)?;
+        let v = qkv.i((.., .., 2))?;
+        Ok((q, k, v))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    fc1: Linear,
+    fc2: Linear,
+    act: Activation,
+}
+
+impl Mlp {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let n_inner = cfg.n_inner.unwrap_or(4 * cfg.n_embd);
+        let fc1 = linear(cfg.n_embd, n_inner, vb.pp("fc1"))?;
+        let fc2 = linear(n_inner, cfg.n_embd, vb.pp("fc2"))?;
+        Ok(Self { fc1, fc2, act: cfg.activation_function })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.fc1)?.apply(&self.act)?.apply(&self.fc2)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CausalLMHead {
+    ln: candle_nn::LayerNorm,
+    linear: Linear,
+}
+
+impl CausalLMHead {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let ln = layer_norm(cfg.n_embd, cfg.layer_norm_epsilon, vb.pp("ln"))?;
+        let linear = linear(cfg.n_embd, cfg.vocab_size, vb.pp("linear"))?;
+        Ok(Self { ln, linear })
+    }
+}
+
+impl Module for CausalLMHead {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.ln)?.apply(&self.linear)?.to_dtype(DType::F32)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    wqkv: Linear,
+    out_proj: Linear,
+    rotary_emb: RotaryEmbedding,
+    kv_cache: Option<(Tensor, Tensor)>,
+    head_dim: usize,
+    n_head: usize,
+    softmax_scale: f64,
+}
+
+impl Attention {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let head_dim = cfg.n_embd / cfg.n_head;
+        let wqkv = linear(cfg.n_embd, 3 * cfg.n_embd, vb.pp("Wqkv"))?;
+        let out_proj = linear(cfg.n_embd, cfg.n_embd, vb.pp("out_proj"))?;
+        let rotary_emb = RotaryEmbedding::new(cfg.rotary_dim, MAX_SEQ_LEN, vb.device())?;
+        Ok(Self {
+            wqkv,
+            out_proj,
+            head_dim,
+            n_head: cfg.n_head,
+            kv_cache: None,
+            rotary_emb,
+            softmax_scale: 1f64 / (head_dim as f64).sqrt(),
+        })
+    }
+
+    /// Number of positions already in the kv cache
+    fn seqlen_offset(&self) -> Result<usize> {
+        match &self.kv_cache {
+            None => Ok(0),
+            Some((prev_k, _)) => prev_k.dim(1),
+        }
+    }
+
+    fn forward(&mut self, xs: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
+        let (b_size, seq_len, _n_embd) = xs.dims3()?;
+        let qkv = self.wqkv.forward(xs)?.reshape((b_size, seq_len, 3, (), self.head_dim))?;
+        let (q, k, v) = self.rotary_emb.apply_rotary_emb_qkv(&qkv, self.seqlen_offset()?)?;
+        let (k, v) = match &self.kv_cache {
+            None => (k, v),
+            Some((prev_k, prev_v)) => (Tensor::cat(&[prev_k, &k], 1)?, Tensor::cat(&[prev_v, &v], 1)?),
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
+
+        // b*h, t, d for the queries and b*h, s, d for the keys and values
+        let q = q.transpose(1, 2)?.flatten_to(1)?;
+        let k = k.transpose(1, 2)?.flatten_to(1)?;
+        let v = v.transpose(1, 2)?.flatten_to(1)?;
+        let attn_weights = (q.matmul(&k.t()?)? * self.softmax_scale)?;
+
+        let attn_weights = match mask {
+            None => attn_weights,
+            Some(mask) => masked_fill(&attn_weights, &mask.broadcast_left(b_size * self.n_head)?, f32::NEG_INFINITY)?,
+        };
+        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+
+        let attn_output = attn_weights.matmul(&v)?;
+        let attn_output = attn_output
+            .reshape((b_size, (), seq_len, self.head_dim))?
+            .transpose(1, 2)?
+            .flatten_from(D::Minus2)?;
+        attn_output.apply(&self.out_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ParallelBlock {
+    ln: candle_nn::LayerNorm,
+    mixer: Attention,
+    mlp: Mlp,
+}
+
+impl ParallelBlock {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let ln = layer_norm(cfg.n_embd, cfg.layer_norm_epsilon, vb.pp("ln"))?;
+        let mixer = Attention::new(cfg, vb.pp("mixer"))?;
+        let mlp = Mlp::new(cfg, vb.pp("mlp"))?;
+        Ok(Self { ln, mixer, mlp })
+    }
+
+    fn forward(&mut self, xs: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
+        let residual = xs;
+        let xs = xs.apply(&self.ln)?;
+        let attn_outputs = self.mixer.forward(&xs, mask)?;
+        let feed_forward_hidden_states = self.mlp.forward(&xs)?;
+        attn_outputs + feed_forward_hidden_states + residual
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MixFormerSequentialForCausalLM {
+    embedding: candle_transformers::quantized_nn::Embedding,
+    blocks: Vec<ParallelBlock>,
+    head: CausalLMHead,
+}
+
+impl MixFormerSequentialForCausalLM {
+    pub fn new_v2(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb_head = vb.pp("lm_head");
+        let vb = vb.pp("transformer");
+        let embedding = candle_transformers::quantized_nn::Embedding::new(cfg.vocab_size, cfg.n_embd, vb.pp("embd").pp("wte"))?;
+        let blocks = (0..cfg.n_layer)
+            .map(|i| ParallelBlock::new(cfg, vb.pp("h").pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        let head = CausalLMHead::new(cfg, vb_head)?;
+        Ok(Self { embedding, blocks, head })
+    }
+
+    /// Logits of the last token of `xs`, positions follow the kv cache
+    pub fn forward(&mut self, xs: &Tensor) -> Result<Tensor> {
+        let (_b_size, seq_len) = xs.dims2()?;
+        let offset = match self.blocks.first() {
+            Some(block) => block.mixer.seqlen_offset()?,
+            None => 0,
+        };
+        let mut xs = xs.apply(&self.embedding)?;
+        let mask = if seq_len <= 1 {
+            None
+        } else {
+            Some(causal_mask(seq_len, offset, xs.device())?)
+        };
+        for block in self.blocks.iter_mut() {
+            xs = block.forward(&xs, mask.as_ref())?;
+        }
+        xs.narrow(1, seq_len - 1, 1)?.apply(&self.head)?.squeeze(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle::quantized::{gguf_file, GgmlDType, QTensor};
+
+    use super::*;
+
+    /// Two layers with random weights, named the tensor-tools way
+    fn tiny_model() -> MixFormerSequentialForCausalLM {
+        let config = Config {
+            vocab_size: 16,
+            n_embd: 8,
+            n_layer: 2,
+            n_inner: Some(16),
+            n_head: 2,
+            rotary_dim: 2,
+            activation_function: Activation::Gelu,
+            layer_norm_epsilon: 1e-5,
+        };
+        let random = |shape: &[usize]| QTensor::quantize(&Tensor::randn(0f32, 0.5, shape, &Device::Cpu).unwrap(), GgmlDType::F32).unwrap();
+        let mut tensors = vec![
+            ("transformer.embd.wte.weight".to_string(), random(&[16, 8])),
+            ("lm_head.ln.weight".to_string(), random(&[8])),
+            ("lm_head.ln.bias".to_string(), random(&[8])),
+            ("lm_head.linear.weight".to_string(), random(&[16, 8])),
+            ("lm_head.linear.bias".to_string(), random(&[16])),
+        ];
+        for layer in 0..2 {
+            for (name, out_dim, in_dim) in [("mixer.Wqkv", 24, 8), ("mixer.out_proj", 8, 8), ("mlp.fc1", 16, 8), ("mlp.fc2", 8, 16)] {
+                tensors.push((format!("transformer.h.{layer}.{name}.weight"), random(&[out_dim, in_dim])));
+                tensors.push((format!("transformer.h.{layer}.{name}.bias"), random(&[out_dim])));
+            }
+            tensors.push((format!("transformer.h.{layer}.ln.weight"), random(&[8])));
+            tensors.push((format!("transformer.h.{layer}.ln.bias"), random(&[8])));
+        }
+
+        let mut buffer = std::io::Cursor::new(Vec::new());
+        gguf_file::write(&mut buffer, &[], &tensors.iter().map(|(name, tensor)| (name.as_str(), tensor)).collect::<Vec<_>>()).unwrap();
+        let vb = VarBuilder::from_gguf_buffer(buffer.get_ref(), &Device::Cpu).unwrap();
+        MixFormerSequentialForCausalLM::new_v2(&config, vb).unwrap()
+    }
+
+    fn max_difference(a: &Tensor, b: &Tensor) -> f32 {
+        (a - b).unwrap().abs().unwrap().flatten_all().unwrap().max(0).unwrap().to_scalar::<f32>().unwrap()
+    }
+
+    #[test]
+    fn prompt_after_a_cached_prefix_matches_a_single_pass() {
+        let tokens = Tensor::new(&[[1u32, 5, 7, 2, 9, 3, 11]], &Device::Cpu).unwrap();
+        let mut model = tiny_model();
+        let mut whole = model.clone();
+        let expected = whole.forward(&tokens).unwrap();
+
+        // Prefix of 3 tokens, then the 4 remaining ones in one forward
+        model.forward(&tokens.narrow(1, 0, 3).unwrap()).unwrap();
+        let mut token_by_token = model.clone();
+        let logits = model.forward(&tokens.narrow(1, 3, 4).unwrap()).unwrap();
+        assert!(max_difference(&logits, &expected) < 1e-4);
+
+        let mut logits = None;
+        for index in 3..7 {
+            logits = Some(token_by_token.forward(&tokens.narrow(1, index, 1).unwrap()).unwrap());
+        }
+        assert!(max_difference(&logits.unwrap(), &expected) < 1e-4);
+    }
+
+    #[test]
+    fn mask_covers_the_cached_positions() {
+        let mask = causal_mask(2, 2, &Device::Cpu).unwrap().to_vec2::<u8>().unwrap();
+        assert_eq!(mask, vec![vec![0, 0, 0, 1], vec![0, 0, 0, 0]]);
+
+        let mask = causal_mask(2, 0, &Device::Cpu).unwrap().to_vec2::<u8>().unwrap();
+        assert_eq!(mask, vec![vec![0, 1], vec![0, 0]]);
+    }
+}
@@ -0,0 +1,146 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Number of distinct prefixes ( contexts ) kept in the cache
+const MAX_PREFIX_ENTRIES: usize = 8;
+
+struct PrefixEntry<M> {
+    prefix: String,
+    tokens: Vec<u32>,
+    model: M,
+}
+
+/// Counters exposed on the metrics route
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PrefixCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub tokens_saved: u64,
+}
+
+/// Keeps a copy of the model ( and thus of its kv cache ) right after the shared
+/// part of the prompt, usually the system context, has been processed.
+/// Requests sharing this prefix fork it and only process their own suffix.
+pub struct PrefixCache<M> {
+    entries: Mutex<Vec<PrefixEntry<M>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    tokens_saved: AtomicU64,
+}
+
+impl<M: Clone> PrefixCache<M> {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(Vec::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            tokens_saved: AtomicU64::new(0),
+        }
+    }
+
+    /// Fork the cached model for `prefix`, provided the prompt tokens start with the
+    /// cached tokens and go beyond them. Returns the model and the number of tokens
+    /// already processed.
+    pub fn lookup(&self, prefix: &str, prompt_tokens: &[u32]) -> Option<(M, usize)> {
+        let entries = self.entries.lock().unwrap();
+        let found = entries
+            .iter()
+            .find(|e| e.prefix == prefix)
+            .filter(|e| e.tokens.len() < prompt_tokens.len() && prompt_tokens.starts_with(&e.tokens))
+            .map(|e| (e.model.clone(), e.tokens.len()));
+
+        match &found {
+            Some((_, saved)) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                self.tokens_saved.fetch_add(*saved as u64, Ordering::Relaxed);
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        found
+    }
+
+    pub fn contains(&self, prefix: &str) -> bool {
+        self.entries.lock().unwrap().iter().any(|e| e.prefix == prefix)
+    }
+
+    /// Keep the model state after `tokens`, the oldest prefix is evicted when full
+    pub fn insert(&self, prefix: &str, tokens: Vec<u32>, model: M) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|e| e.prefix != prefix);
+        if entries.len() >= MAX_PREFIX_ENTRIES {
+            entries.remove(0);
+        }
+        entries.push(PrefixEntry {
+            prefix: prefix.to_string(),
+            tokens,
+            model,
+        });
+    }
+
+    pub fn stats(&self) -> PrefixCacheStats {
+        PrefixCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            tokens_saved: self.tokens_saved.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<M> fmt::Debug for PrefixCache<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrefixCache")
+            .field("entries", &self.entries.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl<M: Clone> Default for PrefixCache<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_forks_the_longest_matching_entry() {
+        let cache = PrefixCache::new();
+        cache.insert("context", vec![1, 2, 3], "after context");
+
+        assert_eq!(cache.lookup("context", &[1, 2, 3, 4, 5]), Some(("after context", 3)));
+        // Another prefix text, prompt not going beyond the prefix, or not starting with it
+        assert_eq!(cache.lookup("other", &[1, 2, 3, 4]), None);
+        assert_eq!(cache.lookup("context", &[1, 2, 3]), None);
+        assert_eq!(cache.lookup("context", &[1, 9, 3, 4]), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.tokens_saved), (1, 3, 3));
+    }
+
+    #[test]
+    fn insert_replaces_the_same_prefix() {
+        let cache = PrefixCache::new();
+        cache.insert("context", vec![1, 2], "first");
+        cache.insert("context", vec![1, 2, 3], "second");
+
+        assert_eq!(cache.lookup("context", &[1, 2, 3, 4]), Some(("second", 3)));
+        assert_eq!(format!("{:?}", cache), "PrefixCache { entries: 1 }");
+    }
+
+    #[test]
+    fn oldest_prefix_is_evicted() {
+        let cache = PrefixCache::new();
+        for index in 0..=MAX_PREFIX_ENTRIES {
+            cache.insert(&format!("context {}", index), vec![index as u32], index);
+        }
+
+        assert!(!cache.contains("context 0"));
+        assert!((1..=MAX_PREFIX_ENTRIES).all(|index| cache.contains(&format!("context {}", index))));
+        assert_eq!(cache.lookup("context 1", &[1, 0]), Some((1, 1)));
+    }
+}
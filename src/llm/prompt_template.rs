@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+use anyhow::{Error as E, Result};
+use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
+
+use crate::args_init::args::ModelFamily;
+use crate::llm::chat_template::ChatTemplate;
+
+/// A question and its answer, from a previous turn of a conversation
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Exchange {
+    pub user: String,
+    pub assistant: String,
+}
+
+/// How the context and the user prompt are laid out for a given model.
+/// The context always comes first, so that the part of the prompt depending only
+/// on it ( the prefix ) can be cached.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PromptTemplate {
+    /// `<s>[INST]context\n\nprompt[/INST]`, mistral has no system role, the context
+    /// opens the first instruction
+    MistralInstruct,
+    /// `GPT4 Correct User: context\n\nprompt<|end_of_turn|>GPT4 Correct Assistant:`
+    OpenChat,
+    /// `Context:context.\nInstruct: prompt.\nOutput:`
+    Phi2,
+    /// Chat template shipped with the model
+    Chat(Arc<ChatTemplate>),
+}
+
+impl PromptTemplate {
+    /// Built-in template matching a model family, `model_type` refines the llama family
+    pub fn for_model(model_family: ModelFamily, model_type: &str) -> Self {
+        match model_family {
+            ModelFamily::Mistral => PromptTemplate::MistralInstruct,
+            ModelFamily::PhiV2 => PromptTemplate::Phi2,
+            ModelFamily::Llama => match model_type {
+                "open_chat" => PromptTemplate::OpenChat,
+                _ => PromptTemplate::MistralInstruct,
+            },
+        }
+    }
+
+    /// Part of the prompt which only depends on the context
+    pub fn prefix(&self, context: &str) -> Result<String> {
+        let context = context.trim();
+        Ok(match self {
+            PromptTemplate::MistralInstruct if context.is_empty() => "<s>[INST]".to_string(),
+            PromptTemplate::MistralInstruct => format!("<s>[INST]{}\n\n", context),
+            PromptTemplate::OpenChat if context.is_empty() => "GPT4 Correct User: ".to_string(),
+            PromptTemplate::OpenChat => format!("GPT4 Correct User: {}\n\n", context),
+            PromptTemplate::Phi2 => format!("Context:{}.\nInstruct:", context),
+            PromptTemplate::Chat(chat_template) => return chat_template.prefix(context),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PromptTemplate::MistralInstruct => "mistral instruct",
+            PromptTemplate::OpenChat => "open chat",
+            PromptTemplate::Phi2 => "phi-2",
+            PromptTemplate::Chat(_) => "chat template of the model",
+        }
+    }
+
+    /// Tokens ending the answer of the model
+    pub fn stop_tokens(&self) -> Vec<String> {
+        match self {
+            PromptTemplate::MistralInstruct => vec!["</s>".to_string()],
+            PromptTemplate::OpenChat => vec!["<|end_of_turn|>".to_string()],
+            PromptTemplate::Phi2 => vec!["<|endoftext|>".to_string()],
+            PromptTemplate::Chat(chat_template) => chat_template.stop_tokens().to_vec(),
+        }
+    }
+
+    /// Full prompt, starting with `prefix(context)`
+    pub fn render(&self, context: &str, prompt: &str) -> Result<String> {
+        self.render_conversation(context, &[], prompt)
+    }
+
+    /// Full prompt with the previous turns of a conversation between the context and `prompt`
+    pub fn render_conversation(&self, context: &str, history: &[Exchange], prompt: &str) -> Result<String> {
+        if let PromptTemplate::Chat(chat_template) = self {
+            return chat_template.render_conversation(context, history, prompt);
+        }
+        let mut rendered = self.prefix(context)?;
+        for exchange in history {
+            let (user, assistant) = (exchange.user.trim(), exchange.assistant.trim());
+            rendered.push_str(&match self {
+                PromptTemplate::MistralInstruct => format!("{}[/INST]{}</s>[INST]", user, assistant),
+                PromptTemplate::OpenChat => format!(
+                    "{}<|end_of_turn|>GPT4 Correct Assistant: {}<|end_of_turn|>GPT4 Correct User: ",
+                    user, assistant
+                ),
+                PromptTemplate::Phi2 => format!(" {}.\nOutput: {}\nInstruct:", user, assistant),
+                PromptTemplate::Chat(_) => unreachable!(),
+            });
+        }
+        let prompt = prompt.trim();
+        rendered.push_str(&match self {
+            PromptTemplate::MistralInstruct => format!("{}[/INST]", prompt),
+            PromptTemplate::OpenChat => format!("{}<|end_of_turn|>GPT4 Correct Assistant:", prompt),
+            PromptTemplate::Phi2 => format!(" {}.\nOutput:", prompt),
+            PromptTemplate::Chat(_) => unreachable!(),
+        });
+        Ok(rendered)
+    }
+}
+
+/// Tokens of a rendered prompt. Most chat templates, as well as the mistral instruct template,
+/// already start the prompt with the BOS token : the tokenizer would add a second one, so
+/// special tokens are only added when the prompt does not start with the BOS token.
+/// The prefix goes through the same rule, so that the cached prefix tokens match the prompt ones.
+pub fn encode_prompt(tokenizer: &Tokenizer, text: &str) -> Result<Vec<u32>> {
+    let bos = tokenizer.encode("", true).map_err(E::msg)?.get_ids().first().copied();
+    let tokens = tokenizer.encode(text, false).map_err(E::msg)?.get_ids().to_vec();
+    match bos {
+        Some(bos) if tokens.first() != Some(&bos) => Ok(tokenizer.encode(text, true).map_err(E::msg)?.get_ids().to_vec()),
+        _ => Ok(tokens),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    /// Word level tokenizer adding `<s>` in front of the text, as the llama and mistral ones do
+    fn tokenizer() -> Tokenizer {
+        let json = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [
+                { "id": 1, "content": "<s>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true }
+            ],
+            "normalizer": null,
+            "pre_tokenizer": { "type": "WhitespaceSplit" },
+            "post_processor": {
+                "type": "TemplateProcessing",
+                "single": [{ "SpecialToken": { "id": "<s>", "type_id": 0 } }, { "Sequence": { "id": "A", "type_id": 0 } }],
+                "pair": [{ "Sequence": { "id": "A", "type_id": 0 } }, { "Sequence": { "id": "B", "type_id": 1 } }],
+                "special_tokens": { "<s>": { "id": "<s>", "ids": [1], "tokens": ["<s>"] } }
+            },
+            "decoder": null,
+            "model": { "type": "WordLevel", "vocab": { "<unk>": 0, "<s>": 1, "[INST]": 2, "hello": 3, "[/INST]": 4 }, "unk_token": "<unk>" }
+        });
+        Tokenizer::from_str(json.to_string().as_str()).unwrap()
+    }
+
+    #[test]
+    fn bos_token_is_added_once() {
+        let tokenizer = tokenizer();
+        assert_eq!(encode_prompt(&tokenizer, "hello").unwrap(), vec![1, 3]);
+        assert_eq!(encode_prompt(&tokenizer, "<s> [INST] hello [/INST]").unwrap(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn prefix_tokens_start_the_prompt_tokens() {
+        let tokenizer = tokenizer();
+        let prefix = encode_prompt(&tokenizer, "<s> [INST]").unwrap();
+        let prompt = encode_prompt(&tokenizer, "<s> [INST] hello [/INST]").unwrap();
+        assert_eq!(prefix, vec![1, 2]);
+        assert!(prompt.starts_with(&prefix));
+    }
+}
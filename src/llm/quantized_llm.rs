@@ -1,57 +1,134 @@
+use std::sync::Arc;
 use anyhow::{Error as E, Result};
 use candle::Device;
-use candle_transformers::generation::LogitsProcessor;
 use tokenizers::Tokenizer;
 use tokio::sync::mpsc::UnboundedSender;
 use crate::llm::token_output_stream::TokenOutputStream;
-use crate::args_init::args::Args;
+use crate::args_init::args::ModelFamily;
+use crate::llm::engine::LlmEngine;
+use crate::llm::classification::Classification;
+use crate::llm::generation_summary::GenerationSummary;
+use crate::llm::grammar::token_constraint::VocabularyCache;
+use crate::llm::sampler::Sampler;
+use crate::llm::prefix_cache::{PrefixCache, PrefixCacheStats};
+use crate::llm::prompt_template::{encode_prompt, Exchange, PromptTemplate};
+use crate::llm::sampling_params::SamplingParams;
 
 
-use candle_transformers::models::quantized_llama as model;
+use crate::llm::llama_llm::quantized_llama as model;
 use model::ModelWeights;
 
 #[derive( Debug,Clone)]
 pub struct QuantizedLlmPackage {
-    pub model_type:String,
+    /// Chat format, the one shipped with the model or a built-in one
+    pub prompt_template:PromptTemplate,
     pub model_weights:ModelWeights,
     pub device:Device,
     pub tokenizer:Tokenizer,
-    pub seed:u64,
-    pub temperature:f64,
-    pub top_p:f64,
-    pub repeat_penalty:f32,
-    pub repeat_last_n:usize,
-    pub sample_len:usize,
+    /// Default sampling parameters, from the command line
+    pub sampling:SamplingParams,
+    /// Maximum number of tokens, prompt and generated ones, the model can attend to
+    pub context_length:usize,
+    /// Tokens ending the answer, the eos token and the ones ending a turn of the chat format
+    pub stop_tokens:Vec<u32>,
+    /// Model states after the context part of the prompt, shared by all clones
+    pub prefix_cache:Arc<PrefixCache<ModelWeights>>,
+    /// Token texts for constrained decoding, shared by all clones
+    pub vocabulary:Arc<VocabularyCache>,
 }
 
 pub struct QuantizedTextGeneration {
-    pub model_type:String,
+    pub prompt_template:PromptTemplate,
     pub model_weights: ModelWeights,
     pub device: Device,
     pub tokenizer: TokenOutputStream,
-    pub logits_processor: LogitsProcessor,
+    pub stop_tokens: Vec<u32>,
+    pub sampler: Sampler,
     pub repeat_penalty: f32,
     pub repeat_last_n: usize,
 }
 
 
-pub trait QuantizedLLM {
-    fn initialize(&self,args_init: Args) ->  Result<QuantizedLlmPackage> ;
+pub fn generate( quantized_llm_package:QuantizedLlmPackage,prompt:&str,history:&[Exchange],sampling:&SamplingParams,tx:UnboundedSender<String>,context:&str) -> Result<GenerationSummary> {
+    let mut pipeline = QuantizedTextGeneration::new(
+        quantized_llm_package.prompt_template,
+        quantized_llm_package.model_weights,
+        quantized_llm_package.tokenizer,
+        quantized_llm_package.stop_tokens,
+        Sampler::new(sampling),
+        sampling.repeat_penalty,
+        sampling.repeat_last_n,
+        &quantized_llm_package.device,
+    );
+    pipeline.run(prompt, history, sampling,tx,context,&quantized_llm_package.prefix_cache,&quantized_llm_package.vocabulary)
 }
 
-
-pub fn generate( quantized_llm_package:QuantizedLlmPackage,prompt:&str,tx:UnboundedSender<String>,context:&str) -> Result<()> {
+pub fn classify( quantized_llm_package:QuantizedLlmPackage,text:&str,labels:&[String],context:&str) -> Result<Classification> {
+    let sampling = &quantized_llm_package.sampling;
     let mut pipeline = QuantizedTextGeneration::new(
-        quantized_llm_package.model_type,
+        quantized_llm_package.prompt_template,
         quantized_llm_package.model_weights,
         quantized_llm_package.tokenizer,
-        quantized_llm_package.seed,
-        Some(quantized_llm_package.temperature),
-        Some(quantized_llm_package.top_p),
-        quantized_llm_package.repeat_penalty,
-        quantized_llm_package.repeat_last_n,
+        quantized_llm_package.stop_tokens,
+        Sampler::new(sampling),
+        sampling.repeat_penalty,
+        sampling.repeat_last_n,
         &quantized_llm_package.device,
     );
-    pipeline.run(prompt, quantized_llm_package.sample_len,quantized_llm_package.seed,Some(quantized_llm_package.temperature),Some(quantized_llm_package.top_p),tx,context)?;
-    Ok(())
-}
\ No newline at end of file
+    pipeline.classify(text, labels, context, &quantized_llm_package.prefix_cache)
+}
+
+
+impl LlmEngine for QuantizedLlmPackage {
+    fn model_family(&self) -> ModelFamily {
+        ModelFamily::Llama
+    }
+
+    fn default_sampling(&self) -> &SamplingParams {
+        &self.sampling
+    }
+
+    fn generate(&self, prompt: &str, history: &[Exchange], sampling: &SamplingParams, tx: UnboundedSender<String>, context: &str) -> Result<GenerationSummary> {
+        // Each generation works on its own copy of the model ( and of its kv cache )
+        generate(self.clone(), prompt, history, sampling, tx, context)
+    }
+
+    fn classify(&self, text: &str, labels: &[String], context: &str) -> Result<Classification> {
+        classify(self.clone(), text, labels, context)
+    }
+
+    fn prompt_template(&self) -> PromptTemplate {
+        self.prompt_template.clone()
+    }
+
+    fn context_length(&self) -> usize {
+        self.context_length
+    }
+
+    fn count_tokens(&self, text: &str) -> Result<usize> {
+        Ok(encode_prompt(&self.tokenizer, text)?.len())
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.tokenizer.get_vocab_size(true)
+    }
+
+    fn warm_prefix_cache(&self, context: &str) -> Result<()> {
+        let sampling = &self.sampling;
+        QuantizedTextGeneration::new(
+            self.prompt_template.clone(),
+            self.model_weights.clone(),
+            self.tokenizer.clone(),
+            self.stop_tokens.clone(),
+            Sampler::new(sampling),
+            sampling.repeat_penalty,
+            sampling.repeat_last_n,
+            &self.device,
+        )
+        .warm_prefix(context, &self.prefix_cache)
+    }
+
+    fn prefix_cache_stats(&self) -> PrefixCacheStats {
+        self.prefix_cache.stats()
+    }
+}
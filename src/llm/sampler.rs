@@ -0,0 +1,392 @@
+use anyhow::{bail, Result};
+use candle::{DType, Tensor};
+use clap::ValueEnum;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+
+use crate::llm::sampling_params::SamplingParams;
+
+/// Truncation steps of the sampler chain, applied in the given order after the temperature
+#[derive(Serialize, Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum SamplerKind {
+    /// Keep the `top_k` most likely tokens
+    TopK,
+    /// Keep the tokens closest to the expected information content, up to `typical_p`
+    TypicalP,
+    /// Keep the most likely tokens, up to a cumulative probability of `top_p`
+    TopP,
+    /// Keep the tokens at least `min_p` times as likely as the most likely one
+    MinP,
+}
+
+/// Default order of the sampler chain
+pub const DEFAULT_SAMPLERS: [SamplerKind; 4] = [SamplerKind::TopK, SamplerKind::TypicalP, SamplerKind::TopP, SamplerKind::MinP];
+
+/// Mirostat v2 settings, it replaces the sampler chain and keeps the surprise of the
+/// sampled tokens around `tau`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mirostat {
+    /// Target surprise, in bits
+    pub tau: f64,
+    /// Learning rate
+    pub eta: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    token: u32,
+    prob: f32,
+}
+
+/// Picks the next token from the logits of the model, following the sampling parameters
+/// of a generation. Mirostat keeps a state, a sampler is used for one generation only.
+pub struct Sampler {
+    rng: StdRng,
+    temperature: f64,
+    samplers: Vec<SamplerKind>,
+    top_k: usize,
+    top_p: f64,
+    min_p: f64,
+    typical_p: f64,
+    mirostat: Option<Mirostat>,
+    /// Mirostat maximum surprise
+    mu: f64,
+}
+
+impl Sampler {
+    pub fn new(sampling: &SamplingParams) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(sampling.seed),
+            temperature: sampling.temperature,
+            samplers: sampling.samplers.clone(),
+            top_k: sampling.top_k,
+            top_p: sampling.top_p,
+            min_p: sampling.min_p,
+            typical_p: sampling.typical_p,
+            mirostat: sampling.mirostat,
+            mu: sampling.mirostat.map(|mirostat| 2. * mirostat.tau).unwrap_or_default(),
+        }
+    }
+
+    pub fn sample(&mut self, logits: &Tensor) -> Result<u32> {
+        let logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        self.sample_logits(&logits)
+    }
+
+    fn sample_logits(&mut self, logits: &[f32]) -> Result<u32> {
+        if logits.is_empty() {
+            bail!("no logits to sample from");
+        }
+
+        // Greedy decoding
+        if self.temperature < 1e-7 {
+            return Ok(argmax(logits));
+        }
+
+        let mut candidates = softmax(logits, self.temperature);
+
+        if let Some(mirostat) = self.mirostat {
+            return self.sample_mirostat(candidates, mirostat);
+        }
+
+        for sampler in &self.samplers {
+            match sampler {
+                SamplerKind::TopK => top_k(&mut candidates, self.top_k),
+                SamplerKind::TypicalP => typical_p(&mut candidates, self.typical_p),
+                SamplerKind::TopP => top_p(&mut candidates, self.top_p),
+                SamplerKind::MinP => min_p(&mut candidates, self.min_p),
+            }
+        }
+
+        let index = self.pick(&candidates)?;
+        Ok(candidates[index].token)
+    }
+
+    fn sample_mirostat(&mut self, mut candidates: Vec<Candidate>, mirostat: Mirostat) -> Result<u32> {
+        // Leave out the tokens more surprising than mu, the most likely one always stays
+        let kept = candidates.iter().skip(1).take_while(|c| surprise(c.prob) <= self.mu).count() + 1;
+        candidates.truncate(kept);
+        normalize(&mut candidates);
+
+        let index = self.pick(&candidates)?;
+        let observed = surprise(candidates[index].prob);
+        self.mu -= mirostat.eta * (observed - mirostat.tau);
+        Ok(candidates[index].token)
+    }
+
+    fn pick(&mut self, candidates: &[Candidate]) -> Result<usize> {
+        let distribution = WeightedIndex::new(candidates.iter().map(|c| c.prob))?;
+        Ok(distribution.sample(&mut self.rng))
+    }
+}
+
+fn argmax(logits: &[f32]) -> u32 {
+    logits
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(index, _)| index as u32)
+        .unwrap_or_default()
+}
+
+/// Probabilities of all tokens, most likely first
+fn softmax(logits: &[f32], temperature: f64) -> Vec<Candidate> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut candidates = logits
+        .iter()
+        .enumerate()
+        .map(|(token, logit)| Candidate {
+            token: token as u32,
+            prob: (((logit - max) as f64) / temperature).exp() as f32,
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by(|a, b| b.prob.total_cmp(&a.prob));
+    normalize(&mut candidates);
+    candidates
+}
+
+fn normalize(candidates: &mut [Candidate]) {
+    let total: f32 = candidates.iter().map(|c| c.prob).sum();
+    if total > 0. {
+        candidates.iter_mut().for_each(|c| c.prob /= total);
+    }
+}
+
+fn surprise(prob: f32) -> f64 {
+    -(prob as f64).log2()
+}
+
+/// `candidates` are sorted, most likely first. 0 disables it
+fn top_k(candidates: &mut Vec<Candidate>, k: usize) {
+    if k > 0 && k < candidates.len() {
+        candidates.truncate(k);
+        normalize(candidates);
+    }
+}
+
+/// `candidates` are sorted, most likely first. 1 disables it
+fn top_p(candidates: &mut Vec<Candidate>, p: f64) {
+    if p <= 0. || p >= 1. {
+        return;
+    }
+    let mut cumulative = 0.;
+    let kept = candidates
+        .iter()
+        .position(|c| {
+            cumulative += c.prob as f64;
+            cumulative >= p
+        })
+        .map(|index| index + 1)
+        .unwrap_or(candidates.len());
+    candidates.truncate(kept);
+    normalize(candidates);
+}
+
+/// `candidates` are sorted, most likely first. 0 disables it
+fn min_p(candidates: &mut Vec<Candidate>, p: f64) {
+    if p <= 0. || candidates.is_empty() {
+        return;
+    }
+    let threshold = candidates[0].prob as f64 * p;
+    candidates.retain(|c| c.prob as f64 >= threshold);
+    normalize(candidates);
+}
+
+/// Locally typical sampling: keep the tokens whose information content is the closest
+/// to the entropy of the distribution, up to a cumulative probability of `p`.
+/// `candidates` stay sorted, most likely first. 1 disables it
+fn typical_p(candidates: &mut Vec<Candidate>, p: f64) {
+    if p <= 0. || p >= 1. {
+        return;
+    }
+    let entropy: f64 = candidates
+        .iter()
+        .filter(|c| c.prob > 0.)
+        .map(|c| -(c.prob as f64) * (c.prob as f64).ln())
+        .sum();
+    let deviation = |c: &Candidate| {
+        if c.prob > 0. {
+            (-(c.prob as f64).ln() - entropy).abs()
+        } else {
+            f64::INFINITY
+        }
+    };
+
+    let mut by_deviation = candidates.clone();
+    by_deviation.sort_by(|a, b| deviation(a).total_cmp(&deviation(b)));
+
+    let mut cumulative = 0.;
+    let kept = by_deviation
+        .iter()
+        .position(|c| {
+            cumulative += c.prob as f64;
+            cumulative >= p
+        })
+        .map(|index| index + 1)
+        .unwrap_or(by_deviation.len());
+    by_deviation.truncate(kept);
+    by_deviation.sort_by(|a, b| b.prob.total_cmp(&a.prob));
+    normalize(&mut by_deviation);
+    *candidates = by_deviation;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Logits of the distribution [0.5, 0.25, 0.125, 0.0625, 0.0625]
+    fn logits() -> Vec<f32> {
+        [0.5f32, 0.25, 0.125, 0.0625, 0.0625].iter().map(|p| p.ln()).collect()
+    }
+
+    fn tokens(candidates: &[Candidate]) -> Vec<u32> {
+        candidates.iter().map(|c| c.token).collect()
+    }
+
+    fn assert_probs(candidates: &[Candidate], expected: &[f32]) {
+        assert_eq!(candidates.len(), expected.len());
+        for (c, p) in candidates.iter().zip(expected) {
+            assert!((c.prob - p).abs() < 1e-5, "{:?} != {:?}", candidates, expected);
+        }
+    }
+
+    fn params() -> SamplingParams {
+        SamplingParams {
+            seed: 42,
+            temperature: 1.,
+            top_p: 1.,
+            repeat_penalty: 1.,
+            repeat_last_n: 64,
+            sample_len: 10,
+            stop: Vec::new(),
+            max_time: None,
+            samplers: DEFAULT_SAMPLERS.to_vec(),
+            top_k: 0,
+            min_p: 0.,
+            typical_p: 1.,
+            mirostat: None,
+            frequency_penalty: 0.,
+            presence_penalty: 0.,
+            logit_bias: Default::default(),
+            grammar: None,
+        }
+    }
+
+    #[test]
+    fn softmax_sorts_and_normalizes() {
+        let candidates = softmax(&[0.0625f32.ln(), 0.5f32.ln(), 0.4375f32.ln()], 1.);
+        assert_eq!(tokens(&candidates), vec![1, 2, 0]);
+        assert_probs(&candidates, &[0.5, 0.4375, 0.0625]);
+    }
+
+    #[test]
+    fn temperature_flattens_the_distribution() {
+        let candidates = softmax(&logits(), 2.);
+        let sharp = softmax(&logits(), 0.5);
+        assert!(candidates[0].prob < 0.5);
+        assert!(sharp[0].prob > 0.5);
+    }
+
+    #[test]
+    fn top_k_keeps_the_k_most_likely_tokens() {
+        let mut candidates = softmax(&logits(), 1.);
+        top_k(&mut candidates, 2);
+        assert_eq!(tokens(&candidates), vec![0, 1]);
+        assert_probs(&candidates, &[2. / 3., 1. / 3.]);
+
+        let mut candidates = softmax(&logits(), 1.);
+        top_k(&mut candidates, 0);
+        assert_eq!(candidates.len(), 5);
+    }
+
+    #[test]
+    fn top_p_keeps_the_smallest_set_reaching_p() {
+        let mut candidates = softmax(&logits(), 1.);
+        top_p(&mut candidates, 0.8);
+        assert_eq!(tokens(&candidates), vec![0, 1, 2]);
+        assert_probs(&candidates, &[0.5 / 0.875, 0.25 / 0.875, 0.125 / 0.875]);
+
+        let mut candidates = softmax(&logits(), 1.);
+        top_p(&mut candidates, 0.1);
+        assert_eq!(tokens(&candidates), vec![0]);
+    }
+
+    #[test]
+    fn min_p_is_relative_to_the_most_likely_token() {
+        let mut candidates = softmax(&logits(), 1.);
+        min_p(&mut candidates, 0.2);
+        assert_eq!(tokens(&candidates), vec![0, 1, 2]);
+
+        let mut candidates = softmax(&logits(), 1.);
+        min_p(&mut candidates, 0.6);
+        assert_eq!(tokens(&candidates), vec![0]);
+        assert_probs(&candidates, &[1.]);
+    }
+
+    #[test]
+    fn typical_p_keeps_the_tokens_closest_to_the_entropy() {
+        // Entropy of [0.4, 0.3, 0.2, 0.1] is 1.28 nats, the information content of
+        // the tokens is 0.92, 1.20, 1.61 and 2.30 nats : token 1 is the most typical,
+        // then token 2, then token 0
+        let logits = [0.4f32, 0.3, 0.2, 0.1].iter().map(|p| p.ln()).collect::<Vec<_>>();
+        let mut candidates = softmax(&logits, 1.);
+        typical_p(&mut candidates, 0.45);
+        assert_eq!(tokens(&candidates), vec![1, 2]);
+        assert_probs(&candidates, &[0.6, 0.4]);
+
+        let mut candidates = softmax(&logits, 1.);
+        typical_p(&mut candidates, 0.2);
+        assert_eq!(tokens(&candidates), vec![1]);
+    }
+
+    #[test]
+    fn chain_applies_the_samplers_in_order() {
+        let mut sampling = params();
+        sampling.top_k = 3;
+        sampling.min_p = 0.3;
+        let mut sampler = Sampler::new(&sampling);
+        for _ in 0..50 {
+            let token = sampler.sample_logits(&logits()).unwrap();
+            assert!(token == 0 || token == 1, "token {} should have been filtered", token);
+        }
+    }
+
+    #[test]
+    fn zero_temperature_is_greedy() {
+        let mut sampling = params();
+        sampling.temperature = 0.;
+        let mut sampler = Sampler::new(&sampling);
+        assert_eq!(sampler.sample_logits(&[0.1, 2.0, -1.0]).unwrap(), 1);
+    }
+
+    #[test]
+    fn same_seed_same_tokens() {
+        let sample = || {
+            let mut sampler = Sampler::new(&params());
+            (0..20).map(|_| sampler.sample_logits(&logits()).unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(sample(), sample());
+    }
+
+    #[test]
+    fn mirostat_truncates_on_surprise_and_adapts_mu() {
+        let mut sampling = params();
+        sampling.mirostat = Some(Mirostat { tau: 1., eta: 0.5 });
+        let mut sampler = Sampler::new(&sampling);
+        assert_eq!(sampler.mu, 2.);
+
+        // Surprises are 1, 2, 3, 4 and 4 bits, mu = 2 keeps the first two tokens
+        let token = sampler.sample_logits(&logits()).unwrap();
+        let observed = if token == 0 { (1.5f64).log2() } else { 3f64.log2() };
+        assert!(token == 0 || token == 1);
+        assert!((sampler.mu - (2. - 0.5 * (observed - 1.))).abs() < 1e-6);
+
+        // Below the surprise of every token, only the most likely one is left
+        sampler.mu = 0.;
+        assert_eq!(sampler.sample_logits(&logits()).unwrap(), 0);
+    }
+}
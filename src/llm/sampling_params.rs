@@ -0,0 +1,301 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::args_init::args::Args;
+use crate::llm::grammar::gbnf::Grammar;
+use crate::llm::grammar::json_schema::json_schema_to_gbnf;
+use crate::llm::sampler::{Mirostat, SamplerKind};
+
+/// Upper bound accepted for a per request `sample_len`
+pub const MAX_SAMPLE_LEN: usize = 32_768;
+
+/// Mirostat learning rate when only the target surprise is given
+pub const DEFAULT_MIROSTAT_ETA: f64 = 0.1;
+
+/// Upper bound accepted for the number of stop sequences of a request
+pub const MAX_STOP_SEQUENCES: usize = 16;
+
+/// Frequency and presence penalties are accepted in [-MAX_PENALTY, MAX_PENALTY]
+pub const MAX_PENALTY: f32 = 2.;
+
+/// Logit biases are accepted in [-MAX_LOGIT_BIAS, MAX_LOGIT_BIAS], -MAX_LOGIT_BIAS bans the token
+pub const MAX_LOGIT_BIAS: f32 = 100.;
+
+/// Sampling parameters used for one generation
+#[derive(Debug, Clone, PartialEq)]
+pub struct SamplingParams {
+    pub seed: u64,
+    pub temperature: f64,
+    pub top_p: f64,
+    pub repeat_penalty: f32,
+    pub repeat_last_n: usize,
+    pub sample_len: usize,
+    /// Generation stops on the first of these texts, which is left out of the answer
+    pub stop: Vec<String>,
+    /// Generation stops after this time, prompt processing included
+    pub max_time: Option<Duration>,
+    /// Truncation steps applied after the temperature, in this order
+    pub samplers: Vec<SamplerKind>,
+    pub top_k: usize,
+    pub min_p: f64,
+    pub typical_p: f64,
+    /// Replaces the sampler chain when set
+    pub mirostat: Option<Mirostat>,
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+    /// Bias added to the logits of the tokens, by token id
+    pub logit_bias: BTreeMap<u32, f32>,
+    /// Tokens which would take the answer out of the grammar are never sampled
+    pub grammar: Option<Arc<Grammar>>,
+}
+
+impl SamplingParams {
+    /// Process wide defaults, taken from the command line
+    pub fn from_args(args_init: &Args) -> Self {
+        Self {
+            seed: args_init.seed,
+            temperature: args_init.temperature,
+            top_p: args_init.top_p,
+            repeat_penalty: args_init.repeat_penalty,
+            repeat_last_n: args_init.repeat_last_n,
+            sample_len: args_init.sample_len,
+            stop: args_init.stop.clone(),
+            max_time: args_init.max_time.filter(|secs| secs.is_finite() && *secs > 0.).map(Duration::from_secs_f64),
+            samplers: args_init.samplers.clone(),
+            top_k: args_init.top_k,
+            min_p: args_init.min_p,
+            typical_p: args_init.typical_p,
+            mirostat: args_init
+                .mirostat_tau
+                .filter(|tau| *tau > 0.)
+                .map(|tau| Mirostat { tau, eta: args_init.mirostat_eta }),
+            frequency_penalty: args_init.frequency_penalty,
+            presence_penalty: args_init.presence_penalty,
+            logit_bias: BTreeMap::new(),
+            grammar: None,
+        }
+    }
+
+    /// Check the ranges of the parameters, the command line defaults as well as the resolved requests
+    pub fn validate(&self) -> Result<(), InvalidParameter> {
+        if !self.temperature.is_finite() || self.temperature < 0. {
+            return Err(invalid("temperature", "must be a finite number >= 0"));
+        }
+        if !(self.top_p > 0. && self.top_p <= 1.) {
+            return Err(invalid("top_p", "must be in the range (0, 1]"));
+        }
+        if !self.repeat_penalty.is_finite() || self.repeat_penalty <= 0. {
+            return Err(invalid("repeat_penalty", "must be a finite number > 0"));
+        }
+        if self.sample_len == 0 || self.sample_len > MAX_SAMPLE_LEN {
+            return Err(invalid("sample_len", format!("must be between 1 and {}", MAX_SAMPLE_LEN)));
+        }
+        if !(0. ..=1.).contains(&self.min_p) {
+            return Err(invalid("min_p", "must be in the range [0, 1]"));
+        }
+        if !(self.typical_p > 0. && self.typical_p <= 1.) {
+            return Err(invalid("typical_p", "must be in the range (0, 1]"));
+        }
+        if self.samplers.iter().enumerate().any(|(i, s)| self.samplers[..i].contains(s)) {
+            return Err(invalid("samplers", "each sampler can only appear once"));
+        }
+        if self.stop.len() > MAX_STOP_SEQUENCES {
+            return Err(invalid("stop", format!("at most {} stop sequences", MAX_STOP_SEQUENCES)));
+        }
+        if !(-MAX_PENALTY..=MAX_PENALTY).contains(&self.frequency_penalty) {
+            return Err(invalid("frequency_penalty", format!("must be in the range [-{0}, {0}]", MAX_PENALTY)));
+        }
+        if !(-MAX_PENALTY..=MAX_PENALTY).contains(&self.presence_penalty) {
+            return Err(invalid("presence_penalty", format!("must be in the range [-{0}, {0}]", MAX_PENALTY)));
+        }
+        if let Some(mirostat) = self.mirostat {
+            if !mirostat.tau.is_finite() || mirostat.tau < 0. {
+                return Err(invalid("mirostat_tau", "must be a finite number >= 0"));
+            }
+            if !(mirostat.eta > 0. && mirostat.eta <= 1.) {
+                return Err(invalid("mirostat_eta", "must be in the range (0, 1]"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Optional per request overrides of the sampling parameters
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SamplingOverrides {
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub top_p: Option<f64>,
+    #[serde(default)]
+    pub repeat_penalty: Option<f32>,
+    #[serde(default)]
+    pub repeat_last_n: Option<usize>,
+    #[serde(default)]
+    pub sample_len: Option<usize>,
+    #[serde(default)]
+    pub stop: Option<Vec<String>>,
+    /// In seconds
+    #[serde(default)]
+    pub max_time: Option<f64>,
+    #[serde(default)]
+    pub samplers: Option<Vec<SamplerKind>>,
+    #[serde(default)]
+    pub top_k: Option<usize>,
+    #[serde(default)]
+    pub min_p: Option<f64>,
+    #[serde(default)]
+    pub typical_p: Option<f64>,
+    /// 0 disables mirostat
+    #[serde(default)]
+    pub mirostat_tau: Option<f64>,
+    #[serde(default)]
+    pub mirostat_eta: Option<f64>,
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
+    /// Token ids, as strings like OpenAI clients send them, to bias
+    #[serde(default)]
+    pub logit_bias: Option<BTreeMap<String, f32>>,
+    /// GBNF grammar the answer must follow
+    #[serde(default)]
+    pub grammar: Option<String>,
+    /// JSON schema the answer must be valid against, converted to a grammar
+    #[serde(default)]
+    pub json_schema: Option<Value>,
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidParameter {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for InvalidParameter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid `{}`: {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for InvalidParameter {}
+
+impl SamplingOverrides {
+    /// Keep the parameters set here, take the missing ones from `fallback`
+    pub fn or(&self, fallback: &SamplingOverrides) -> SamplingOverrides {
+        // A grammar or a schema replaces both the grammar and the schema of the fallback
+        let constraint = if self.grammar.is_some() || self.json_schema.is_some() { self } else { fallback };
+        SamplingOverrides {
+            seed: self.seed.or(fallback.seed),
+            temperature: self.temperature.or(fallback.temperature),
+            top_p: self.top_p.or(fallback.top_p),
+            repeat_penalty: self.repeat_penalty.or(fallback.repeat_penalty),
+            repeat_last_n: self.repeat_last_n.or(fallback.repeat_last_n),
+            sample_len: self.sample_len.or(fallback.sample_len),
+            stop: self.stop.clone().or_else(|| fallback.stop.clone()),
+            max_time: self.max_time.or(fallback.max_time),
+            samplers: self.samplers.clone().or_else(|| fallback.samplers.clone()),
+            top_k: self.top_k.or(fallback.top_k),
+            min_p: self.min_p.or(fallback.min_p),
+            typical_p: self.typical_p.or(fallback.typical_p),
+            mirostat_tau: self.mirostat_tau.or(fallback.mirostat_tau),
+            mirostat_eta: self.mirostat_eta.or(fallback.mirostat_eta),
+            frequency_penalty: self.frequency_penalty.or(fallback.frequency_penalty),
+            presence_penalty: self.presence_penalty.or(fallback.presence_penalty),
+            logit_bias: self.logit_bias.clone().or_else(|| fallback.logit_bias.clone()),
+            grammar: constraint.grammar.clone(),
+            json_schema: constraint.json_schema.clone(),
+        }
+    }
+
+    /// Apply the overrides on top of the defaults, and validate the result
+    pub fn resolve(&self, defaults: &SamplingParams) -> Result<SamplingParams, InvalidParameter> {
+        let params = SamplingParams {
+            seed: self.seed.unwrap_or(defaults.seed),
+            temperature: self.temperature.unwrap_or(defaults.temperature),
+            top_p: self.top_p.unwrap_or(defaults.top_p),
+            repeat_penalty: self.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+            repeat_last_n: self.repeat_last_n.unwrap_or(defaults.repeat_last_n),
+            sample_len: self.sample_len.unwrap_or(defaults.sample_len),
+            stop: match &self.stop {
+                Some(stop) => stop.iter().filter(|s| !s.is_empty()).cloned().collect(),
+                None => defaults.stop.clone(),
+            },
+            max_time: match self.max_time {
+                Some(secs) if !secs.is_finite() || secs <= 0. => {
+                    return Err(invalid("max_time", "must be a number of seconds > 0"));
+                }
+                Some(secs) => Some(Duration::from_secs_f64(secs)),
+                None => defaults.max_time,
+            },
+            samplers: self.samplers.clone().unwrap_or_else(|| defaults.samplers.clone()),
+            top_k: self.top_k.unwrap_or(defaults.top_k),
+            min_p: self.min_p.unwrap_or(defaults.min_p),
+            typical_p: self.typical_p.unwrap_or(defaults.typical_p),
+            mirostat: match (self.mirostat_tau, self.mirostat_eta) {
+                (None, None) => defaults.mirostat,
+                (tau, eta) => {
+                    let tau = tau.or(defaults.mirostat.map(|m| m.tau)).unwrap_or_default();
+                    let eta = eta.or(defaults.mirostat.map(|m| m.eta)).unwrap_or(DEFAULT_MIROSTAT_ETA);
+                    if !tau.is_finite() || tau < 0. {
+                        return Err(invalid("mirostat_tau", "must be a finite number >= 0"));
+                    }
+                    if !(eta > 0. && eta <= 1.) {
+                        return Err(invalid("mirostat_eta", "must be in the range (0, 1]"));
+                    }
+                    Some(Mirostat { tau, eta }).filter(|m| m.tau > 0.)
+                }
+            },
+            frequency_penalty: self.frequency_penalty.unwrap_or(defaults.frequency_penalty),
+            presence_penalty: self.presence_penalty.unwrap_or(defaults.presence_penalty),
+            logit_bias: match &self.logit_bias {
+                Some(logit_bias) => parse_logit_bias(logit_bias)?,
+                None => defaults.logit_bias.clone(),
+            },
+            grammar: match (&self.grammar, &self.json_schema) {
+                (Some(_), Some(_)) => return Err(invalid("grammar", "grammar and json_schema cannot be used together")),
+                (Some(grammar), None) => Some(Arc::new(Grammar::parse(grammar).map_err(|e| invalid("grammar", e.to_string()))?)),
+                (None, Some(schema)) => {
+                    let grammar = json_schema_to_gbnf(schema)
+                        .and_then(|grammar| Grammar::parse(grammar.as_str()))
+                        .map_err(|e| invalid("json_schema", e.to_string()))?;
+                    Some(Arc::new(grammar))
+                }
+                (None, None) => defaults.grammar.clone(),
+            },
+        };
+
+        params.validate()?;
+        Ok(params)
+    }
+}
+
+/// Keys are token ids, values are in [-MAX_LOGIT_BIAS, MAX_LOGIT_BIAS]
+fn parse_logit_bias(logit_bias: &BTreeMap<String, f32>) -> Result<BTreeMap<u32, f32>, InvalidParameter> {
+    logit_bias
+        .iter()
+        .map(|(token, bias)| {
+            let token = token
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| invalid("logit_bias", format!("`{}` is not a token id", token)))?;
+            if !(-MAX_LOGIT_BIAS..=MAX_LOGIT_BIAS).contains(bias) {
+                return Err(invalid("logit_bias", format!("biases must be in the range [-{0}, {0}]", MAX_LOGIT_BIAS)));
+            }
+            Ok((token, *bias))
+        })
+        .collect()
+}
+
+fn invalid(field: &'static str, message: impl Into<String>) -> InvalidParameter {
+    InvalidParameter {
+        field,
+        message: message.into(),
+    }
+}
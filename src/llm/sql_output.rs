@@ -0,0 +1,370 @@
+use std::collections::BTreeMap;
+use std::ops::ControlFlow;
+
+use serde::Serialize;
+use sqlparser::ast::{AssignmentTarget, Expr, Ident, ObjectName, Query, SelectItem, SetExpr, Statement, TableFactor, Visit, Visitor};
+use sqlparser::dialect::{dialect_from_str, Dialect, MySqlDialect};
+use sqlparser::parser::Parser;
+
+use crate::llm::sampling_params::InvalidParameter;
+
+/// Dialect used when a schema is given to a profile which does not name one
+pub const DEFAULT_SQL_DIALECT: &str = "mysql";
+
+/// Statements looked for in the answer when the query is not in a code block
+const STATEMENT_KEYWORDS: [&str; 10] = ["select", "with", "insert", "update", "delete", "create", "alter", "drop", "replace", "show"];
+
+/// Columns of each table, by table name
+pub type SqlSchema = BTreeMap<String, Vec<String>>;
+
+pub fn is_sql_dialect(name: &str) -> bool {
+    dialect_from_str(name).is_some()
+}
+
+/// Query found in the answer, and why it does not fit the dialect or the schema
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SqlOutput {
+    pub query: Option<String>,
+    pub dialect: String,
+    /// The query parses, and only refers to the tables and columns of the schema
+    pub valid: bool,
+    pub diagnostics: Vec<String>,
+}
+
+/// Dialect and schema the answer of a request is checked against
+#[derive(Debug, Clone)]
+pub struct SqlCheck {
+    dialect: String,
+    schema: Option<SqlSchema>,
+}
+
+impl SqlCheck {
+    /// Answers are only checked when the profile names a dialect or the request gives a schema
+    pub fn new(dialect: Option<&str>, schema: Option<SqlSchema>) -> Result<Option<Self>, InvalidParameter> {
+        if dialect.is_none() && schema.is_none() {
+            return Ok(None);
+        }
+        if let Some(schema) = &schema {
+            if schema.is_empty() || schema.values().any(|columns| columns.is_empty()) {
+                return Err(InvalidParameter {
+                    field: "sql_schema",
+                    message: "must list tables along with their columns".to_string(),
+                });
+            }
+        }
+        Ok(Some(Self {
+            dialect: dialect.unwrap_or(DEFAULT_SQL_DIALECT).to_lowercase(),
+            schema,
+        }))
+    }
+
+    pub fn check(&self, answer: &str) -> SqlOutput {
+        let dialect = dialect_from_str(self.dialect.as_str()).unwrap_or_else(|| Box::new(MySqlDialect {}));
+        let mut output = SqlOutput {
+            query: extract_query(answer, dialect.as_ref()),
+            dialect: self.dialect.clone(),
+            valid: false,
+            diagnostics: Vec::new(),
+        };
+
+        match &output.query {
+            None => output.diagnostics.push("no sql query found in the answer".to_string()),
+            Some(query) => match Parser::parse_sql(dialect.as_ref(), query) {
+                Ok(statements) if statements.is_empty() => output.diagnostics.push("the query is empty".to_string()),
+                Ok(statements) => {
+                    if let Some(schema) = &self.schema {
+                        for statement in &statements {
+                            output.diagnostics.extend(check_references(statement, schema));
+                        }
+                    }
+                }
+                Err(e) => output.diagnostics.push(e.to_string()),
+            },
+        }
+        output.valid = output.diagnostics.is_empty();
+        output
+    }
+}
+
+/// Query of the answer : the content of the first code block, or else the first text
+/// starting with a statement keyword which parses, up to the next `;`
+pub fn extract_query(answer: &str, dialect: &dyn Dialect) -> Option<String> {
+    if let Some((_, rest)) = answer.split_once("```") {
+        // Leave out the language of the block, alone on the first line
+        let rest = match rest.split_once('\n') {
+            Some((info, body)) if is_info_string(info.trim()) => body,
+            _ => rest,
+        };
+        let block = rest.split("```").next().unwrap_or_default().trim();
+        if !block.is_empty() {
+            return Some(block.to_string());
+        }
+    }
+
+    let candidates = statement_starts(answer)
+        .map(|start| {
+            let text = &answer[start..];
+            text[..text.find(';').unwrap_or(text.len())].trim()
+        })
+        .collect::<Vec<_>>();
+    candidates
+        .iter()
+        .find(|candidate| Parser::parse_sql(dialect, candidate).is_ok())
+        .or(candidates.first())
+        .map(|candidate| candidate.to_string())
+}
+
+/// Language of a code block, e.g. `sql` or `mysql`, rather than the start of the query
+fn is_info_string(text: &str) -> bool {
+    text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        && !STATEMENT_KEYWORDS.iter().any(|keyword| text.eq_ignore_ascii_case(keyword))
+}
+
+/// Positions of the statement keywords starting a word
+fn statement_starts(text: &str) -> impl Iterator<Item = usize> + '_ {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    text.char_indices()
+        .map(|(i, _)| i)
+        .filter(move |i| !text[..*i].ends_with(is_word))
+        .filter(move |i| {
+            let rest = &text[*i..];
+            STATEMENT_KEYWORDS.iter().any(|keyword| {
+                rest.get(..keyword.len()).is_some_and(|word| word.eq_ignore_ascii_case(keyword))
+                    && !rest[keyword.len()..].starts_with(is_word)
+            })
+        })
+}
+
+fn lower(ident: &Ident) -> String {
+    ident.value.to_lowercase()
+}
+
+/// Table name, without its database
+fn table_name(name: &ObjectName) -> String {
+    name.0.last().map(lower).unwrap_or_default()
+}
+
+/// Tables and columns a statement refers to
+#[derive(Default)]
+struct References {
+    tables: Vec<String>,
+    /// Table aliases, subqueries and common table expressions have no table
+    aliases: BTreeMap<String, Option<String>>,
+    /// Names given to the selected expressions
+    select_aliases: Vec<String>,
+    /// Columns, along with their table or alias when qualified
+    columns: Vec<(Option<String>, String)>,
+}
+
+impl Visitor for References {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<()> {
+        if let Some(with) = &query.with {
+            for cte in &with.cte_tables {
+                self.aliases.insert(lower(&cte.alias.name), None);
+            }
+        }
+        if let SetExpr::Select(select) = query.body.as_ref() {
+            for item in &select.projection {
+                if let SelectItem::ExprWithAlias { alias, .. } = item {
+                    self.select_aliases.push(lower(alias));
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_relation(&mut self, relation: &ObjectName) -> ControlFlow<()> {
+        self.tables.push(table_name(relation));
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_table_factor(&mut self, table_factor: &TableFactor) -> ControlFlow<()> {
+        match table_factor {
+            TableFactor::Table { name, alias: Some(alias), .. } => {
+                self.aliases.insert(lower(&alias.name), Some(table_name(name)));
+            }
+            TableFactor::Derived { alias: Some(alias), .. } => {
+                self.aliases.insert(lower(&alias.name), None);
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        match expr {
+            Expr::Identifier(ident) => self.columns.push((None, lower(ident))),
+            Expr::CompoundIdentifier(idents) if idents.len() >= 2 => {
+                let qualifier = lower(&idents[idents.len() - 2]);
+                self.columns.push((Some(qualifier), lower(&idents[idents.len() - 1])));
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_statement(&mut self, statement: &Statement) -> ControlFlow<()> {
+        match statement {
+            Statement::Insert(insert) => {
+                let table = table_name(&insert.table_name);
+                for column in &insert.columns {
+                    self.columns.push((Some(table.clone()), lower(column)));
+                }
+            }
+            Statement::Update { assignments, .. } => {
+                for assignment in assignments {
+                    let names = match &assignment.target {
+                        AssignmentTarget::ColumnName(name) => std::slice::from_ref(name),
+                        AssignmentTarget::Tuple(names) => names.as_slice(),
+                    };
+                    for name in names {
+                        self.columns.push((None, table_name(name)));
+                    }
+                }
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// Tables and columns of the statement missing from the schema, names are compared ignoring the case
+fn check_references(statement: &Statement, schema: &SqlSchema) -> Vec<String> {
+    // Tables created, altered or dropped by the statement are not expected in the schema
+    if !matches!(statement, Statement::Query(_) | Statement::Insert(_) | Statement::Update { .. } | Statement::Delete(_)) {
+        return Vec::new();
+    }
+
+    let schema = schema
+        .iter()
+        .map(|(table, columns)| (table.to_lowercase(), columns.iter().map(|c| c.to_lowercase()).collect::<Vec<_>>()))
+        .collect::<BTreeMap<_, _>>();
+    let mut references = References::default();
+    let _ = statement.visit(&mut references);
+
+    let mut diagnostics = Vec::new();
+    let mut report = |diagnostic: String| {
+        if !diagnostics.contains(&diagnostic) {
+            diagnostics.push(diagnostic);
+        }
+    };
+
+    // Unqualified columns can only be checked when all the tables are known
+    let mut all_known = references.aliases.values().all(Option::is_some);
+    let mut tables = Vec::new();
+    for table in &references.tables {
+        if references.aliases.get(table).is_some_and(Option::is_none) {
+            continue;
+        }
+        if !schema.contains_key(table) {
+            report(format!("unknown table `{}`", table));
+            all_known = false;
+        } else if !tables.contains(table) {
+            tables.push(table.clone());
+        }
+    }
+
+    for (qualifier, column) in &references.columns {
+        let known = match qualifier {
+            Some(qualifier) => {
+                let table = match references.aliases.get(qualifier) {
+                    Some(table) => table.as_ref(),
+                    None => Some(qualifier),
+                };
+                table.and_then(|table| schema.get(table)).map(|columns| columns.contains(column))
+            }
+            None if references.select_aliases.contains(column) => None,
+            None if !all_known || tables.is_empty() => None,
+            None => Some(tables.iter().any(|table| schema[table].contains(column))),
+        };
+        if known == Some(false) {
+            match qualifier {
+                Some(qualifier) => report(format!("unknown column `{}.{}`", qualifier, column)),
+                None => report(format!("unknown column `{}`", column)),
+            }
+        }
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extract(answer: &str) -> Option<String> {
+        extract_query(answer, &MySqlDialect {})
+    }
+
+    fn schema() -> SqlSchema {
+        SqlSchema::from([
+            ("product".to_string(), vec!["productId".to_string(), "name".to_string(), "price".to_string()]),
+            ("orders".to_string(), vec!["orderId".to_string(), "productId".to_string()]),
+        ])
+    }
+
+    fn diagnostics(query: &str) -> Vec<String> {
+        let statements = Parser::parse_sql(&MySqlDialect {}, query).unwrap();
+        statements.iter().flat_map(|statement| check_references(statement, &schema())).collect()
+    }
+
+    #[test]
+    fn query_of_a_code_block() {
+        assert_eq!(extract("Here it is :\n```sql\nSELECT a FROM t;\n```\nDone").as_deref(), Some("SELECT a FROM t;"));
+        assert_eq!(extract("```\nSELECT a FROM t\n```").as_deref(), Some("SELECT a FROM t"));
+    }
+
+    #[test]
+    fn code_block_without_language_keeps_its_first_word() {
+        assert_eq!(extract("```SELECT a FROM t```").as_deref(), Some("SELECT a FROM t"));
+        assert_eq!(extract("```select\na FROM t```").as_deref(), Some("select\na FROM t"));
+    }
+
+    #[test]
+    fn query_in_the_text() {
+        let answer = "To select the names, use SELECT name FROM product WHERE price > 10; it is fast.";
+        assert_eq!(extract(answer).as_deref(), Some("SELECT name FROM product WHERE price > 10"));
+        assert_eq!(extract("No query here"), None);
+    }
+
+    #[test]
+    fn unknown_tables_and_columns_are_reported() {
+        assert!(diagnostics("SELECT name FROM product WHERE price > 10").is_empty());
+        assert_eq!(diagnostics("SELECT name FROM customer"), vec!["unknown table `customer`"]);
+        assert_eq!(diagnostics("SELECT colour FROM product"), vec!["unknown column `colour`"]);
+    }
+
+    #[test]
+    fn names_are_compared_ignoring_the_case() {
+        assert!(diagnostics("SELECT PRODUCTID, Name FROM Product").is_empty());
+    }
+
+    #[test]
+    fn aliases_and_joins() {
+        assert!(diagnostics("SELECT p.name, o.orderId FROM product p JOIN orders o ON o.productId = p.productId").is_empty());
+        assert_eq!(diagnostics("SELECT p.colour FROM product p"), vec!["unknown column `p.colour`"]);
+        assert!(diagnostics("SELECT price * 2 AS doubled FROM product ORDER BY doubled").is_empty());
+    }
+
+    #[test]
+    fn insert_and_update_columns() {
+        assert!(diagnostics("INSERT INTO product (productId, name) VALUES (1, 'a')").is_empty());
+        assert_eq!(diagnostics("UPDATE product SET colour = 'red'"), vec!["unknown column `colour`"]);
+    }
+
+    #[test]
+    fn created_tables_are_not_checked() {
+        assert!(diagnostics("CREATE TABLE customer (id INT)").is_empty());
+    }
+
+    #[test]
+    fn check_reports_parse_errors() {
+        let check = SqlCheck::new(Some("mysql"), Some(schema())).unwrap().unwrap();
+        let output = check.check("```sql\nSELECT name FROM product\n```");
+        assert!(output.valid, "{:?}", output);
+        let output = check.check("```sql\nSELECT name FROM WHERE\n```");
+        assert!(!output.valid);
+        assert_eq!(output.diagnostics.len(), 1);
+    }
+}
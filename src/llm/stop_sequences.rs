@@ -0,0 +1,145 @@
+/// Watches the streamed text for stop sequences. Text that could be the
+/// beginning of a stop sequence is held back until it can be decided.
+#[derive(Debug, Clone, Default)]
+pub struct StopMatcher {
+    stop: Vec<String>,
+    pending: String,
+    matched: Option<String>,
+}
+
+impl StopMatcher {
+    pub fn new(stop: Vec<String>) -> Self {
+        Self {
+            stop: stop.into_iter().filter(|s| !s.is_empty()).collect(),
+            pending: String::new(),
+            matched: None,
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.matched.is_some()
+    }
+
+    /// Stop sequence found in the text, if any
+    pub fn matched(&self) -> Option<&str> {
+        self.matched.as_deref()
+    }
+
+    /// Push a new chunk of text, returns the text that can safely be emitted.
+    /// The stop sequence itself and whatever follows it are never emitted.
+    pub fn push(&mut self, text: &str) -> String {
+        if self.is_stopped() {
+            return String::new();
+        }
+        if self.stop.is_empty() {
+            return text.to_string();
+        }
+        self.pending.push_str(text);
+
+        // Earliest complete stop sequence wins
+        let earliest = self
+            .stop
+            .iter()
+            .filter_map(|s| self.pending.find(s.as_str()).map(|pos| (pos, s)))
+            .min_by_key(|(pos, _)| *pos);
+        if let Some((pos, stop)) = earliest {
+            self.matched = Some(stop.clone());
+            let emitted = self.pending[..pos].to_string();
+            self.pending.clear();
+            return emitted;
+        }
+
+        // Hold back the longest suffix which is a prefix of a stop sequence
+        let hold = self
+            .stop
+            .iter()
+            .map(|s| longest_suffix_prefix(self.pending.as_str(), s.as_str()))
+            .max()
+            .unwrap_or(0);
+        let split = self.pending.len() - hold;
+        let emitted = self.pending[..split].to_string();
+        self.pending.drain(..split);
+        emitted
+    }
+
+    /// Release whatever is held back at the end of the generation
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Forget the text seen so far, keep the stop sequences
+    pub fn reset(&mut self) {
+        self.pending.clear();
+        self.matched = None;
+    }
+}
+
+fn longest_suffix_prefix(text: &str, stop: &str) -> usize {
+    (1..stop.len().min(text.len() + 1))
+        .rev()
+        .find(|&n| stop.is_char_boundary(n) && text.ends_with(&stop[..n]))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Text emitted for each chunk, and the text released at the end
+    fn run(stop: &[&str], chunks: &[&str]) -> (Vec<String>, String, Option<String>) {
+        let mut matcher = StopMatcher::new(stop.iter().map(|s| s.to_string()).collect());
+        let emitted = chunks.iter().map(|chunk| matcher.push(chunk)).collect();
+        let matched = matcher.matched().map(str::to_string);
+        let rest = if matcher.is_stopped() { String::new() } else { matcher.flush() };
+        (emitted, rest, matched)
+    }
+
+    #[test]
+    fn text_without_stop_sequences_is_emitted_as_is() {
+        let (emitted, rest, matched) = run(&[], &["SELECT", ";", "\n\n"]);
+        assert_eq!(emitted, vec!["SELECT", ";", "\n\n"]);
+        assert_eq!(rest, "");
+        assert_eq!(matched, None);
+    }
+
+    #[test]
+    fn stop_sequence_of_punctuation_and_spaces() {
+        let (emitted, rest, matched) = run(&[";\n\n"], &["SELECT a FROM t", ";", "\n", "\nmore"]);
+        assert_eq!(emitted, vec!["SELECT a FROM t", "", "", ""]);
+        assert_eq!(rest, "");
+        assert_eq!(matched.as_deref(), Some(";\n\n"));
+    }
+
+    #[test]
+    fn stop_sequence_inside_a_chunk() {
+        let (emitted, _, matched) = run(&["```"], &["SELECT 1```\nafter"]);
+        assert_eq!(emitted, vec!["SELECT 1"]);
+        assert_eq!(matched.as_deref(), Some("```"));
+    }
+
+    #[test]
+    fn held_back_text_is_released_when_it_does_not_match() {
+        let (emitted, rest, matched) = run(&[";\n\n"], &["a", ";", "\n", "b", ";"]);
+        assert_eq!(emitted, vec!["a", "", "", ";\nb", ""]);
+        assert_eq!(rest, ";");
+        assert_eq!(matched, None);
+    }
+
+    #[test]
+    fn earliest_stop_sequence_wins() {
+        let (emitted, _, matched) = run(&["\n\n", "```"], &["a```b\n\n"]);
+        assert_eq!(emitted, vec!["a"]);
+        assert_eq!(matched.as_deref(), Some("```"));
+    }
+
+    #[test]
+    fn reset_forgets_the_match() {
+        let mut matcher = StopMatcher::new(vec!["```".to_string()]);
+        assert_eq!(matcher.push("a``"), "a");
+        matcher.reset();
+        assert_eq!(matcher.push("b"), "b");
+        assert_eq!(matcher.matched(), None);
+        assert_eq!(matcher.push("```"), "");
+        assert!(matcher.is_stopped());
+    }
+}
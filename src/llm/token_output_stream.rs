@@ -1,12 +1,20 @@
 use candle::Result;
 
+use crate::llm::stop_sequences::StopMatcher;
+
 /// This is a wrapper around a tokenizer to ensure that tokens can be returned to the user in a
 /// streaming way rather than having to wait for the full decoding.
+/// Text is cut before the first stop sequence, even when it spans several tokens.
 pub struct TokenOutputStream {
     tokenizer: tokenizers::Tokenizer,
     tokens: Vec<u32>,
     prev_index: usize,
     current_index: usize,
+    stop_matcher: StopMatcher,
+    /// Bytes of the text not returned yet which went through the stop matcher
+    checked_len: usize,
+    /// Text let through by the stop matcher, returned along with the next token
+    ready: String,
 }
 
 impl TokenOutputStream {
@@ -16,9 +24,21 @@ impl TokenOutputStream {
             tokens: Vec::new(),
             prev_index: 0,
             current_index: 0,
+            stop_matcher: StopMatcher::default(),
+            checked_len: 0,
+            ready: String::new(),
         }
     }
 
+    pub fn set_stop_sequences(&mut self, stop: Vec<String>) {
+        self.stop_matcher = StopMatcher::new(stop);
+    }
+
+    /// Stop sequence found in the generated text, no more text is returned once there is one
+    pub fn stop_sequence(&self) -> Option<&str> {
+        self.stop_matcher.matched()
+    }
+
     pub fn into_inner(self) -> tokenizers::Tokenizer {
         self.tokenizer
     }
@@ -40,17 +60,47 @@ impl TokenOutputStream {
         };
         self.tokens.push(token);
         let text = self.decode(&self.tokens[self.prev_index..])?;
-        if text.len() > prev_text.len() && text.chars().last().unwrap().is_alphanumeric() {
-            let text = text.split_at(prev_text.len());
+
+        // Every token goes through the stop matcher, even when its text is held back below,
+        // so that stop sequences made of punctuation or spaces end the generation at once.
+        // Incomplete utf-8 characters wait for the next token.
+        let delta = text.get(prev_text.len()..).unwrap_or_default();
+        if !delta.ends_with('\u{FFFD}') {
+            self.check(delta);
+        }
+
+        if self.stop_matcher.is_stopped() || (text.len() > prev_text.len() && text.chars().last().unwrap().is_alphanumeric()) {
             self.prev_index = self.current_index;
             self.current_index = self.tokens.len();
-            Ok(Some(text.1.to_string()))
+            self.checked_len = 0;
+            Ok(Some(std::mem::take(&mut self.ready)).filter(|text| !text.is_empty()))
         } else {
             Ok(None)
         }
     }
 
-    pub fn decode_rest(&self) -> Result<Option<String>> {
+    /// Run the part of `delta` not seen yet through the stop matcher
+    fn check(&mut self, delta: &str) {
+        if let Some(unchecked) = delta.get(self.checked_len..) {
+            let emitted = self.stop_matcher.push(unchecked);
+            self.ready.push_str(emitted.as_str());
+            self.checked_len = delta.len();
+        }
+    }
+
+    /// Text not returned yet, to be sent once generation is over
+    pub fn decode_rest(&mut self) -> Result<Option<String>> {
+        if let Some(text) = self.decode_tail()? {
+            self.check(text.as_str());
+        }
+        let mut rest = std::mem::take(&mut self.ready);
+        if !self.stop_matcher.is_stopped() {
+            rest.push_str(self.stop_matcher.flush().as_str());
+        }
+        Ok(Some(rest).filter(|rest| !rest.is_empty()))
+    }
+
+    fn decode_tail(&self) -> Result<Option<String>> {
         let prev_text = if self.tokens.is_empty() {
             String::new()
         } else {
@@ -82,5 +132,102 @@ impl TokenOutputStream {
         self.tokens.clear();
         self.prev_index = 0;
         self.current_index = 0;
+        self.checked_len = 0;
+        self.ready.clear();
+        self.stop_matcher.reset();
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    const VOCABULARY: [&str; 10] = ["<unk>", "SELECT", " a", " FROM", " t", ";", "\n", "\n\n", "```", " more"];
+
+    /// Word level tokenizer whose tokens are decoded back to back
+    fn tokenizer() -> tokenizers::Tokenizer {
+        let vocab = VOCABULARY.iter().enumerate().map(|(i, token)| (token.to_string(), serde_json::Value::from(i))).collect::<serde_json::Map<_, _>>();
+        let json = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": null,
+            "post_processor": null,
+            "decoder": { "type": "Fuse" },
+            "model": { "type": "WordLevel", "vocab": vocab, "unk_token": "<unk>" }
+        });
+        tokenizers::Tokenizer::from_str(json.to_string().as_str()).unwrap()
+    }
+
+    fn token(text: &str) -> u32 {
+        VOCABULARY.iter().position(|token| *token == text).unwrap() as u32
+    }
+
+    /// Feed the tokens until a stop sequence is found, returns the text emitted and the number of tokens fed
+    fn generate(stop: &[&str], tokens: &[&str]) -> (String, usize, Option<String>) {
+        let mut stream = TokenOutputStream::new(tokenizer());
+        stream.set_stop_sequences(stop.iter().map(|s| s.to_string()).collect());
+        let mut text = String::new();
+        let mut fed = 0;
+        for t in tokens {
+            fed += 1;
+            if let Some(emitted) = stream.next_token(token(t)).unwrap() {
+                text.push_str(emitted.as_str());
+            }
+            if stream.stop_sequence().is_some() {
+                break;
+            }
+        }
+        if let Some(rest) = stream.decode_rest().unwrap() {
+            text.push_str(rest.as_str());
+        }
+        (text, fed, stream.stop_sequence().map(str::to_string))
+    }
+
+    #[test]
+    fn stop_sequence_of_punctuation_ends_on_the_token_completing_it() {
+        let tokens = ["SELECT", " a", " FROM", " t", ";", "\n\n", " more", " more"];
+        let (text, fed, stop) = generate(&[";\n\n"], &tokens);
+        assert_eq!(text, "SELECT a FROM t");
+        assert_eq!(fed, 6);
+        assert_eq!(stop.as_deref(), Some(";\n\n"));
+    }
+
+    #[test]
+    fn stop_sequence_spanning_non_alphanumeric_tokens() {
+        let tokens = ["SELECT", " a", ";", "\n", "\n", " more"];
+        let (text, fed, stop) = generate(&[";\n\n"], &tokens);
+        assert_eq!(text, "SELECT a");
+        assert_eq!(fed, 5);
+        assert_eq!(stop.as_deref(), Some(";\n\n"));
+    }
+
+    #[test]
+    fn code_fence_stop_sequence() {
+        let tokens = ["SELECT", " a", "```", " more"];
+        let (text, fed, stop) = generate(&["```"], &tokens);
+        assert_eq!(text, "SELECT a");
+        assert_eq!(fed, 3);
+        assert_eq!(stop.as_deref(), Some("```"));
+    }
+
+    #[test]
+    fn trailing_punctuation_is_returned_at_the_end() {
+        let tokens = ["SELECT", " a", ";", "\n"];
+        let (text, fed, stop) = generate(&[";\n\n"], &tokens);
+        assert_eq!(text, "SELECT a;\n");
+        assert_eq!(fed, 4);
+        assert_eq!(stop, None);
+    }
+
+    #[test]
+    fn text_is_unchanged_without_stop_sequences() {
+        let tokens = ["SELECT", " a", ";", "\n\n", " more"];
+        let (text, _, stop) = generate(&[], &tokens);
+        assert_eq!(text, "SELECT a;\n\n more");
+        assert_eq!(stop, None);
+    }
+}
@@ -5,67 +5,75 @@ extern crate intel_mkl_src;
 extern crate accelerate_src;
 
 use std::convert::Infallible;
+use std::pin::Pin;
 use warp::{Filter};
 
 use tokio_stream::wrappers::{UnboundedReceiverStream};
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::{ UnboundedReceiver, UnboundedSender};
 
 use std::{fs, thread};
 use std::process::exit;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use bytes::{Bytes};
 
 use futures_util::{Stream, StreamExt};
 use hyper::Body;
+use hyper::header::HeaderValue;
+use warp::Reply;
 use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+use llm_stream::args_init::args::{Args, Command};
+use llm_stream::batch::batch_runner::run_batch;
+use llm_stream::chat::chat_repl::run_chat;
+use llm_stream::api::api_error::ApiError;
+use llm_stream::api::openai::{chunk_stream, collect_completion, ChatCompletionRequest, ChatMessage};
+use llm_stream::api::sessions::{record_reply, SessionSettings, SessionStore};
+use llm_stream::api::token_events::token_event_stream;
+use llm_stream::api::generation_response::collect_generation;
+use llm_stream::llm::sampling_params::{InvalidParameter, SamplingOverrides, SamplingParams};
+use llm_stream::llm::sql_output::{SqlCheck, SqlSchema};
+use llm_stream::llm::engine::initialize_engine;
+use llm_stream::prompt_config::prompt_config_watcher::{watch_prompt_config, PromptProfilesStore};
+use llm_stream::scheduler::generation_scheduler::{GenerationScheduler, QueueState};
+use llm_stream::server_config::server_settings::{bind_unix_socket, check_admin_token, Listener, ServerSettings};
+use llm_stream::scheduler::generation_task::{ClassificationOutcome, GenerationChannels, GenerationService};
+use llm_stream::api::websocket::handle_socket;
 
-use toml;
 
-use llm_stream::args_init::args::Args;
-
-
-#[cfg(not(feature = "llama"))]
-use llm_stream::llm::llm::{LLM, LlmPackage,generate};
-
-#[cfg(feature = "llama")]
-use llm_stream::llm::quantized_llm::{QuantizedLLM,QuantizedLlmPackage,generate};
-
-#[cfg(feature = "llama")]
-use llm_stream::llm::llama_llm::llama_initialization::QuantizedLlmModel;
-
-
-// todo: to be put under feature
-#[cfg(feature = "mistral")]
-use llm_stream::llm::mistral_llm::mistral_initialization::{LlmModel};
-
-#[cfg(feature = "phi-v2")]
-use llm_stream::llm::phi_v2_llm::phi_v2_initialization::{LlmModel};
 
+const NB_WORKERS:usize = 4;
 
+/// Body of a streamed response
+type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, Infallible>> + Send>>;
 
-const NB_WORKERS:usize = 4;
 
 #[derive(Serialize, Deserialize, Debug,Clone)]
 pub struct Prompt {
     pub query: String,
+    /// Prompt profile, the one given by `--context-type` when omitted
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Tables and columns the query written by a sql profile may refer to
+    #[serde(default)]
+    pub sql_schema: Option<SqlSchema>,
+    /// Answer with a single JSON object once the generation ends when false
+    #[serde(default)]
+    pub stream: Option<bool>,
+    /// Optional overrides of the command line sampling parameters
+    #[serde(flatten)]
+    pub sampling: SamplingOverrides,
 }
 
-
-// Top level struct to hold the TOML data.
-#[derive(Deserialize)]
-struct Data {
-    prompt_config: Config,
-}
-
-// Config struct holds to data from the `[config]` section.
-#[derive(Deserialize)]
-struct Config {
-    context_general: String,
-    context_classifier: String,
-    context_sql: String,
-    context_math: String,
+#[derive(Serialize, Deserialize, Debug,Clone)]
+pub struct ClassifyRequest {
+    pub text: String,
+    /// Candidate answers, scored by the model
+    pub labels: Vec<String>,
+    /// Prompt profile, the one given by `--context-type` when omitted
+    #[serde(default)]
+    pub profile: Option<String>,
 }
 
 #[tokio::main]
@@ -98,28 +106,75 @@ async fn main() ->anyhow::Result<()> {
     /**************************************************************/
     // Initialization Chain
     /**************************************************************/
-    let args_init=Args::new();
+    let mut args_init=Args::new();
+    let model_id=args_init.model_id.clone();
+    let command=args_init.command.take();
+
+    // Checked before the model is loaded
+    let (listener,admin_token)=load_listener(&args_init);
+    let default_sampling=load_default_sampling(&args_init);
+
+    /**************************************************************/
+    // Scheduler in front of the generations
+    /**************************************************************/
+    // A batch runs as many generations as it keeps requests in flight
+    let (max_concurrent_generations,max_queue_size)=match &command {
+        Some(Command::Batch(batch_args)) => (batch_args.parallelism.max(1),batch_args.parallelism.max(1)),
+        Some(Command::Chat(_)) | None => (args_init.max_concurrent_generations,args_init.max_queue_size),
+    };
+    let scheduler=GenerationScheduler::new(
+        max_concurrent_generations,
+        max_queue_size,
+        args_init.queue_retry_after,
+    );
+
+    // Conversations kept on the server
+    let sessions=Arc::new(SessionStore::new(args_init.max_sessions,Duration::from_secs(args_init.session_idle_timeout)));
 
     /**************************************************************/
-    // Initialize context for the interaction
+    // Initialize prompt profiles ( context and sampling defaults ) for the interaction
     /**************************************************************/
     let config_file_name=  "./config/prompt_config.toml";
-    let context=get_prompt_context(config_file_name,args_init.context_type.clone()).to_lowercase();
+    let prompt_profiles=Arc::new(load_prompt_profiles(config_file_name,args_init.context_type.as_str(),default_sampling));
+
+    // Changes of the file, SIGHUP or the admin route swap in the new profiles
+    if let Err(e) = watch_prompt_config(prompt_profiles.clone()) {
+        eprintln!("Unable to watch `{}`, reload it with /admin/reload_prompt_config: {:#}", config_file_name, e);
+    }
 
     /**************************************************************/
-    // Model Selection Chain
+    // Model Selection and Initialization llm model
     /**************************************************************/
-    #[cfg(not(feature = "llama"))]
-    let llm_initialize: Box<dyn LLM>=   Box::new(LlmModel);
+    // Retrieve llm engine : Model, Device, Tokenizer for the selected model family
+    let llm_engine=initialize_engine(args_init).unwrap();
+
+    let model_name=model_id.unwrap_or_else(|| llm_engine.model_family().default_model_id().to_string());
+
+    // Process the context of each profile once, requests start from its kv cache
+    for (name,profile) in prompt_profiles.current().iter() {
+        let start = std::time::Instant::now();
+        match llm_engine.warm_prefix_cache(profile.context.to_lowercase().as_str()) {
+            Ok(()) => println!("prefix cache warmed for profile {} in {:?}", name, start.elapsed()),
+            Err(e) => eprintln!("Unable to warm the prefix cache for profile {}: {}", name, e),
+        }
+    }
 
-    #[cfg(feature = "llama")]
-        let llm_initialize: Box<dyn QuantizedLLM>=   Box::new(QuantizedLlmModel);
+    // Shared by all the generation routes
+    let generation_service=GenerationService {
+        dedicated_runtime,
+        scheduler,
+        llm_engine:llm_engine.clone(),
+        prompt_profiles:prompt_profiles.clone(),
+    };
 
     /**************************************************************/
-    // Initialization llm model
+    // Batch and chat modes run without the HTTP server
     /**************************************************************/
-    // Retrieve llm package : Model, Device, Tokenizer
-    let llm_package=llm_initialize.initialize(args_init).unwrap();
+    match command {
+        Some(Command::Batch(batch_args)) => return run_batch(generation_service,batch_args).await,
+        Some(Command::Chat(chat_args)) => return run_chat(generation_service,chat_args).await,
+        None => {}
+    }
 
     /**************************************************************/
     // Initialization of the demo web page
@@ -129,45 +184,237 @@ async fn main() ->anyhow::Result<()> {
     // Route to retrieve the html page
     let routes_index=warp::get().map(move || warp::reply::html(index_text.clone()));
 
+    /**************************************************************/
+    // Metrics Route
+    /**************************************************************/
+    let metrics_llm_engine=llm_engine.clone();
+    let routes_metrics=warp::path("metrics")
+        .and(warp::get())
+        .map(move || {
+            let stats=metrics_llm_engine.prefix_cache_stats();
+            format!(
+                "# TYPE llm_prefix_cache_hits_total counter\nllm_prefix_cache_hits_total {}\n\
+                 # TYPE llm_prefix_cache_misses_total counter\nllm_prefix_cache_misses_total {}\n\
+                 # TYPE llm_prefix_cache_tokens_saved_total counter\nllm_prefix_cache_tokens_saved_total {}\n",
+                stats.hits, stats.misses, stats.tokens_saved
+            )
+        });
+
+    /**************************************************************/
+    // Admin Route, reload the prompt config
+    /**************************************************************/
+    let admin_profiles=prompt_profiles.clone();
+    let routes_admin=warp::path!("admin" / "reload_prompt_config")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .map(move |authorization:Option<String>| {
+            if let Err(message) = check_admin_token(admin_token.as_deref(),authorization.as_deref()) {
+                eprintln!("admin route refused: {}", message);
+                return ApiError::Unauthorized(message).into_response();
+            }
+            match admin_profiles.reload() {
+                Ok(profiles) => {
+                    let names:Vec<&str>=profiles.iter().map(|(name,_)| name).collect();
+                    println!("prompt config reloaded ( admin route ), profiles: {}", names.join(", "));
+                    warp::reply::json(&serde_json::json!({ "profiles": names })).into_response()
+                }
+                Err(e) => {
+                    eprintln!("prompt config not reloaded ( admin route ), keeping the previous one: {:#}", e);
+                    ApiError::InvalidConfig(format!("{:#}", e)).into_response()
+                }
+            }
+        });
+
+    /**************************************************************/
+    // OpenAI compatible Chat Completions Route
+    /**************************************************************/
+
+    let chat_service=generation_service.clone();
+
+    let routes_chat_completions = warp::path!("v1" / "chat" / "completions")
+        .and(warp::post())
+        .and(chat_completion_json_body())
+        .map( move |request :ChatCompletionRequest| {
+
+            // Request parameters override the profile, then the command line defaults
+            let channels=chat_service.resolve(request.profile.as_deref(),&request.sampling_overrides())
+                .map_err(ApiError::from)
+                .and_then(|(profile_context,sampling)| {
+                    let (context,history,query)=request.context_and_conversation(profile_context.as_str())?;
+                    chat_service.spawn_conversation(history,query,sampling,context).map_err(ApiError::from)
+                });
+
+            (request,channels)
+        })
+        .untuple_one()
+        .then({
+            let model_name=model_name.clone();
+            move |request:ChatCompletionRequest,channels:Result<GenerationChannels,ApiError>| handler_chat_completions(request,channels,model_name.clone())
+        });
+
     /**************************************************************/
     // Text Generation Route
     /**************************************************************/
 
+    let stream_service=generation_service.clone();
     let routes_generation = warp::path("token_stream")
         .and(warp::post())
         .and(prompt_json_body())
-        .map( move |prompt :Prompt| {
+        .and(warp::header::optional::<String>("accept"))
+        .map( move |prompt :Prompt, accept:Option<String>| -> Result<_, ApiError> {
 
+            // Request parameters override the profile, then the command line defaults
+            let (context,sampling)=stream_service.resolve(prompt.profile.as_deref(),&prompt.sampling)?;
 
-            // Create a new channel for each request
-            let (tx, rx):(UnboundedSender<String>,UnboundedReceiver<String>)  = mpsc::unbounded_channel();
-            let rx_stream = UnboundedReceiverStream::new(rx);
+            // Answers of the sql profiles are checked against the dialect and the schema of the request.
+            // The raw text stream has no room for the check, a schema is only accepted along with events or JSON
+            let stream=prompt.stream.unwrap_or(true);
+            if prompt.sql_schema.is_some() && stream && !accepts_event_stream(accept.as_deref()) {
+                return Err(InvalidParameter {
+                    field: "sql_schema",
+                    message: "needs \"stream\":false or an `Accept: text/event-stream` header, the raw text stream does not carry the sql check".to_string(),
+                }.into());
+            }
+            let sql_dialect=stream_service.prompt_profiles.current().get(prompt.profile.as_deref())?.sql_dialect.clone();
+            let sql_check=SqlCheck::new(sql_dialect.as_deref(),prompt.sql_schema)?;
 
-            let context=context.clone();
-            let llm_package_clone=llm_package.clone();
+            let channels=stream_service.spawn(prompt.query,sampling,context)?;
 
-            // Clone the Arc for the closure
-            let dedicated_runtime_clone = dedicated_runtime.clone();
+            Ok((channels,sql_check,stream,accept))
 
-            // Spawn a Tokio task in the dedicated runtime for this specific channel
-            let _ = dedicated_runtime_clone.lock().unwrap().as_ref().unwrap().spawn(async move {
-                process_generation(llm_package_clone, prompt.query, tx,context.to_lowercase()).await;
-            });
+    })
+        .then(handler_generation);
+
+    /**************************************************************/
+    // Classification Route, labels scored by the model without sampling
+    /**************************************************************/
 
-            let event_stream = rx_stream.map(  move |token| {
-                Ok(Bytes::from(token))
-            });
+    let classify_service=generation_service.clone();
+    let routes_classify = warp::path("classify")
+        .and(warp::post())
+        .and(classify_json_body())
+        .map( move |request :ClassifyRequest| -> Result<_, ApiError> {
 
-            event_stream
+            let (context,_)=classify_service.resolve(request.profile.as_deref(),&SamplingOverrides::default())?;
+            classify_service.check_classification(request.text.as_str(),&request.labels,context.as_str())?;
 
-    })
+            Ok(classify_service.spawn_classification(request.text,request.labels,context)?)
+        })
+        .then(handler_classify);
+
+    /**************************************************************/
+    // Session Routes, conversations kept on the server
+    /**************************************************************/
+
+    let create_sessions=sessions.clone();
+    let routes_session_create = warp::path!("sessions")
+        .and(warp::post())
+        .and(optional_json_body::<SessionSettings>())
+        .map(move |settings:Result<SessionSettings,ApiError>| {
+            match settings {
+                Ok(settings) => {
+                    let view=create_sessions.create(settings);
+                    warp::reply::with_status(warp::reply::json(&view), warp::http::StatusCode::CREATED).into_response()
+                }
+                Err(err) => err.into_response(),
+            }
+        });
+
+    let get_sessions=sessions.clone();
+    let routes_session_get = warp::path!("sessions" / String)
+        .and(warp::get())
+        .map(move |id:String| {
+            match get_sessions.get(id.as_str()) {
+                Ok(view) => warp::reply::json(&view).into_response(),
+                Err(err) => err.into_response(),
+            }
+        });
+
+    let delete_sessions=sessions.clone();
+    let routes_session_delete = warp::path!("sessions" / String)
+        .and(warp::delete())
+        .map(move |id:String| {
+            match delete_sessions.delete(id.as_str()) {
+                Ok(()) => warp::http::StatusCode::NO_CONTENT.into_response(),
+                Err(err) => err.into_response(),
+            }
+        });
+
+    let append_sessions=sessions.clone();
+    let routes_session_append = warp::path!("sessions" / String / "messages")
+        .and(warp::post())
+        .and(session_message_json_body())
+        .map(move |id:String, message:ChatMessage| {
+            match append_sessions.append(id.as_str(),message) {
+                Ok(count) => warp::reply::json(&serde_json::json!({ "messages": count })).into_response(),
+                Err(err) => err.into_response(),
+            }
+        });
+
+    let reply_sessions=sessions.clone();
+    let reply_service=generation_service.clone();
+    let routes_session_reply = warp::path!("sessions" / String / "reply")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("accept"))
+        .map(move |id:String, accept:Option<String>| -> Result<_, ApiError> {
+
+            let pending=reply_sessions.start_reply(id.as_str())?;
+
+            // The history is rendered with the chat format of the model, oldest turns are
+            // left out when it does not fit in the context
+            let channels=reply_service.resolve(pending.settings.profile.as_deref(),&pending.settings.sampling)
+                .map_err(ApiError::from)
+                .and_then(|(context,sampling)| {
+                    reply_service.spawn_conversation(pending.history,pending.query,sampling,context).map_err(ApiError::from)
+                });
+            let channels=match channels {
+                Ok(channels) => channels,
+                Err(err) => {
+                    reply_sessions.finish_reply(id.as_str(),None);
+                    return Err(err);
+                }
+            };
+
+            let channels=record_reply(reply_sessions.clone(),id,channels);
+            Ok(token_stream_body(channels,accept,None))
+        })
         .then(handler_stream);
 
+    let routes_sessions=routes_session_create
+        .or(routes_session_get)
+        .or(routes_session_delete)
+        .or(routes_session_append)
+        .or(routes_session_reply);
+
+    /**************************************************************/
+    // WebSocket Route, one conversation per connection
+    /**************************************************************/
+
+    let routes_ws = warp::path("ws")
+        .and(warp::ws())
+        .map(move |ws: warp::ws::Ws| {
+            let service=generation_service.clone();
+            ws.on_upgrade(move |socket| handle_socket(socket,service))
+        });
+
     /**************************************************************/
     // Launch Server
     /**************************************************************/
 
-    warp::serve(routes_generation.or(routes_index)).run(([127, 0, 0, 1], 3030)).await;
+    let routes=routes_ws.or(routes_admin).or(routes_sessions).or(routes_chat_completions).or(routes_classify).or(routes_generation).or(routes_metrics).or(routes_index);
+
+    match listener {
+        Listener::Tcp(address) => {
+            let (address,server)=warp::serve(routes).try_bind_ephemeral(address)?;
+            println!("listening on http://{}", address);
+            server.await;
+        }
+        Listener::Unix(path) => {
+            let incoming=bind_unix_socket(&path)?;
+            println!("listening on unix socket {}", path.display());
+            warp::serve(routes).run_incoming(incoming).await;
+        }
+    }
 
     Ok(())
 }
@@ -177,10 +424,117 @@ async fn main() ->anyhow::Result<()> {
 /*****************************************************************/
 
 async fn handler_stream(
-    body: impl Stream<Item = Result< Bytes, Infallible>> + Unpin + Send + Sync + 'static,
+    body: Result<(ByteStream, usize, bool), ApiError>,
 ) -> Result<hyper::Response<Body>, Infallible> {
+    let (body,queue_position,sse)= match body {
+        Ok(body) => body,
+        Err(err) => return Ok(err.into_response()),
+    };
     let body= hyper::Body::wrap_stream(body);
-    Ok(warp::reply::Response::new(body))
+    let mut response=warp::reply::Response::new(body);
+    if sse {
+        response.headers_mut().insert("content-type", HeaderValue::from_static("text/event-stream"));
+        response.headers_mut().insert("cache-control", HeaderValue::from_static("no-cache"));
+    }
+    // Number of requests ahead of this one when it was received
+    response.headers_mut().insert("x-queue-position", HeaderValue::from(queue_position));
+    Ok(response)
+}
+
+
+async fn handler_generation(
+    generation: Result<(GenerationChannels, Option<SqlCheck>, bool, Option<String>), ApiError>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    let (channels,sql_check,stream,accept)= match generation {
+        Ok(generation) => generation,
+        Err(err) => return Ok(err.into_response()),
+    };
+    if stream {
+        return handler_stream(Ok(token_stream_body(channels,accept,sql_check))).await;
+    }
+
+    let queue_position=queue_position(&channels);
+    let GenerationChannels { tokens: rx, outcome, .. }= channels;
+    match collect_generation(rx,outcome,sql_check).await {
+        Ok(response) => {
+            let reply=warp::reply::json(&response);
+            Ok(warp::reply::with_header(reply, "x-queue-position", queue_position.to_string()).into_response())
+        }
+        Err(err) => Ok(err.into_response()),
+    }
+}
+
+
+async fn handler_chat_completions(
+    request: ChatCompletionRequest,
+    channels: Result<GenerationChannels, ApiError>,
+    model_name: String,
+) -> Result<hyper::Response<Body>, Infallible> {
+    let GenerationChannels { tokens: rx, queue, outcome }= match channels {
+        Ok(channels) => channels,
+        Err(err) => return Ok(err.into_response()),
+    };
+    let model= request.model.unwrap_or(model_name);
+
+    if request.stream.unwrap_or(false) {
+        let body= hyper::Body::wrap_stream(chunk_stream(rx,queue,outcome,model));
+        let mut response=warp::reply::Response::new(body);
+        response.headers_mut().insert("content-type", HeaderValue::from_static("text/event-stream"));
+        response.headers_mut().insert("cache-control", HeaderValue::from_static("no-cache"));
+        Ok(response)
+    } else {
+        match collect_completion(rx,outcome,model).await {
+            Ok(completion) => Ok(warp::reply::json(&completion).into_response()),
+            Err(err) => Ok(err.into_response()),
+        }
+    }
+}
+
+
+async fn handler_classify(
+    outcome: Result<tokio::sync::oneshot::Receiver<ClassificationOutcome>, ApiError>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    let outcome= match outcome {
+        Ok(outcome) => outcome.await,
+        Err(err) => return Ok(err.into_response()),
+    };
+    match outcome {
+        Ok(Ok(classification)) => Ok(warp::reply::json(&classification).into_response()),
+        Ok(Err(message)) => Ok(ApiError::Internal(message).into_response()),
+        Err(_) => Ok(ApiError::Internal("classification was dropped".to_string()).into_response()),
+    }
+}
+
+
+/// Raw text, or server sent events when asked for with the accept header, along with
+/// the position in the queue. The sql check only applies to server sent events.
+fn token_stream_body(channels:GenerationChannels, accept:Option<String>, sql_check:Option<SqlCheck>) -> (ByteStream, usize, bool) {
+    let queue_position=queue_position(&channels);
+
+    let sse=accepts_event_stream(accept.as_deref());
+    let event_stream:ByteStream = if sse {
+        Box::pin(token_event_stream(channels.tokens,channels.queue,channels.outcome,sql_check))
+    } else {
+        let rx_stream = UnboundedReceiverStream::new(channels.tokens);
+        Box::pin(rx_stream.map(  move |token| {
+            Ok(Bytes::from(token))
+        }))
+    };
+
+    (event_stream,queue_position,sse)
+}
+
+
+fn accepts_event_stream(accept:Option<&str>) -> bool {
+    accept.is_some_and(|accept| accept.contains("text/event-stream"))
+}
+
+/// Number of requests ahead of this one when it was received
+fn queue_position(channels:&GenerationChannels) -> usize {
+    match *channels.queue.borrow() {
+        QueueState::Waiting(position) => position,
+        QueueState::Running => 0,
+    }
 }
 
 
@@ -189,58 +543,69 @@ fn prompt_json_body() -> impl Filter<Extract = (Prompt,), Error = warp::Rejectio
         .and(warp::body::json())
 }
 
-/*****************************************************************/
-// This will call the generate method for appropriate llm model
-/*****************************************************************/
-#[cfg(not(feature = "llama"))]
-async fn process_generation(llm_package:LlmPackage,prompt:String,tx: UnboundedSender<String>,context_string:String) {
-    let _ = generate(llm_package, prompt.as_str(),tx,context_string.as_str());
+fn classify_json_body() -> impl Filter<Extract = (ClassifyRequest,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(1024 * 16)
+        .and(warp::body::json())
 }
 
-#[cfg(feature = "llama")]
-async fn process_generation(llm_package:QuantizedLlmPackage,prompt:String,tx: UnboundedSender<String>,context_string:String) {
-    let _ = generate(llm_package, prompt.as_str(),tx,context_string.as_str());
+fn session_message_json_body() -> impl Filter<Extract = (ChatMessage,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(1024 * 16)
+        .and(warp::body::json())
+}
+
+/// JSON body which may be left empty, its defaults are used then
+fn optional_json_body<T: DeserializeOwned + Default + Send>() -> impl Filter<Extract = (Result<T,ApiError>,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(1024 * 16)
+        .and(warp::body::bytes())
+        .map(|body:Bytes| {
+            if body.iter().all(|b| b.is_ascii_whitespace()) {
+                return Ok(T::default());
+            }
+            serde_json::from_slice(&body).map_err(|e| ApiError::from(InvalidParameter { field: "body", message: e.to_string() }))
+        })
 }
 
+fn chat_completion_json_body() -> impl Filter<Extract = (ChatCompletionRequest,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(1024 * 64)
+        .and(warp::body::json())
+}
 
 /*****************************************************************/
-// Retrieve the context of the prompt from toml file
+// Address of the HTTP server, from the command line and the server config file
 /*****************************************************************/
-fn get_prompt_context(config_file_name:&str, context_type: String) -> String {
-    let contents=match fs::read_to_string(config_file_name) {
-        // If successful return the files text as `contents`.
-        // `c` is a local variable.
-        Ok(c) => c,
-        // Handle the `error` case.
-        Err(_) => {
-            // Write `msg` to `stderr`.
-            eprintln!("Could not read file `{}`", config_file_name);
-            // Exit the program with exit code `1`.
+fn load_listener(args_init:&Args) -> (Listener,Option<String>) {
+    match ServerSettings::from_args(args_init).and_then(|settings| Ok((settings.listener()?,settings.admin_token))) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("{:#}", e);
             exit(1);
         }
-    };
+    }
+}
 
-    let data: Data = match toml::from_str(&contents) {
-        // If successful, return data as `Data` struct.
-        // `d` is a local variable.
-        Ok(d) => d,
-        // Handle the `error` case.
-        Err(_) => {
+/*****************************************************************/
+// Sampling defaults of the command line, checked like the parameters of a request
+/*****************************************************************/
+fn load_default_sampling(args_init:&Args) -> SamplingParams {
+    let default_sampling=SamplingParams::from_args(args_init);
+    if let Err(e) = default_sampling.validate() {
+        eprintln!("Invalid sampling parameters on the command line: {}", e);
+        exit(1);
+    }
+    default_sampling
+}
+
+/*****************************************************************/
+// Retrieve the prompt profiles from toml file
+/*****************************************************************/
+fn load_prompt_profiles(config_file_name:&str, context_type: &str, default_sampling:SamplingParams) -> PromptProfilesStore {
+    match PromptProfilesStore::load(config_file_name,context_type,default_sampling) {
+        Ok(profiles) => profiles,
+        Err(e) => {
             // Write `msg` to `stderr`.
-            eprintln!("Unable to load data from `{}`", config_file_name);
+            eprintln!("{:#}", e);
             // Exit the program with exit code `1`.
             exit(1);
         }
-    };
-
-    match context_type.as_str() {
-        // If successful, return data as `Data` struct.
-        // `d` is a local variable.
-        "general" => data.prompt_config.context_general,
-        "classifier" => data.prompt_config.context_classifier,
-        "sql" => data.prompt_config.context_sql,
-        "math" => data.prompt_config.context_math,
-        _ => data.prompt_config.context_general,
     }
-
-}
\ No newline at end of file
+}
@@ -0,0 +1,2 @@
+pub mod prompt_profiles;
+pub mod prompt_config_watcher;
@@ -0,0 +1,142 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+use crate::llm::sampling_params::SamplingParams;
+use crate::prompt_config::prompt_profiles::PromptProfiles;
+
+/// Editors often write a file in several steps, wait for them to settle before reloading
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Prompt profiles currently live, swapped as a whole when the config file changes.
+/// Requests take a snapshot, so a reload never affects a running generation.
+pub struct PromptProfilesStore {
+    config_file_name: String,
+    default_profile: String,
+    /// Command line sampling parameters the profiles are checked against
+    defaults: SamplingParams,
+    current: RwLock<Arc<PromptProfiles>>,
+}
+
+impl PromptProfilesStore {
+    pub fn load(config_file_name: &str, default_profile: &str, defaults: SamplingParams) -> Result<Self> {
+        let profiles = PromptProfiles::load(config_file_name, default_profile, &defaults)?;
+        Ok(Self {
+            config_file_name: config_file_name.to_string(),
+            default_profile: default_profile.to_string(),
+            defaults,
+            current: RwLock::new(Arc::new(profiles)),
+        })
+    }
+
+    pub fn current(&self) -> Arc<PromptProfiles> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Read the config file again. When it is invalid, the previous profiles stay live.
+    pub fn reload(&self) -> Result<Arc<PromptProfiles>> {
+        let profiles = Arc::new(PromptProfiles::load(&self.config_file_name, &self.default_profile, &self.defaults)?);
+        *self.current.write().unwrap() = profiles.clone();
+        Ok(profiles)
+    }
+
+    /// Reload, and log the outcome
+    pub fn reload_and_log(&self, trigger: &str) {
+        match self.reload() {
+            Ok(profiles) => println!(
+                "prompt config reloaded ( {} ), profiles: {}",
+                trigger,
+                profiles.iter().map(|(name, _)| name).collect::<Vec<_>>().join(", ")
+            ),
+            Err(e) => eprintln!("prompt config not reloaded ( {} ), keeping the previous one: {:#}", trigger, e),
+        }
+    }
+}
+
+/*****************************************************************/
+// Reload the store when the config file changes, or on SIGHUP
+/*****************************************************************/
+pub fn watch_prompt_config(store: Arc<PromptProfilesStore>) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let signal_store = store.clone();
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            signal_store.reload_and_log("SIGHUP");
+        }
+    });
+
+    let config_path = PathBuf::from(&store.config_file_name);
+    let file_name = config_path.file_name().map(|f| f.to_os_string());
+
+    // Watch the directory rather than the file, editors usually replace the file
+    let watched_dir = match config_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => Path::new(".").to_path_buf(),
+    };
+
+    let (changed_tx, mut changed_rx) = mpsc::unbounded_channel::<()>();
+    let mut watcher = RecommendedWatcher::new(
+        move |event: notify::Result<notify::Event>| {
+            if let Ok(event) = event {
+                let touches_config = event
+                    .paths
+                    .iter()
+                    .any(|path| path.file_name().map(|f| f.to_os_string()) == file_name);
+                if touches_config && (event.kind.is_modify() || event.kind.is_create()) {
+                    let _ = changed_tx.send(());
+                }
+            }
+        },
+        notify::Config::default(),
+    )?;
+    watcher
+        .watch(&watched_dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Unable to watch `{}`", watched_dir.display()))?;
+
+    tokio::spawn(async move {
+        // The watcher stops when dropped, keep it with the task
+        let _watcher = watcher;
+        while changed_rx.recv().await.is_some() {
+            tokio::time::sleep(RELOAD_DEBOUNCE).await;
+            while changed_rx.try_recv().is_ok() {}
+            store.reload_and_log("file changed");
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::args_init::args::Args;
+
+    #[test]
+    fn invalid_reload_keeps_the_previous_profiles() {
+        let config_file = std::env::temp_dir().join(format!("prompt_config_reload_{}.toml", std::process::id()));
+        let config_file_name = config_file.to_str().unwrap();
+        std::fs::write(&config_file, "[profiles.general]\ncontext = \"first\"\ntemperature = 0.5\n").unwrap();
+        let defaults = SamplingParams::from_args(&Args::parse_from(["llm_stream"]));
+        let store = PromptProfilesStore::load(config_file_name, "general", defaults).unwrap();
+
+        std::fs::write(&config_file, "[profiles.general]\ncontext = \"second\"\ntemperature = -1.0\n").unwrap();
+        assert!(store.reload().is_err());
+        let profiles = store.current();
+        let profile = profiles.get(None).unwrap();
+        assert_eq!(profile.context, "first");
+        assert_eq!(profile.sampling.temperature, Some(0.5));
+
+        std::fs::write(&config_file, "[profiles.general]\ncontext = \"third\"\n").unwrap();
+        assert!(store.reload().is_ok());
+        assert_eq!(store.current().get(None).unwrap().context, "third");
+
+        let _ = std::fs::remove_file(&config_file);
+    }
+}
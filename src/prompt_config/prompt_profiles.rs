@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::llm::sampling_params::{InvalidParameter, SamplingOverrides, SamplingParams};
+use crate::llm::sql_output::is_sql_dialect;
+
+/// A named context, with its own default sampling parameters
+#[derive(Deserialize, Debug, Clone)]
+pub struct PromptProfile {
+    /// System text placed before the user prompt
+    pub context: String,
+    /// Answers are checked as queries of this sql dialect
+    #[serde(default)]
+    pub sql_dialect: Option<String>,
+    /// Profile defaults, requests can still override them
+    #[serde(flatten)]
+    pub sampling: SamplingOverrides,
+    /// Keys left over by the fields above, rejected so that typos are not silently ignored.
+    /// `deny_unknown_fields` does not work along with `flatten`
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+// Top level struct to hold the TOML data, one `[profiles.<name>]` table per profile
+#[derive(Deserialize)]
+struct Data {
+    profiles: BTreeMap<String, PromptProfile>,
+}
+
+/// Profiles read from the prompt config file
+#[derive(Debug, Clone)]
+pub struct PromptProfiles {
+    profiles: BTreeMap<String, PromptProfile>,
+    default_profile: String,
+}
+
+impl PromptProfiles {
+    /// Parse the profiles, `default_profile` is used by requests which do not name one.
+    /// The sampling parameters of each profile are checked on top of the command line `defaults`,
+    /// as they would be for a request selecting the profile.
+    pub fn from_toml(contents: &str, default_profile: &str, defaults: &SamplingParams) -> Result<Self> {
+        let data: Data = toml::from_str(contents)?;
+        if !data.profiles.contains_key(default_profile) {
+            return Err(anyhow!("default profile `{}` is not defined", default_profile));
+        }
+        for (name, profile) in data.profiles.iter() {
+            if let Some(key) = profile.unknown.keys().next() {
+                return Err(anyhow!("profile `{}` has an unknown key `{}`", name, key));
+            }
+            if let Some(dialect) = profile.sql_dialect.as_deref().filter(|dialect| !is_sql_dialect(dialect)) {
+                return Err(anyhow!("profile `{}` has an unknown sql dialect `{}`", name, dialect));
+            }
+            profile
+                .sampling
+                .resolve(defaults)
+                .map_err(|e| anyhow!("profile `{}` has {}", name, e))?;
+        }
+        Ok(Self {
+            profiles: data.profiles,
+            default_profile: default_profile.to_string(),
+        })
+    }
+
+    pub fn load(config_file_name: &str, default_profile: &str, defaults: &SamplingParams) -> Result<Self> {
+        let contents = fs::read_to_string(config_file_name)
+            .with_context(|| format!("Could not read file `{}`", config_file_name))?;
+        Self::from_toml(&contents, default_profile, defaults)
+            .with_context(|| format!("Unable to load data from `{}`", config_file_name))
+    }
+
+    /// Profile named by a request, or the default one
+    pub fn get(&self, name: Option<&str>) -> Result<&PromptProfile, InvalidParameter> {
+        let name = name.unwrap_or(self.default_profile.as_str());
+        self.profiles.get(name).ok_or_else(|| InvalidParameter {
+            field: "profile",
+            message: format!(
+                "unknown profile `{}`, expected one of: {}",
+                name,
+                self.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+            ),
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &PromptProfile)> {
+        self.profiles.iter().map(|(name, profile)| (name.as_str(), profile))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::args_init::args::Args;
+
+    fn defaults() -> SamplingParams {
+        SamplingParams::from_args(&Args::parse_from(["llm_stream"]))
+    }
+
+    fn config(profile: &str) -> String {
+        format!("[profiles.general]\ncontext = \"general\"\n\n[profiles.other]\ncontext = \"other\"\n{}\n", profile)
+    }
+
+    fn load_error(profile: &str) -> String {
+        format!("{:#}", PromptProfiles::from_toml(config(profile).as_str(), "general", &defaults()).unwrap_err())
+    }
+
+    #[test]
+    fn valid_profiles_are_loaded() {
+        let profiles = PromptProfiles::from_toml(config("temperature = 0.5\nstop = [\";\"]").as_str(), "general", &defaults()).unwrap();
+        assert_eq!(profiles.get(Some("other")).unwrap().sampling.temperature, Some(0.5));
+        assert_eq!(profiles.get(None).unwrap().context, "general");
+    }
+
+    #[test]
+    fn shipped_config_is_valid() {
+        PromptProfiles::load("./config/prompt_config.toml", "general", &defaults()).unwrap();
+    }
+
+    #[test]
+    fn out_of_range_sampling_is_rejected() {
+        assert!(load_error("temperature = -1.0").contains("temperature"));
+        assert!(load_error("top_p = 1.5").contains("top_p"));
+    }
+
+    #[test]
+    fn grammar_and_schema_are_compiled() {
+        assert!(load_error("grammar = 'root ::= missing'").contains("grammar"));
+        assert!(load_error("json_schema = { type = \"no such type\" }").contains("json_schema"));
+    }
+
+    #[test]
+    fn invalid_logit_bias_is_rejected() {
+        assert!(load_error("logit_bias = { \"13\" = -500.0 }").contains("logit_bias"));
+        assert!(load_error("logit_bias = { \"token\" = 1.0 }").contains("logit_bias"));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(load_error("temprature = 0.5").contains("unknown key `temprature`"));
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+
+/// Where a request stands in the scheduler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueState {
+    /// Waiting for a free slot, with the number of requests ahead of it
+    Waiting(usize),
+    /// A slot was granted, generation is running
+    Running,
+}
+
+/// The wait queue is full, the request should be retried later
+#[derive(Debug, Clone)]
+pub struct QueueFull {
+    pub retry_after_secs: u64,
+}
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server is busy, retry in {} seconds", self.retry_after_secs)
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+struct Waiter {
+    id: u64,
+    state: watch::Sender<QueueState>,
+}
+
+struct Inner {
+    slots: Arc<Semaphore>,
+    waiting: Mutex<VecDeque<Waiter>>,
+    max_queue_size: usize,
+    retry_after_secs: u64,
+    next_id: AtomicU64,
+}
+
+impl Inner {
+    /// Remove a waiter, and let the others know their new position
+    fn remove(&self, id: u64) {
+        let mut waiting = self.waiting.lock().unwrap();
+        if let Some(index) = waiting.iter().position(|w| w.id == id) {
+            if let Some(waiter) = waiting.remove(index) {
+                let _ = waiter.state.send(QueueState::Running);
+            }
+            for (position, waiter) in waiting.iter().enumerate().skip(index) {
+                let _ = waiter.state.send(QueueState::Waiting(position));
+            }
+        }
+    }
+}
+
+/// Limits the number of generations running at the same time, and keeps the
+/// others in a bounded FIFO queue
+#[derive(Clone)]
+pub struct GenerationScheduler {
+    inner: Arc<Inner>,
+}
+
+impl GenerationScheduler {
+    pub fn new(max_concurrent: usize, max_queue_size: usize, retry_after_secs: u64) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                slots: Arc::new(Semaphore::new(max_concurrent.max(1))),
+                waiting: Mutex::new(VecDeque::new()),
+                max_queue_size,
+                retry_after_secs,
+                next_id: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Take a place in the queue, or fail right away when the queue is full
+    pub fn enqueue(&self) -> Result<QueueTicket, QueueFull> {
+        let mut waiting = self.inner.waiting.lock().unwrap();
+
+        // Requests which will get a free slot right away do not count as queued
+        let capacity = self.inner.max_queue_size + self.inner.slots.available_permits();
+        if waiting.len() >= capacity {
+            return Err(QueueFull {
+                retry_after_secs: self.inner.retry_after_secs,
+            });
+        }
+
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let position = waiting.len();
+        let (state, state_rx) = watch::channel(QueueState::Waiting(position));
+        waiting.push_back(Waiter { id, state });
+
+        Ok(QueueTicket {
+            id,
+            inner: self.inner.clone(),
+            state_rx,
+        })
+    }
+}
+
+/// A place in the queue. Dropping it before a slot is granted leaves the queue.
+pub struct QueueTicket {
+    id: u64,
+    inner: Arc<Inner>,
+    state_rx: watch::Receiver<QueueState>,
+}
+
+impl QueueTicket {
+    /// Follow the position of this request in the queue
+    pub fn subscribe(&self) -> watch::Receiver<QueueState> {
+        self.state_rx.clone()
+    }
+
+    /// Wait for a free slot. The slot is released when the permit is dropped.
+    pub async fn acquire(self) -> GenerationPermit {
+        let permit = self
+            .inner
+            .slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("generation slots are never closed");
+        GenerationPermit { _permit: permit }
+        // `self` is dropped here, which removes it from the queue
+    }
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        self.inner.remove(self.id);
+    }
+}
+
+/// A running generation slot
+pub struct GenerationPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn free_slots_extend_the_queue_capacity() {
+        let scheduler = GenerationScheduler::new(2, 1, 7);
+
+        // Two requests get a slot right away, one more can wait
+        let tickets: Vec<_> = (0..3).map(|_| scheduler.enqueue().unwrap()).collect();
+        let full = scheduler.enqueue().err().unwrap();
+        assert_eq!(full.retry_after_secs, 7);
+
+        // Once slots are taken, only the queue itself remains
+        let mut tickets = tickets.into_iter();
+        let _first = tickets.next().unwrap().acquire().await;
+        let _second = tickets.next().unwrap().acquire().await;
+        let _third = tickets.next().unwrap();
+        assert!(scheduler.enqueue().is_err());
+    }
+
+    #[tokio::test]
+    async fn positions_follow_the_queue() {
+        let scheduler = GenerationScheduler::new(1, 4, 1);
+        let running = scheduler.enqueue().unwrap();
+        let second = scheduler.enqueue().unwrap();
+        let third = scheduler.enqueue().unwrap();
+        let (second_state, third_state) = (second.subscribe(), third.subscribe());
+        assert_eq!(*second_state.borrow(), QueueState::Waiting(1));
+        assert_eq!(*third_state.borrow(), QueueState::Waiting(2));
+
+        let running_state = running.subscribe();
+        let permit = running.acquire().await;
+        assert_eq!(*running_state.borrow(), QueueState::Running);
+        assert_eq!(*second_state.borrow(), QueueState::Waiting(0));
+        assert_eq!(*third_state.borrow(), QueueState::Waiting(1));
+
+        // The slot goes to the next one in line when released
+        drop(permit);
+        let _permit = second.acquire().await;
+        assert_eq!(*second_state.borrow(), QueueState::Running);
+        assert_eq!(*third_state.borrow(), QueueState::Waiting(0));
+    }
+
+    #[tokio::test]
+    async fn dropped_ticket_leaves_the_queue() {
+        let scheduler = GenerationScheduler::new(1, 2, 1);
+        let _permit = scheduler.enqueue().unwrap().acquire().await;
+        let second = scheduler.enqueue().unwrap();
+        let third = scheduler.enqueue().unwrap();
+        assert!(scheduler.enqueue().is_err());
+
+        let third_state = third.subscribe();
+        drop(second);
+        assert_eq!(*third_state.borrow(), QueueState::Waiting(0));
+        assert_eq!(scheduler.inner.waiting.lock().unwrap().len(), 1);
+        assert!(scheduler.enqueue().is_ok());
+    }
+}
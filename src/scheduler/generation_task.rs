@@ -0,0 +1,238 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, watch};
+
+use crate::llm::classification::{validate_labels, Classification};
+use crate::llm::engine::LlmEngine;
+use crate::llm::generation_summary::{FinishReason, GenerationOutcome, GenerationSummary};
+use crate::llm::prompt_template::Exchange;
+use crate::llm::sampling_params::{InvalidParameter, SamplingOverrides, SamplingParams};
+use crate::prompt_config::prompt_config_watcher::PromptProfilesStore;
+use crate::scheduler::generation_scheduler::{GenerationScheduler, QueueFull, QueueState};
+
+/// Runtime running the generations, away from the one serving the requests
+pub type DedicatedRuntime = Arc<Mutex<Option<tokio::runtime::Runtime>>>;
+
+/// Channels of a queued generation
+pub struct GenerationChannels {
+    /// Generated text, closed when the generation ends. Dropping it cancels the generation.
+    pub tokens: UnboundedReceiver<String>,
+    /// Position in the scheduler queue
+    pub queue: watch::Receiver<QueueState>,
+    /// How the generation ended, sent after the last token
+    pub outcome: oneshot::Receiver<GenerationOutcome>,
+}
+
+/// Labels ranked by the model, or why scoring failed
+pub type ClassificationOutcome = Result<Classification, String>;
+
+/// Everything needed to start a generation, shared by the routes
+#[derive(Clone)]
+pub struct GenerationService {
+    pub dedicated_runtime: DedicatedRuntime,
+    pub scheduler: GenerationScheduler,
+    pub llm_engine: Arc<dyn LlmEngine>,
+    pub prompt_profiles: Arc<PromptProfilesStore>,
+}
+
+impl GenerationService {
+    /// Context and sampling parameters of a generation. Request parameters override
+    /// the profile ones, which override the command line ones.
+    pub fn resolve(&self, profile: Option<&str>, overrides: &SamplingOverrides) -> Result<(String, SamplingParams), InvalidParameter> {
+        let profiles = self.prompt_profiles.current();
+        let profile = profiles.get(profile)?;
+        let sampling = overrides.or(&profile.sampling).resolve(self.llm_engine.default_sampling())?;
+
+        // Checked here rather than in the generation loop, which would fail once the stream started
+        let vocab_size = self.llm_engine.vocab_size();
+        if let Some(token) = sampling.logit_bias.keys().find(|token| **token as usize >= vocab_size) {
+            return Err(InvalidParameter {
+                field: "logit_bias",
+                message: format!("token {} is not in the vocabulary of {} tokens", token, vocab_size),
+            });
+        }
+        Ok((profile.context.to_lowercase(), sampling))
+    }
+
+    pub fn spawn(&self, prompt: String, sampling: SamplingParams, context: String) -> Result<GenerationChannels, QueueFull> {
+        self.spawn_conversation(Vec::new(), prompt, sampling, context)
+    }
+
+    /// Start a generation following the previous turns in `history`. The oldest turns are
+    /// left out when the prompt and `sample_len` new tokens would not fit in the model context.
+    pub fn spawn_conversation(&self, mut history: Vec<Exchange>, prompt: String, sampling: SamplingParams, context: String) -> Result<GenerationChannels, QueueFull> {
+        let template = self.llm_engine.prompt_template();
+        let context_length = self.llm_engine.context_length();
+        let turns = history.len();
+
+        fit_history(&mut history, |history| {
+            // A prompt which cannot be rendered or counted is left as is, generation reports the error
+            template
+                .render_conversation(context.as_str(), history, prompt.as_str())
+                .and_then(|rendered| self.llm_engine.count_tokens(rendered.as_str()))
+                .map_or(true, |prompt_tokens| prompt_tokens + sampling.sample_len <= context_length)
+        });
+        if history.len() < turns {
+            println!("left out the {} oldest turns to fit in the context length", turns - history.len());
+        }
+
+        spawn_generation(&self.dedicated_runtime, &self.scheduler, self.llm_engine.clone(), prompt, history, sampling, context)
+    }
+
+    /// Check the labels, and that the prompt followed by the longest label fits in the model context
+    pub fn check_classification(&self, text: &str, labels: &[String], context: &str) -> Result<(), InvalidParameter> {
+        validate_labels(labels)?;
+        if text.trim().is_empty() {
+            return Err(InvalidParameter { field: "text", message: "cannot be empty".to_string() });
+        }
+
+        // A prompt which cannot be rendered is not checked, classification reports the error
+        let Ok(rendered) = self.llm_engine.prompt_template().render_conversation(context, &[], text) else {
+            return Ok(());
+        };
+        let count = |text: &str| self.llm_engine.count_tokens(text).unwrap_or(0);
+        let longest_label = labels.iter().map(|label| count(label)).max().unwrap_or(0);
+        let context_length = self.llm_engine.context_length();
+        if count(rendered.as_str()) + longest_label > context_length {
+            return Err(InvalidParameter {
+                field: "text",
+                message: format!("the prompt and the labels do not fit in the {} tokens of the model context", context_length),
+            });
+        }
+        Ok(())
+    }
+
+    /// Score the labels on the dedicated runtime once the scheduler grants a slot
+    pub fn spawn_classification(&self, text: String, labels: Vec<String>, context: String) -> Result<oneshot::Receiver<ClassificationOutcome>, QueueFull> {
+        let ticket = self.scheduler.enqueue()?;
+        let llm_engine = self.llm_engine.clone();
+        let (mut outcome_tx, outcome_rx) = oneshot::channel();
+
+        self.dedicated_runtime.lock().unwrap().as_ref().unwrap().spawn(async move {
+            // Leave the queue if the client goes away while waiting
+            let permit = tokio::select! {
+                permit = ticket.acquire() => permit,
+                _ = outcome_tx.closed() => {
+                    println!("classification cancelled by the client while queued");
+                    return;
+                }
+            };
+            let start = std::time::Instant::now();
+            // Scoring is synchronous, it runs on the blocking pool like the generations
+            let label_count = labels.len();
+            let scoring = tokio::task::spawn_blocking(move || llm_engine.classify(text.as_str(), &labels, context.as_str()));
+            let outcome = match scoring.await {
+                Ok(outcome) => outcome.map_err(|e| {
+                    eprintln!("classification failed: {:#}", e);
+                    e.to_string()
+                }),
+                Err(e) => {
+                    eprintln!("classification task failed: {}", e);
+                    Err(e.to_string())
+                }
+            };
+            drop(permit);
+            if let Ok(classification) = &outcome {
+                println!("{} labels scored in {:?}, best: {}", label_count, start.elapsed(), classification.label);
+            }
+            let _ = outcome_tx.send(outcome);
+        });
+
+        Ok(outcome_rx)
+    }
+}
+
+/// Leave out the oldest turns until the prompt built from the rest fits
+fn fit_history(history: &mut Vec<Exchange>, fits: impl Fn(&[Exchange]) -> bool) {
+    while !history.is_empty() && !fits(history) {
+        history.remove(0);
+    }
+}
+
+/*****************************************************************/
+// Queue a generation on the dedicated runtime, tokens are sent on the returned channel
+// once the scheduler grants a slot
+/*****************************************************************/
+pub fn spawn_generation(dedicated_runtime:&DedicatedRuntime,scheduler:&GenerationScheduler,llm_engine:Arc<dyn LlmEngine>,prompt:String,history:Vec<Exchange>,sampling:SamplingParams,context:String) -> Result<GenerationChannels,QueueFull> {
+    let ticket=scheduler.enqueue()?;
+    let queue=ticket.subscribe();
+
+    // Create a new channel for each request
+    let (tx, rx):(UnboundedSender<String>,UnboundedReceiver<String>)  = mpsc::unbounded_channel();
+    let (outcome_tx, outcome_rx) = oneshot::channel();
+
+    // Spawn a Tokio task in the dedicated runtime for this specific channel
+    let _ = dedicated_runtime.lock().unwrap().as_ref().unwrap().spawn(async move {
+        // Leave the queue if the client goes away while waiting
+        let permit= tokio::select! {
+            permit = ticket.acquire() => permit,
+            _ = tx.closed() => {
+                println!("request cancelled by the client while queued");
+                let _ = outcome_tx.send(Ok(GenerationSummary::new(FinishReason::Cancelled, 0, 0, Duration::ZERO)));
+                return;
+            }
+        };
+        let outcome=process_generation(llm_engine, prompt, history, sampling, tx,context).await;
+        drop(permit);
+        let _ = outcome_tx.send(outcome);
+    });
+
+    Ok(GenerationChannels { tokens: rx, queue, outcome: outcome_rx })
+}
+
+/*****************************************************************/
+// This will call the generate method for appropriate llm model.
+// Generation is synchronous, it runs on the blocking pool so that the runtime
+// workers stay free whatever the number of concurrent generations
+/*****************************************************************/
+async fn process_generation(llm_engine:Arc<dyn LlmEngine>,prompt:String,history:Vec<Exchange>,sampling:SamplingParams,tx: UnboundedSender<String>,context_string:String) -> GenerationOutcome {
+    let generation = tokio::task::spawn_blocking(move || {
+        llm_engine.generate(prompt.as_str(),&history,&sampling,tx,context_string.as_str())
+    });
+    match generation.await {
+        Ok(outcome) => outcome.map_err(|e| {
+            eprintln!("generation failed: {:#}", e);
+            e.to_string()
+        }),
+        Err(e) => {
+            eprintln!("generation task failed: {}", e);
+            Err(e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(user: &str, assistant: &str) -> Exchange {
+        Exchange {
+            user: user.to_string(),
+            assistant: assistant.to_string(),
+        }
+    }
+
+    fn length(history: &[Exchange]) -> usize {
+        history.iter().map(|e| e.user.len() + e.assistant.len()).sum()
+    }
+
+    #[test]
+    fn oldest_turns_are_left_out_first() {
+        let mut history = vec![exchange("first", "one"), exchange("second", "two"), exchange("third", "three")];
+        fit_history(&mut history, |history| length(history) <= 20);
+        assert_eq!(history.iter().map(|e| e.user.as_str()).collect::<Vec<_>>(), vec!["second", "third"]);
+
+        // Nothing fits, the prompt goes without history
+        fit_history(&mut history, |_| false);
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn history_which_fits_is_kept() {
+        let mut history = vec![exchange("first", "one"), exchange("second", "two")];
+        fit_history(&mut history, |history| length(history) <= 100);
+        assert_eq!(history.len(), 2);
+    }
+}
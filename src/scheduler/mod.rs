@@ -0,0 +1,2 @@
+pub mod generation_scheduler;
+pub mod generation_task;
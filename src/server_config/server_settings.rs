@@ -0,0 +1,150 @@
+use std::fs;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+
+use crate::args_init::args::Args;
+
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 3030;
+/// Read when it exists and no other file is given on the command line
+pub const DEFAULT_SERVER_CONFIG: &str = "./config/server_config.toml";
+
+/// Where the HTTP server listens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listener {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// `[server]` table of the server config file, command line options override it
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ServerSettings {
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Path of a Unix domain socket, used instead of the host and port
+    #[serde(default)]
+    pub unix_socket: Option<String>,
+    /// Bearer token of the admin routes, they are disabled without it.
+    /// Only read from the file, to keep it out of the process list
+    #[serde(default)]
+    pub admin_token: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct Data {
+    #[serde(default)]
+    server: ServerSettings,
+}
+
+impl ServerSettings {
+    pub fn from_toml(contents: &str) -> Result<Self> {
+        let data: Data = toml::from_str(contents)?;
+        Ok(data.server)
+    }
+
+    pub fn load(config_file_name: &str) -> Result<Self> {
+        let contents = fs::read_to_string(config_file_name)
+            .with_context(|| format!("Could not read file `{}`", config_file_name))?;
+        Self::from_toml(&contents)
+            .with_context(|| format!("Unable to load data from `{}`", config_file_name))
+    }
+
+    /// Settings of the config file given on the command line, or of the default one when it exists,
+    /// overridden by the command line options
+    pub fn from_args(args_init: &Args) -> Result<Self> {
+        let file_settings = match &args_init.server_config {
+            Some(config_file_name) => Self::load(config_file_name)?,
+            None if Path::new(DEFAULT_SERVER_CONFIG).exists() => Self::load(DEFAULT_SERVER_CONFIG)?,
+            None => Self::default(),
+        };
+
+        Ok(Self {
+            host: args_init.host.clone().or(file_settings.host),
+            port: args_init.port.or(file_settings.port),
+            unix_socket: args_init.unix_socket.clone().or(file_settings.unix_socket),
+            admin_token: file_settings.admin_token.filter(|token| !token.is_empty()),
+        })
+    }
+
+    pub fn listener(&self) -> Result<Listener> {
+        if let Some(unix_socket) = &self.unix_socket {
+            return Ok(Listener::Unix(PathBuf::from(unix_socket)));
+        }
+
+        let host = self.host.as_deref().unwrap_or(DEFAULT_HOST);
+        let port = self.port.unwrap_or(DEFAULT_PORT);
+        // Ip addresses, IPv6 ones included, are taken as is, names are resolved
+        if let Ok(ip) = host.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>() {
+            return Ok(Listener::Tcp(SocketAddr::new(ip, port)));
+        }
+        (host, port)
+            .to_socket_addrs()
+            .with_context(|| format!("Unable to resolve host `{}`", host))?
+            .next()
+            .map(Listener::Tcp)
+            .ok_or_else(|| anyhow!("host `{}` has no address", host))
+    }
+}
+
+/// Check the `Authorization: Bearer <token>` header of an admin request
+pub fn check_admin_token(admin_token: Option<&str>, authorization: Option<&str>) -> Result<(), String> {
+    let Some(admin_token) = admin_token else {
+        return Err("admin routes are disabled, set admin_token in the [server] table of the server config".to_string());
+    };
+    let given = authorization.and_then(|header| header.strip_prefix("Bearer ")).unwrap_or_default();
+    // Compare every byte, so that the time taken does not tell how much of the token matched
+    let matching = given.len() == admin_token.len()
+        && given.bytes().zip(admin_token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+    if matching {
+        Ok(())
+    } else {
+        Err("missing or invalid admin token".to_string())
+    }
+}
+
+/// Listen on a Unix domain socket. A socket left behind by a previous run is replaced,
+/// any other file at this path is an error.
+pub fn bind_unix_socket(path: &Path) -> Result<UnixListenerStream> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("`{}` exists and is not a socket", path.display());
+        }
+        fs::remove_file(path).with_context(|| format!("Unable to remove the previous socket `{}`", path.display()))?;
+    }
+    let listener = UnixListener::bind(path).with_context(|| format!("Unable to listen on `{}`", path.display()))?;
+    Ok(UnixListenerStream::new(listener))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_token_from_the_server_table() {
+        let settings = ServerSettings::from_toml("[server]\nport = 8080\nadmin_token = \"s3cret\"\n").unwrap();
+        assert_eq!(settings.admin_token.as_deref(), Some("s3cret"));
+        assert!(ServerSettings::from_toml("[server]\nadmin = \"s3cret\"\n").is_err());
+    }
+
+    #[test]
+    fn admin_requests_need_the_bearer_token() {
+        assert!(check_admin_token(Some("s3cret"), Some("Bearer s3cret")).is_ok());
+        assert!(check_admin_token(Some("s3cret"), Some("Bearer s3cre")).is_err());
+        assert!(check_admin_token(Some("s3cret"), Some("Bearer s3cret2")).is_err());
+        assert!(check_admin_token(Some("s3cret"), Some("s3cret")).is_err());
+        assert!(check_admin_token(Some("s3cret"), None).is_err());
+
+        // No token configured, the admin routes are closed
+        assert!(check_admin_token(None, Some("Bearer ")).is_err());
+        assert!(check_admin_token(None, None).is_err());
+    }
+}